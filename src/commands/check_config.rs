@@ -0,0 +1,161 @@
+use crate::model::{AppState, AppStateError, Config, SocketListener, UnixSocketOptions};
+
+#[derive(thiserror::Error, Debug)]
+enum CheckError {
+    #[error("--listen is required")]
+    MissingListener,
+    #[error("Couldn't bind to '{0}'")]
+    Bind(String, #[source] std::io::Error),
+    #[error("Link store file '{}' isn't usable", .0.display())]
+    LinkStoreFile(std::path::PathBuf, #[source] std::io::Error),
+    #[error("Invalid config")]
+    AppState(#[from] AppStateError),
+}
+
+/// Prints the effective config (with redacted secrets) and every problem which would prevent startup.
+pub fn check_config(mut config: Config<'static, 'static>) -> i32 {
+    let mut errors = Vec::new();
+
+    println!("{config:#?}");
+
+    if config.listen.is_empty() {
+        errors.push(CheckError::MissingListener);
+    }
+
+    for listener in &config.listen {
+        if let Err(err) = test_bind(listener, &config.unix_socket) {
+            errors.push(CheckError::Bind(listener.to_string(), err));
+        }
+    }
+
+    if let Some(file_path) = config
+        .link_store
+        .as_ref()
+        .and_then(|options| options.file_path.as_deref())
+        && let Err(err) = check_link_store_file(file_path)
+    {
+        errors.push(CheckError::LinkStoreFile(file_path.to_owned(), err));
+    }
+
+    // loading the link store would compact the file, which might be in use by a running server
+    config.link_store = None;
+
+    if let Err(err) = AppState::try_from(config) {
+        errors.push(err.into());
+    }
+
+    if errors.is_empty() {
+        println!("Config is valid");
+        return 0;
+    }
+
+    for err in &errors {
+        eprintln!("error: {}", crate::format_error(err));
+    }
+
+    1
+}
+
+/// Binds to the listener (with the socket permissions) and releases it right away.
+fn test_bind(listener: &SocketListener, options: &UnixSocketOptions) -> std::io::Result<()> {
+    match listener {
+        SocketListener::Tcp(address) => std::net::TcpListener::bind(address).map(drop),
+        #[cfg(unix)]
+        SocketListener::Unix(path) => {
+            // stale sockets will be removed on startup, but not by a dry run
+            if options.remove_stale && crate::server::lib::is_stale_socket(path)? {
+                return Ok(());
+            }
+
+            // the socket file is removed along with the listener
+            crate::server::lib::bind_unix_socket(path, options).map(drop)
+        }
+        // inherited sockets are only passed to the service itself
+        #[cfg(unix)]
+        SocketListener::Systemd(_) => Ok(()),
+    }
+}
+
+/// Checks that the file can be appended to, or that its directory exists (the file is created on startup).
+fn check_link_store_file(file_path: &std::path::Path) -> std::io::Result<()> {
+    match std::fs::OpenOptions::new().append(true).open(file_path) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => std::fs::read_dir(
+            file_path
+                .parent()
+                .filter(|dir_path| !dir_path.as_os_str().is_empty())
+                .unwrap_or(std::path::Path::new(".")),
+        )
+        .map(drop),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_link_store_file, test_bind};
+    use crate::model::{SocketListener, UnixSocketOptions};
+
+    #[test]
+    fn bind_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = UnixSocketOptions::default();
+
+        assert!(
+            test_bind(
+                &SocketListener::Tcp("127.0.0.1:0".parse().unwrap()),
+                &options
+            )
+            .is_ok()
+        );
+        assert!(
+            test_bind(
+                &SocketListener::Tcp(listener.local_addr().unwrap()),
+                &options
+            )
+            .is_err()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bind_unix_listener() {
+        let socket_path =
+            std::env::temp_dir().join(format!("searproxy-check-{}.sock", std::process::id()));
+
+        let listener = SocketListener::Unix(socket_path.clone());
+        let options = UnixSocketOptions {
+            mode: Some(0o600),
+            ..UnixSocketOptions::default()
+        };
+
+        assert!(test_bind(&listener, &options).is_ok());
+        assert!(!socket_path.exists());
+
+        // stale socket files are skipped (only) if they'll be removed on startup
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+
+        assert!(test_bind(&listener, &options).is_err());
+        assert!(
+            test_bind(
+                &listener,
+                &UnixSocketOptions {
+                    remove_stale: true,
+                    ..options
+                }
+            )
+            .is_ok()
+        );
+        assert!(socket_path.exists());
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[test]
+    fn link_store_file() {
+        let temp_dir = std::env::temp_dir();
+
+        assert!(check_link_store_file(&temp_dir.join("searproxy-check-links.txt")).is_ok());
+        assert!(check_link_store_file(&temp_dir.join("searproxy-missing/links.txt")).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+use crate::model::ProxyCommand;
+
+pub use check_config::check_config;
+
+mod check_config;
+mod rewrite;
+mod sign;
+mod verify;
+
+/// Runs the command (with the shared values already set) and returns its exit code.
+pub fn run(command: ProxyCommand) -> i32 {
+    match command {
+        ProxyCommand::Rewrite { base_url, css, csp } => rewrite::rewrite(base_url, css, csp),
+        ProxyCommand::Sign { url } => sign::sign(&url),
+        ProxyCommand::Verify { proxy_url } => verify::verify(&proxy_url),
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    io::{Read, Write},
+    rc::Rc,
+};
+
+use crate::{
+    server::lib::get_content_security_policy,
+    utilities::{
+        ClientError, CssRewrite, HtmlRewrite, create_style_nonce, detect_html_encoding,
+        get_shared_state,
+    },
+};
+
+/// Rewrites the document from stdin, which makes the sanitizer reproducible without fetching the page.
+pub fn rewrite(base_url: url::Url, css: bool, csp: bool) -> i32 {
+    let mut input = Vec::new();
+
+    if let Err(err) = std::io::stdin().read_to_end(&mut input) {
+        eprintln!("Failed to read the input: {err}");
+        return 1;
+    }
+
+    // like the server, which only sets a style nonce for HTML documents
+    let style_nonce_opt = if css {
+        None
+    } else {
+        match create_style_nonce() {
+            Ok(style_nonce) => Some(style_nonce),
+            Err(err) => {
+                eprintln!("Failed to create the style nonce: {err}");
+                return 1;
+            }
+        }
+    };
+
+    let output = match rewrite_document(Rc::new(base_url), &input, style_nonce_opt.clone()) {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Failed to rewrite the input: {err}");
+            return 1;
+        }
+    };
+
+    if let Err(err) = std::io::stdout().write_all(&output) {
+        eprintln!("Failed to write the output: {err}");
+        return 1;
+    }
+
+    if csp {
+        eprintln!(
+            "{}",
+            String::from_utf8_lossy(
+                get_content_security_policy(style_nonce_opt.as_deref()).as_bytes()
+            )
+        );
+    }
+
+    0
+}
+
+/// Rewrites the input as stylesheet without a style nonce, or as HTML document otherwise.
+fn rewrite_document(
+    base_url: Rc<url::Url>,
+    input: &[u8],
+    style_nonce_opt: Option<Rc<str>>,
+) -> Result<Vec<u8>, ClientError> {
+    let Some(style_nonce) = style_nonce_opt else {
+        let mut rewriter = CssRewrite::new(get_shared_state(), base_url);
+
+        rewriter.write(input)?;
+
+        return Ok(rewriter.end()?);
+    };
+
+    // like fetched documents without a charset in their "Content-Type" header
+    let mut rewriter = HtmlRewrite::with_encoding(
+        get_shared_state(),
+        base_url,
+        Some(style_nonce),
+        detect_html_encoding(&mime::TEXT_HTML, input),
+    );
+
+    rewriter.write(input)?;
+
+    Ok(rewriter.end()?.html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rewrite_document;
+
+    #[test]
+    fn rewrite_html() {
+        crate::utilities::test_setup_hmac();
+
+        let html = String::from_utf8(
+            rewrite_document(
+                std::rc::Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+                b"<html><head><style>body { color: red; }</style><script>alert(1)</script></head><body><a href=\"/index.html\">index</a></body></html>",
+                Some(std::rc::Rc::from("bm9uY2U=")),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<style nonce=\"bm9uY2U=\">"));
+        assert!(html.contains("./?url=https%3A%2F%2Fwww.example.com%2Findex.html&hash=7554946c4d3998da8be40b803c938c943f3dbbbb78958addd008b55bcacfb8c0"));
+    }
+
+    #[test]
+    fn rewrite_css() {
+        crate::utilities::test_setup_hmac();
+
+        let output = rewrite_document(
+            std::rc::Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            b"body { background: url(/main.css); }",
+            None,
+        )
+        .unwrap();
+
+        assert!(
+            String::from_utf8(output)
+                .unwrap()
+                .contains("./?url=https%3A%2F%2Fwww.example.com%2Fmain.css&hash=")
+        );
+    }
+}
@@ -0,0 +1,27 @@
+use crate::utilities::{RewriteUrlError, get_proxy_url, get_shared_state, get_url_expiry};
+
+/// Prints the proxy URL (relative to the SearProxy base URL), which `rewrite_url` would produce.
+pub fn sign(url: &str) -> i32 {
+    let shared_state = get_shared_state();
+    let proxy_url_res = url::Url::parse(url)
+        .map_err(RewriteUrlError::from)
+        .and_then(|target_url| {
+            get_proxy_url(
+                &shared_state,
+                target_url,
+                get_url_expiry(&shared_state),
+                "./p/",
+            )
+        });
+
+    match proxy_url_res {
+        Ok(proxy_url) => {
+            println!("{proxy_url}");
+            0
+        }
+        Err(err) => {
+            eprintln!("Failed to sign '{url}': {err}");
+            1
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use base64::Engine;
+
+use crate::{
+    model::IndexHttpArgs,
+    utilities::{
+        BASE64_URL_ENGINE, GLOBAL_CONFIG, HMAC, HmacKeys, SignedUrl, URL_CIPHER, UrlCipher,
+        UrlCipherError, get_unix_timestamp, verify_url_expiry,
+    },
+};
+
+/// Relative proxy URLs are resolved against this URL, since only the path and query are relevant.
+const BASE_URL: &str = "http://searproxy.invalid/";
+
+#[derive(thiserror::Error, Debug)]
+enum VerifyError {
+    #[error("HMAC instance uninitialized")]
+    HmacInstance,
+    #[error("URL cipher uninitialized")]
+    UrlCipherInstance,
+    #[error("Proxy URL parsing failed")]
+    UrlParse(#[from] url::ParseError),
+    #[error("Proxy URL query parsing failed")]
+    Query(#[from] serde_qs::Error),
+    #[error("Proxy URL doesn't contain a signed URL")]
+    MissingUrl,
+    #[error("Short links can only be resolved by the server")]
+    Link,
+    #[error("Hash isn't valid hexadecimal")]
+    Hex(#[from] hex::FromHexError),
+    #[error("Hash or URL isn't valid Base64")]
+    Base64(#[from] base64::DecodeError),
+    #[error("URL isn't valid UTF-8")]
+    Utf8String(#[from] std::string::FromUtf8Error),
+    #[error("Encrypted URL couldn't be decrypted with any key")]
+    UrlCipher(#[from] UrlCipherError),
+}
+
+#[derive(Debug, PartialEq)]
+struct Verification {
+    expires: Option<u64>,
+    /// Index of the matching key, `0` is the primary key.
+    key_index: Option<usize>,
+    url: String,
+}
+
+/// Prints whether the proxy URL is valid and which key it was signed (or encrypted) with.
+pub fn verify(proxy_url: &str) -> i32 {
+    let verification_res = match (HMAC.get(), URL_CIPHER.get()) {
+        (Some(hmac_keys), Some(url_cipher)) => verify_proxy_url(proxy_url, &hmac_keys, &url_cipher),
+        (None, _) => Err(VerifyError::HmacInstance),
+        (_, None) => Err(VerifyError::UrlCipherInstance),
+    };
+    let verification = match verification_res {
+        Ok(verification) => verification,
+        Err(err) => {
+            eprintln!("Invalid: {err}");
+            return 1;
+        }
+    };
+    let expiry_res = verify_url_expiry(
+        verification.expires,
+        GLOBAL_CONFIG
+            .get()
+            .is_some_and(|config| config.require_url_expiry),
+        get_unix_timestamp(),
+    );
+
+    println!("URL: {}", verification.url);
+
+    match verification.expires {
+        Some(expires) => println!("Expires: {expires}"),
+        None => println!("Expires: never"),
+    }
+
+    match (verification.key_index, expiry_res) {
+        (Some(0), Ok(())) => println!("Valid: signed with the primary key"),
+        (Some(index), Ok(())) => println!("Valid: signed with verify-only key #{index}"),
+        (Some(_), Err(err)) => {
+            println!("Invalid: {err}");
+            return 1;
+        }
+        (None, _) => {
+            println!("Invalid: the hash doesn't match any key");
+            return 1;
+        }
+    }
+
+    0
+}
+
+fn verify_proxy_url(
+    proxy_url: &str,
+    hmac_keys: &HmacKeys,
+    url_cipher: &UrlCipher,
+) -> Result<Verification, VerifyError> {
+    let proxy_url = url::Url::parse(BASE_URL)?.join(proxy_url)?;
+    let query: IndexHttpArgs = serde_qs::from_str(proxy_url.query().unwrap_or_default())?;
+    let path_segments: Vec<&str> = proxy_url
+        .path_segments()
+        .map(Iterator::collect)
+        .unwrap_or_default();
+
+    if query.link.is_some() || matches!(path_segments.as_slice(), [.., "p", "l", _]) {
+        return Err(VerifyError::Link);
+    }
+
+    let signed_url = match path_segments.as_slice() {
+        [.., "p", hash, url] => SignedUrl::Path {
+            url,
+            hash,
+            expires: query.expires,
+        },
+        _ => query.signed_url().ok_or(VerifyError::MissingUrl)?,
+    };
+
+    match signed_url {
+        SignedUrl::Plain { url, hash, expires } => Ok(Verification {
+            expires,
+            key_index: hmac_keys.find_url_key(url, expires, &hex::decode(hash)?),
+            url: String::from(url),
+        }),
+        SignedUrl::Path { url, hash, expires } => {
+            let url = String::from_utf8(BASE64_URL_ENGINE.decode(url)?)?;
+
+            Ok(Verification {
+                expires,
+                key_index: hmac_keys.find_url_key(&url, expires, &BASE64_URL_ENGINE.decode(hash)?),
+                url,
+            })
+        }
+        SignedUrl::Opaque(opaque_url) => {
+            let (key_index, url, expires) = url_cipher.decrypt_with_key(opaque_url)?;
+
+            Ok(Verification {
+                expires,
+                key_index: Some(key_index),
+                url,
+            })
+        }
+        SignedUrl::Stored { .. } => Err(VerifyError::MissingUrl),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Verification, VerifyError, verify_proxy_url};
+    use crate::utilities::{HmacKeys, UrlCipher, get_path_url};
+
+    #[test]
+    fn verify_plain_and_path() {
+        let hmac_keys = HmacKeys::new(b"primary", [b"previous".as_slice()]).unwrap();
+        let url_cipher = UrlCipher::new(b"primary", []).unwrap();
+        let previous_tag = HmacKeys::new(b"previous", [])
+            .unwrap()
+            .sign_url("https://example.com/", Some(1_700_000_000));
+        let plain_url = format!(
+            "https://proxy.example.com/?url=https%3A%2F%2Fexample.com%2F&hash={}",
+            hex::encode(hmac_keys.sign_url("https://example.com/", None))
+        );
+
+        assert_eq!(
+            verify_proxy_url(&plain_url, &hmac_keys, &url_cipher).unwrap(),
+            Verification {
+                expires: None,
+                key_index: Some(0),
+                url: String::from("https://example.com/"),
+            }
+        );
+        assert_eq!(
+            verify_proxy_url(
+                &get_path_url(
+                    "./p/",
+                    &previous_tag,
+                    "https://example.com/",
+                    Some(1_700_000_000)
+                ),
+                &hmac_keys,
+                &url_cipher
+            )
+            .unwrap()
+            .key_index,
+            Some(1)
+        );
+        assert_eq!(
+            verify_proxy_url(
+                &format!(
+                    "./?url=https%3A%2F%2Fexample.com%2F&hash={}",
+                    hex::encode(&previous_tag)
+                ),
+                &hmac_keys,
+                &url_cipher
+            )
+            .unwrap()
+            .key_index,
+            None
+        );
+    }
+
+    #[test]
+    fn verify_opaque() {
+        let hmac_keys = HmacKeys::new(b"primary", []).unwrap();
+        let url_cipher = UrlCipher::new(b"primary", []).unwrap();
+        let opaque_url = url_cipher
+            .encrypt("https://example.com/", Some(1_700_000_000))
+            .unwrap();
+
+        assert_eq!(
+            verify_proxy_url(&format!("./?opaque={opaque_url}"), &hmac_keys, &url_cipher).unwrap(),
+            Verification {
+                expires: Some(1_700_000_000),
+                key_index: Some(0),
+                url: String::from("https://example.com/"),
+            }
+        );
+        assert!(matches!(
+            verify_proxy_url(
+                &format!("./?opaque={opaque_url}"),
+                &hmac_keys,
+                &UrlCipher::new(b"other", []).unwrap()
+            ),
+            Err(VerifyError::UrlCipher(_))
+        ));
+    }
+
+    #[test]
+    fn reject_unverifiable() {
+        let hmac_keys = HmacKeys::new(b"primary", []).unwrap();
+        let url_cipher = UrlCipher::new(b"primary", []).unwrap();
+
+        assert!(matches!(
+            verify_proxy_url("./?link=abc", &hmac_keys, &url_cipher),
+            Err(VerifyError::Link)
+        ));
+        assert!(matches!(
+            verify_proxy_url("./p/l/abc", &hmac_keys, &url_cipher),
+            Err(VerifyError::Link)
+        ));
+        assert!(matches!(
+            verify_proxy_url("./", &hmac_keys, &url_cipher),
+            Err(VerifyError::MissingUrl)
+        ));
+        assert!(matches!(
+            verify_proxy_url(
+                "./?url=https%3A%2F%2Fexample.com%2F&hash=xyz",
+                &hmac_keys,
+                &url_cipher
+            ),
+            Err(VerifyError::Hex(_))
+        ));
+    }
+}
@@ -26,98 +26,390 @@ use base64::Engine;
 use crate::model::AppState;
 
 mod assets;
+mod commands;
 mod model;
 mod server;
 mod templates;
 mod utilities;
 
 fn main() {
-    let config = get_config();
+    let (command_opt, mut config) = get_config().unwrap_or_else(|err| match err {
+        // includes the help and version output
+        model::ConfigError::Cli(err) => err.exit(),
+        err => exit_with_error(&err),
+    });
 
-    init_logging(&config);
-    log::debug!("{:?}", &config);
-    set_shared_values(AppState::try_from(config).unwrap());
-    server::start_http_service();
+    match command_opt {
+        Some(model::Command::CheckConfig) => {
+            std::process::exit(commands::check_config(config));
+        }
+        Some(model::Command::Proxy(command)) => {
+            // links stored by a command wouldn't be known to the server
+            config.link_store = None;
+            set_shared_values(
+                AppState::try_from(config).unwrap_or_else(|err| exit_with_error(&err)),
+            );
+            std::process::exit(commands::run(command));
+        }
+        None => {
+            init_logging(&config);
+            log::debug!("{:?}", &config);
+            set_shared_values(
+                AppState::try_from(config).unwrap_or_else(|err| exit_with_error(&err)),
+            );
+
+            let server_res = server::start_http_service();
+
+            // statics are never dropped, so the links queued for the writer thread have to be written explicitly
+            if let Some(link_store) = utilities::LINK_STORE.get() {
+                link_store.shutdown();
+            }
+
+            if let Err(err) = server_res {
+                exit_with_error(&err);
+            }
+        }
+    }
 }
 
-fn get_config() -> model::Config<'static, 'static> {
-    use clap::Parser;
+fn get_config()
+-> Result<(Option<model::Command>, model::Config<'static, 'static>), model::ConfigError> {
+    use clap::{CommandFactory, FromArgMatches};
+
+    let (command, config_file) = get_cli_command()?;
+    let matches = command.try_get_matches()?;
+    let mut args = model::Cli::from_arg_matches(&matches)
+        .map_err(|err| err.format(&mut model::Cli::command()))?;
+    let command_opt = args.command.take();
+    let hmac_secret = get_hmac_secret(&mut args, &matches)?;
 
-    let args: model::Cli = model::Cli::parse();
+    args.check_constraints()?;
 
-    model::Config {
+    let config = model::Config {
+        access_control: model::AccessControl::new(
+            args.allowed_ports.clone(),
+            args.allowed_schemes.clone(),
+            get_host_rules(&args, &config_file)?,
+        ),
+        api_tokens: args
+            .api_tokens
+            .iter()
+            .filter(|token| !token.is_empty())
+            .cloned()
+            .collect(),
+        cache_policy: args.cache_policy,
         connect_timeout: args.connect_timeout,
+        encrypt_urls: args.encrypt_urls,
         follow_redirects: args.follow_redirects,
         hmac_secret: std::borrow::Cow::Owned(
             utilities::BASE64_ENGINE
-                .decode(&args.hmac_secret)
-                .expect("HMAC secret couldn't be [base64] decoded"),
+                .decode(hmac_secret)
+                .map_err(model::ConfigError::HmacSecret)?,
         ),
+        hmac_verify_secrets: args
+            .hmac_verify_secrets
+            .iter()
+            .enumerate()
+            .map(|(index, secret)| {
+                utilities::BASE64_ENGINE
+                    .decode(secret)
+                    .map(std::borrow::Cow::Owned)
+                    .map_err(|err| model::ConfigError::HmacVerifySecret(index + 1, err))
+            })
+            .collect::<Result<_, _>>()?,
+        ip_rules: get_ip_rules(&args, &matches, &config_file)?,
         lazy_images: args.lazy_images,
-        listen: parse_socket_listener(&args.listen),
+        link_store: Some(args.link_ttl)
+            .filter(|ttl| *ttl > 0)
+            .map(|ttl| model::LinkStoreOptions {
+                file_path: args.link_store_file.clone(),
+                max_entries: args.link_store_entries,
+                threshold: args.link_threshold,
+                ttl,
+            }),
+        listen: args
+            .listen
+            .iter()
+            .map(|listener| parse_socket_listener(listener))
+            .collect::<Result<_, _>>()?,
         log_level: args.log_level,
+        max_passthrough_size: args.max_passthrough_size,
+        max_rewrite_size: Some(args.max_rewrite_size).filter(|max_size| *max_size > 0),
+        min_throughput: args.min_throughput,
+        path_urls: args.path_urls,
         permitted_ip_range: args.permitted_ip_range,
         request_timeout: args.request_timeout,
+        response_cache: Some(args.response_cache_size)
+            .filter(|max_size| *max_size > 0)
+            .map(|max_size| model::ResponseCacheLimits {
+                max_entries: args.response_cache_entries,
+                max_entry_size: args.response_cache_entry_size,
+                max_size,
+            }),
         proxy_address: args.proxy_address.map(std::borrow::Cow::Owned),
+        read_idle_timeout: Some(args.read_idle_timeout).filter(|timeout| *timeout > 0),
+        require_url_expiry: args.require_url_expiry,
+        unix_socket: model::UnixSocketOptions {
+            group: args
+                .socket_group
+                .as_deref()
+                .map(parse_socket_group)
+                .transpose()?,
+            mode: args.socket_mode,
+            remove_stale: args.remove_stale_sockets,
+        },
+        url_lifetime: Some(args.url_lifetime).filter(|lifetime| *lifetime > 0),
         worker_count: args.worker_count,
+    };
+
+    Ok((command_opt, config))
+}
+
+/// Prints the error (and its sources) in the same format as CLI errors and exits.
+fn exit_with_error(err: &dyn std::error::Error) -> ! {
+    eprintln!("error: {}", format_error(err));
+    std::process::exit(1);
+}
+
+/// Formats the error with all of its sources, e.g. "Access rules couldn't be parsed: Invalid rule ...".
+fn format_error(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source_opt = err.source();
+
+    while let Some(source) = source_opt {
+        message.push_str(": ");
+        message.push_str(&source.to_string());
+        source_opt = source.source();
+    }
+
+    message
+}
+
+/// Applies the values of the config file (if any) as defaults, its path has to be parsed before the other options.
+fn get_cli_command() -> Result<(clap::Command, model::ConfigFile), model::ConfigError> {
+    use clap::CommandFactory;
+
+    let matches = model::Cli::command().ignore_errors(true).get_matches();
+    let Some(path) = matches.get_one::<std::path::PathBuf>("config") else {
+        return Ok((model::Cli::command(), model::ConfigFile::default()));
+    };
+
+    model::ConfigFile::load(path)
+        .and_then(|config_file| {
+            Ok((
+                config_file.apply_defaults(model::Cli::command())?,
+                config_file,
+            ))
+        })
+        .map_err(|err| model::ConfigError::ConfigFile(path.clone(), err))
+}
+
+/// Reads the secret from the option with the highest precedence, either the secret itself or its file.
+fn get_hmac_secret(
+    args: &mut model::Cli,
+    matches: &clap::ArgMatches,
+) -> Result<String, model::ConfigError> {
+    let use_secret_file = args.hmac_secret_file.is_some()
+        && matches.value_source("hmac_secret_file") >= matches.value_source("hmac_secret");
+
+    match (args.hmac_secret.take(), args.hmac_secret_file.as_deref()) {
+        (_, Some(path)) if use_secret_file => std::fs::read_to_string(path)
+            .map(|hmac_secret| hmac_secret.trim().to_owned())
+            .map_err(|err| model::ConfigError::HmacSecretFile(path.to_owned(), err)),
+        (Some(hmac_secret), _) => Ok(hmac_secret),
+        _ => Err(model::ConfigError::MissingHmacSecret),
     }
 }
 
-fn parse_socket_listener(input: &str) -> model::SocketListener {
+fn get_host_rules(
+    args: &model::Cli,
+    config_file: &model::ConfigFile,
+) -> Result<Vec<model::HostRule>, model::ConfigError> {
+    let mut host_rules = model::parse_host_rules(&config_file.access_rules.join("\n"))?;
+
+    if let Some(path) = args.access_rules_file.as_deref() {
+        host_rules.extend(model::parse_host_rules(&read_rules_file(path)?)?);
+    }
+
+    Ok(host_rules)
+}
+
+fn get_ip_rules(
+    args: &model::Cli,
+    matches: &clap::ArgMatches,
+    config_file: &model::ConfigFile,
+) -> Result<Vec<model::IpRule>, model::ConfigError> {
+    let mut ip_rules = model::parse_ip_rules(&config_file.cidr_rules.join("\n"))?;
+
+    if let Some(path) = args.cidr_rules_file.as_deref() {
+        ip_rules.extend(model::parse_ip_rules(&read_rules_file(path)?)?);
+    }
+
+    let mut cli_ip_rules = Vec::with_capacity(args.allow_cidr.len() + args.deny_cidr.len());
+
+    for (action, id, cidr_list) in [
+        (model::IpRuleAction::Allow, "allow_cidr", &args.allow_cidr),
+        (model::IpRuleAction::Deny, "deny_cidr", &args.deny_cidr),
+    ] {
+        // values from ENV don't have an index, those will be checked after the passed options
+        let indices = matches
+            .indices_of(id)
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat(usize::MAX));
+
+        cli_ip_rules.extend(cidr_list.iter().zip(indices).map(|(cidr, index)| {
+            (
+                index,
+                model::IpRule {
+                    action,
+                    cidr: *cidr,
+                },
+            )
+        }));
+    }
+
+    cli_ip_rules.sort_by_key(|(index, _)| *index);
+    ip_rules.extend(cli_ip_rules.into_iter().map(|(_, ip_rule)| ip_rule));
+
+    Ok(ip_rules)
+}
+
+fn read_rules_file(path: &std::path::Path) -> Result<String, model::ConfigError> {
+    std::fs::read_to_string(path).map_err(|err| model::ConfigError::RulesFile(path.to_owned(), err))
+}
+
+/// Accepts a group id or the name of a group in "/etc/group".
+fn parse_socket_group(group: &str) -> Result<u32, model::ConfigError> {
+    if let Ok(group_id) = group.parse() {
+        return Ok(group_id);
+    }
+
+    #[cfg(unix)]
+    {
+        let group_file =
+            std::fs::read_to_string("/etc/group").map_err(model::ConfigError::GroupFile)?;
+
+        server::lib::find_group_id(&group_file, group)
+            .ok_or_else(|| model::ConfigError::SocketGroup(String::from(group)))
+    }
+
+    #[cfg(not(unix))]
+    {
+        Err(model::ConfigError::SocketGroup(String::from(group)))
+    }
+}
+
+fn parse_socket_listener(input: &str) -> Result<model::SocketListener, model::ConfigError> {
     use std::str::FromStr;
 
     if let Ok(address) = std::net::SocketAddr::from_str(input) {
-        return model::SocketListener::Tcp(address);
+        return Ok(model::SocketListener::Tcp(address));
     }
 
     #[cfg(unix)]
     {
+        // "./systemd" can be used for a socket file with that name
+        if input == "systemd" {
+            return Ok(model::SocketListener::Systemd(None));
+        }
+
+        if let Some(name) = input.strip_prefix("systemd:") {
+            return Ok(model::SocketListener::Systemd(Some(String::from(name))));
+        }
+
         let Ok(path) = std::path::PathBuf::from_str(input);
 
-        model::SocketListener::Unix(path)
+        Ok(model::SocketListener::Unix(path))
     }
 
     #[cfg(not(unix))]
     {
-        panic!("Listener could not be parsed: '{input}'")
+        Err(model::ConfigError::Listener(String::from(input)))
     }
 }
 
 fn init_logging(config: &model::Config<'_, '_>) {
-    if config.log_level != log::LevelFilter::Off {
-        let mut logger = fern::Dispatch::new().level(config.log_level);
-
-        if config.log_level != log::LevelFilter::Error {
-            logger = logger.chain(
-                fern::Dispatch::new()
-                    .filter(|meta| meta.level() != log::LevelFilter::Error)
-                    .chain(std::io::stdout()),
-            )
-        }
+    // the level is only filtered by the max level, so it can be changed by a reload
+    fern::Dispatch::new()
+        .level(log::LevelFilter::Trace)
+        .chain(
+            fern::Dispatch::new()
+                .filter(|meta| meta.level() != log::LevelFilter::Error)
+                .chain(std::io::stdout()),
+        )
+        .chain(
+            fern::Dispatch::new()
+                .level(log::LevelFilter::Error)
+                .chain(std::io::stderr()),
+        )
+        .apply()
+        .expect("logging subscriber registration failed");
+    log::set_max_level(config.log_level);
+}
 
-        logger
-            .chain(
-                fern::Dispatch::new()
-                    .level(log::LevelFilter::Error)
-                    .chain(std::io::stderr()),
-            )
-            .apply()
-            .expect("logging subscriber registration failed");
-    } else {
-        log::set_max_level(config.log_level);
+fn set_shared_values(mut app_state: AppState<'static, 'static>) {
+    if let Some(link_store) = app_state.link_store.take()
+        && utilities::LINK_STORE.set(link_store).is_err()
+    {
+        panic!("Failed to set link store");
+    }
+
+    if let Some(response_cache) = app_state.response_cache.take()
+        && utilities::RESPONSE_CACHE.set(response_cache).is_err()
+    {
+        panic!("Failed to set response cache");
     }
+
+    replace_shared_values(app_state);
 }
 
-fn set_shared_values(app_state: AppState<'static, 'static>) {
-    utilities::HMAC
-        .set(app_state.hmac)
-        .expect("Failed to set HMAC instance");
+/// Swaps the config dependent values, the link store and response cache (if any) have to be set separately.
+fn replace_shared_values(app_state: AppState<'static, 'static>) {
+    utilities::replace_shared_state(utilities::SharedState {
+        config: Some(std::sync::Arc::new(app_state.config)),
+        hmac: Some(std::sync::Arc::new(app_state.hmac)),
+        request_client: Some(std::sync::Arc::new(app_state.request_client)),
+        url_cipher: Some(std::sync::Arc::new(app_state.url_cipher)),
+    });
+}
 
-    utilities::REQUEST_CLIENT
-        .set(app_state.request_client)
-        .expect("Failed to set request client");
+/**
+ * Re-reads the options (including the config file) and replaces the shared values.
+ * The previous values are kept if the new options are invalid.
+ **/
+fn reload_shared_values() {
+    let app_state_res = get_config()
+        .map_err(|err| format_error(&err))
+        .and_then(|(_, config)| AppState::reload(config).map_err(|err| format_error(&err)));
+    let app_state = match app_state_res {
+        Ok(app_state) => app_state,
+        Err(message) => {
+            log::error!("Config reload rejected: {message}");
+            return;
+        }
+    };
+    let previous_config_opt = utilities::GLOBAL_CONFIG.get();
+
+    if let Some(previous_config) = previous_config_opt.as_deref()
+        && (previous_config.listen != app_state.config.listen
+            || previous_config.unix_socket != app_state.config.unix_socket
+            || previous_config.worker_count != app_state.config.worker_count
+            || previous_config.link_store != app_state.config.link_store
+            || previous_config.response_cache != app_state.config.response_cache)
+    {
+        log::warn!(
+            "Changes to the listener, worker count, link store and response cache require a restart"
+        );
+    }
+
+    log::set_max_level(app_state.config.log_level);
+    replace_shared_values(app_state);
+
+    // cached responses were fetched with the previous config (e.g. its access rules and cache policy)
+    if let Some(response_cache) = utilities::RESPONSE_CACHE.get() {
+        response_cache.clear();
+    }
 
-    utilities::GLOBAL_CONFIG
-        .set(app_state.config)
-        .expect("Failed to set global config");
+    log::info!("Config reloaded");
 }
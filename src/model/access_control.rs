@@ -0,0 +1,249 @@
+use std::str::FromStr;
+
+/// Default ports which may be requested, if the operator didn't configure any.
+pub const DEFAULT_ALLOWED_PORTS: [u16; 2] = [80, 443];
+/// Default schemes which may be requested, if the operator didn't configure any.
+pub const DEFAULT_ALLOWED_SCHEMES: [&str; 2] = ["http", "https"];
+
+const REGEX_PATTERN_PREFIX: &str = "regex:";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HostRuleAction {
+    Allow,
+    Deny,
+}
+
+/// Host rule, which matches either a glob ("*.example.com") or a regular expression ("regex:^example\.(com|net)$").
+#[derive(Debug, Clone)]
+pub struct HostRule {
+    pub action: HostRuleAction,
+    pattern: regex::Regex,
+    /// Operator-defined reason which will be shown to the user, if this rule denies a request.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    allowed_ports: Vec<u16>,
+    allowed_schemes: Vec<String>,
+    host_rules: Vec<HostRule>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HostRuleParseError {
+    #[error("Unknown rule action `{0}` (expected \"allow\" or \"deny\")")]
+    Action(String),
+    #[error("Missing host pattern")]
+    MissingPattern,
+    #[error("Invalid regular expression")]
+    Regex(#[from] regex::Error),
+    #[error("Invalid rule `{1}` on line {0}")]
+    Line(usize, String),
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum AccessError {
+    #[error("The host `{0}` is denied")]
+    Host(String, Option<String>),
+    #[error("The port `{0}` is not permitted")]
+    Port(u16),
+    #[error("The scheme `{0}` is not permitted")]
+    Scheme(String),
+}
+
+impl HostRule {
+    pub fn matches(&self, host: &str) -> bool {
+        self.pattern.is_match(host)
+    }
+}
+
+impl FromStr for HostRule {
+    type Err = HostRuleParseError;
+
+    /// Parses a rule in the form of "<allow|deny> <pattern> [reason]".
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.trim().splitn(3, char::is_whitespace);
+        let action = match parts.next().unwrap_or_default() {
+            "allow" => HostRuleAction::Allow,
+            "deny" => HostRuleAction::Deny,
+            action_str => return Err(HostRuleParseError::Action(String::from(action_str))),
+        };
+        let pattern_str = parts
+            .next()
+            .filter(|pattern_str| !pattern_str.is_empty())
+            .ok_or(HostRuleParseError::MissingPattern)?;
+        let pattern = match pattern_str.strip_prefix(REGEX_PATTERN_PREFIX) {
+            Some(regex_str) => regex::RegexBuilder::new(regex_str)
+                .case_insensitive(true)
+                .build()?,
+            None => glob_to_regex(pattern_str)?,
+        };
+
+        Ok(Self {
+            action,
+            pattern,
+            reason: parts
+                .next()
+                .map(str::trim)
+                .filter(|reason| !reason.is_empty())
+                .map(String::from),
+        })
+    }
+}
+
+impl AccessControl {
+    pub fn new(
+        allowed_ports: Vec<u16>,
+        allowed_schemes: Vec<String>,
+        host_rules: Vec<HostRule>,
+    ) -> Self {
+        Self {
+            allowed_ports,
+            allowed_schemes: allowed_schemes
+                .into_iter()
+                .map(|scheme| scheme.to_ascii_lowercase())
+                .collect(),
+            host_rules,
+        }
+    }
+
+    /// Checks the scheme, port and host of the given URL, without resolving the host.
+    pub fn verify_url(&self, url: &url::Url) -> Result<(), AccessError> {
+        if !self
+            .allowed_schemes
+            .iter()
+            .any(|scheme| scheme == url.scheme())
+        {
+            return Err(AccessError::Scheme(String::from(url.scheme())));
+        }
+
+        if let Some(port) = url.port_or_known_default()
+            && !self.allowed_ports.contains(&port)
+        {
+            return Err(AccessError::Port(port));
+        }
+
+        let host = url.host_str().unwrap_or_default();
+
+        match self.host_rules.iter().find(|rule| rule.matches(host)) {
+            Some(rule) if rule.action == HostRuleAction::Deny => {
+                Err(AccessError::Host(String::from(host), rule.reason.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for AccessControl {
+    fn default() -> Self {
+        Self::new(
+            Vec::from(DEFAULT_ALLOWED_PORTS),
+            DEFAULT_ALLOWED_SCHEMES.map(String::from).into(),
+            Vec::new(),
+        )
+    }
+}
+
+/**
+ * Parses a list of host rules with one rule per line ("<allow|deny> <pattern> [reason]").
+ * Empty lines and lines starting with "#" will be ignored.
+ **/
+pub fn parse_host_rules(input: &str) -> Result<Vec<HostRule>, HostRuleParseError> {
+    input
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            HostRule::from_str(line)
+                .map_err(|err| HostRuleParseError::Line(index + 1, format!("{line}: {err}")))
+        })
+        .collect()
+}
+
+/// Converts a glob pattern into an anchored, case-insensitive regular expression.
+/// `*` matches any amount of characters (including dots), `?` matches exactly one character.
+fn glob_to_regex(glob: &str) -> Result<regex::Regex, regex::Error> {
+    let mut pattern = String::with_capacity(glob.len() + 8);
+
+    pattern.push('^');
+
+    for char in glob.chars() {
+        match char {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            _ => pattern.push_str(&regex::escape(char.encode_utf8(&mut [0u8; 4]))),
+        }
+    }
+
+    pattern.push('$');
+
+    regex::RegexBuilder::new(&pattern)
+        .case_insensitive(true)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{AccessControl, AccessError, parse_host_rules};
+
+    fn verify(access_control: &AccessControl, url: &str) -> Result<(), AccessError> {
+        access_control.verify_url(&url::Url::from_str(url).unwrap())
+    }
+
+    #[test]
+    fn default_ports_schemes() {
+        let access_control = AccessControl::default();
+
+        assert!(verify(&access_control, "http://example.com/").is_ok());
+        assert!(verify(&access_control, "https://example.com:443/").is_ok());
+        assert!(matches!(
+            verify(&access_control, "https://example.com:8443/"),
+            Err(AccessError::Port(8443))
+        ));
+        assert!(matches!(
+            verify(&access_control, "ftp://example.com/"),
+            Err(AccessError::Scheme(_))
+        ));
+        assert!(matches!(
+            verify(&access_control, "file:///etc/passwd"),
+            Err(AccessError::Scheme(_))
+        ));
+    }
+
+    #[test]
+    fn host_rules_in_order() {
+        let access_control = AccessControl::new(
+            vec![80, 443],
+            vec![String::from("HTTPS")],
+            parse_host_rules(
+                "# comment\n\
+                allow wiki.example.com\n\
+                deny *.example.com\n\
+                deny regex:^(www\\.)?takedown\\.(com|net)$ Removed following a legal takedown notice.\n",
+            )
+            .unwrap(),
+        );
+
+        assert!(verify(&access_control, "https://wiki.example.com/").is_ok());
+        assert!(verify(&access_control, "https://example.com/").is_ok());
+        assert!(verify(&access_control, "https://takedown.org/").is_ok());
+        assert!(matches!(
+            verify(&access_control, "https://A.B.Example.com/"),
+            Err(AccessError::Host(host, None)) if host == "a.b.example.com"
+        ));
+        assert!(matches!(
+            verify(&access_control, "https://www.takedown.net/path"),
+            Err(AccessError::Host(_, Some(reason))) if reason == "Removed following a legal takedown notice."
+        ));
+    }
+
+    #[test]
+    fn host_rules_parse_error() {
+        assert!(parse_host_rules("permit example.com").is_err());
+        assert!(parse_host_rules("deny").is_err());
+        assert!(parse_host_rules("deny regex:(").is_err());
+    }
+}
@@ -1,27 +1,50 @@
-use crate::{model::Config, utilities::HmacInstance};
+use crate::{
+    model::Config,
+    utilities::{
+        HmacKeys, LinkStore, PermittedIpResolver, ResponseCache, UrlCipher, UrlCipherError,
+    },
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppStateError {
     #[error("Invalid HMAC secret")]
     Hmac(#[from] hmac::digest::InvalidLength),
+    #[error("Failed to derive URL encryption key")]
+    UrlCipher(#[from] UrlCipherError),
+    #[error("Failed to load link store")]
+    LinkStore(#[from] std::io::Error),
     #[error("Failed to create request client")]
     RequestClient(#[from] reqwest::Error),
 }
 
 pub struct AppState<'secret, 'proxy> {
     pub config: Config<'secret, 'proxy>,
-    pub hmac: HmacInstance,
+    pub hmac: HmacKeys,
+    pub link_store: Option<LinkStore>,
     pub request_client: reqwest::Client,
+    pub response_cache: Option<ResponseCache>,
+    pub url_cipher: UrlCipher,
 }
 
-impl<'secret, 'proxy> TryFrom<Config<'secret, 'proxy>> for AppState<'secret, 'proxy> {
-    type Error = AppStateError;
-
-    fn try_from(config: Config<'secret, 'proxy>) -> Result<Self, Self::Error> {
-        use hmac::digest::KeyInit;
+impl<'secret, 'proxy> AppState<'secret, 'proxy> {
+    /**
+     * Creates the state for a reloaded config, which doesn't contain a link store or response cache.
+     * Those are created once on startup, since they can't be replaced without losing their entries.
+     **/
+    pub fn reload(config: Config<'secret, 'proxy>) -> Result<Self, AppStateError> {
+        Self::new(config, false)
+    }
 
+    fn new(config: Config<'secret, 'proxy>, with_stores: bool) -> Result<Self, AppStateError> {
         Ok(Self {
-            hmac: hmac::Hmac::new_from_slice(config.hmac_secret.as_ref())?,
+            hmac: HmacKeys::new(
+                config.hmac_secret.as_ref(),
+                config.hmac_verify_secrets.iter().map(AsRef::as_ref),
+            )?,
+            url_cipher: UrlCipher::new(
+                config.hmac_secret.as_ref(),
+                config.hmac_verify_secrets.iter().map(AsRef::as_ref),
+            )?,
             request_client: {
                 let mut request_client_builder = reqwest::Client::builder()
                     .referer(false)
@@ -36,6 +59,18 @@ impl<'secret, 'proxy> TryFrom<Config<'secret, 'proxy>> for AppState<'secret, 'pr
                         "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:91.0) Gecko/20100101 Firefox/91.0",
                     );
 
+                request_client_builder = request_client_builder.dns_resolver(std::sync::Arc::new(
+                    PermittedIpResolver::new(
+                        config.permitted_ip_range,
+                        std::sync::Arc::from(config.ip_rules.as_slice()),
+                        config
+                            .proxy_address
+                            .as_deref()
+                            .and_then(|proxy_address| url::Url::parse(proxy_address).ok())
+                            .and_then(|proxy_url| proxy_url.host_str().map(String::from)),
+                    ),
+                ));
+
                 if let Some(request_timeout) = config.request_timeout {
                     request_client_builder = request_client_builder
                         .timeout(std::time::Duration::from_secs(request_timeout as u64));
@@ -48,7 +83,30 @@ impl<'secret, 'proxy> TryFrom<Config<'secret, 'proxy>> for AppState<'secret, 'pr
 
                 request_client_builder.build()?
             },
+            link_store: match &config.link_store {
+                Some(options) if with_stores => Some(LinkStore::new(
+                    options.ttl,
+                    options.max_entries,
+                    options.file_path.as_deref(),
+                )?),
+                _ => None,
+            },
+            response_cache: config.response_cache.filter(|_| with_stores).map(|limits| {
+                ResponseCache::new(
+                    usize::try_from(limits.max_size).unwrap_or(usize::MAX),
+                    limits.max_entries,
+                    usize::try_from(limits.max_entry_size).unwrap_or(usize::MAX),
+                )
+            }),
             config,
         })
     }
 }
+
+impl<'secret, 'proxy> TryFrom<Config<'secret, 'proxy>> for AppState<'secret, 'proxy> {
+    type Error = AppStateError;
+
+    fn try_from(config: Config<'secret, 'proxy>) -> Result<Self, Self::Error> {
+        Self::new(config, true)
+    }
+}
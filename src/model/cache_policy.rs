@@ -0,0 +1,75 @@
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+/// `Cache-Control` policy for responses which aren't HTML documents (e.g. images, stylesheets).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// Clients have to revalidate every response (with `ETag` / `Last-Modified`).
+    NoCache,
+    /// Forward the upstream `Cache-Control` / `Expires` header.
+    Upstream,
+    /// Clients may reuse a response for the given amount of seconds.
+    MaxAge(u32),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CachePolicyParseError {
+    #[error("Unknown cache policy `{0}`")]
+    Unrecognized(String),
+}
+
+impl Display for CachePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoCache => f.write_str("no-cache"),
+            Self::Upstream => f.write_str("upstream"),
+            Self::MaxAge(seconds) => write!(f, "max-age={seconds}"),
+        }
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = CachePolicyParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "no-cache" => CachePolicy::NoCache,
+            "upstream" => CachePolicy::Upstream,
+            _ => match value
+                .strip_prefix("max-age=")
+                .and_then(|seconds| u32::from_str(seconds).ok())
+            {
+                Some(seconds) => CachePolicy::MaxAge(seconds),
+                None => return Err(CachePolicyParseError::Unrecognized(String::from(value))),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::CachePolicy;
+
+    #[test]
+    fn parse_cache_policy() {
+        assert_eq!(
+            CachePolicy::from_str("no-cache").unwrap(),
+            CachePolicy::NoCache
+        );
+        assert_eq!(
+            CachePolicy::from_str("upstream").unwrap(),
+            CachePolicy::Upstream
+        );
+        assert_eq!(
+            CachePolicy::from_str("max-age=3600").unwrap(),
+            CachePolicy::MaxAge(3600)
+        );
+        assert_eq!(CachePolicy::MaxAge(60).to_string(), "max-age=60");
+        assert!(CachePolicy::from_str("max-age=").is_err());
+        assert!(CachePolicy::from_str("forever").is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use crate::model::PermittedIpRange;
+use crate::model::{
+    CachePolicy, DEFAULT_ALLOWED_PORTS, DEFAULT_ALLOWED_SCHEMES, IpCidr, PermittedIpRange,
+};
 
 const ABOUT_WITH_LICENSE: &str = "This is a SearX & SearXNG compatible web proxy which \
 excludes potentially malicious HTML tags. It also rewrites links to external resources \
@@ -22,47 +24,237 @@ the OpenSSL Toolkit. <https://www.openssl.org/>";
 /// This is free software, and you are welcome to redistribute it under certain conditions;
 /// type `--help` for more details.
 #[derive(clap::Parser, Debug)]
-#[clap(version, about, long_about = Some(ABOUT_WITH_LICENSE))]
+#[clap(version, about, long_about = Some(ABOUT_WITH_LICENSE), subcommand_negates_reqs = true)]
 pub struct Cli {
+    /// Runs the given command instead of the HTTP server.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file with options (named like the CLI options, e.g. `hmac_secret`), which are overridden by
+    /// environment variables and flags.
+    #[clap(long, env = "SEARPROXY_CONFIG")]
+    pub config: Option<std::path::PathBuf>,
+    /// File with one host rule per line ("<allow|deny> <pattern> [reason]").
+    /// Patterns are either globs ("*.example.com") or regular expressions ("regex:^example\.com$"),
+    /// the first matching rule applies and the optional reason will be shown to the user.
+    #[clap(long, env = "SEARPROXY_ACCESS_RULES_FILE")]
+    pub access_rules_file: Option<std::path::PathBuf>,
+    /// Allow outgoing requests to the given IP (v4, v6) network, e.g. "10.20.0.0/16".
+    /// Can be repeated, all CIDR rules are checked in the given order before the permitted IP range.
+    #[clap(long, env = "SEARPROXY_ALLOW_CIDR", value_delimiter = ',')]
+    pub allow_cidr: Vec<IpCidr>,
+    /// File with one CIDR rule per line ("allow <CIDR>" / "deny <CIDR>").
+    /// These rules are checked before any "--allow-cidr" / "--deny-cidr" rule.
+    #[clap(long, env = "SEARPROXY_CIDR_RULES_FILE")]
+    pub cidr_rules_file: Option<std::path::PathBuf>,
+    /// Deny outgoing requests to the given IP (v4, v6) network, e.g. "203.0.113.0/24".
+    /// Can be repeated, all CIDR rules are checked in the given order before the permitted IP range.
+    #[clap(long, env = "SEARPROXY_DENY_CIDR", value_delimiter = ',')]
+    pub deny_cidr: Vec<IpCidr>,
+    /// Comma separated bearer tokens which are accepted by the URL signing API ("POST /api/sign").
+    /// The API rejects every request without tokens.
+    #[clap(long, env = "SEARPROXY_API_TOKENS", value_delimiter = ',')]
+    pub api_tokens: Vec<String>,
+    /// Rewrite URLs into an encrypted form, so the target URL doesn't show up in the browser history or access logs.
+    /// The encryption key is derived from the HMAC secret. Can't be combined with `--path-urls`.
+    #[clap(long, env = "SEARPROXY_ENCRYPT_URLS")]
+    pub encrypt_urls: bool,
     /// Allow "Location" response header following.
     #[clap(short, long, env = "SEARPROXY_FOLLOW_REDIRECTS")]
     pub follow_redirects: bool,
     /// Base64 encoded string to use as HMAC 256 secret.
+    /// Required unless `--hmac-secret-file` is given.
     #[clap(short = 's', long, env = "SEARPROXY_HMAC_SECRET")]
-    pub hmac_secret: String,
+    pub hmac_secret: Option<String>,
+    /// File which contains the Base64 encoded HMAC secret, e.g. a systemd credential or Docker secret.
+    #[clap(long, env = "SEARPROXY_HMAC_SECRET_FILE")]
+    pub hmac_secret_file: Option<std::path::PathBuf>,
+    /// Base64 encoded secrets which are only used to verify URLs (e.g. the previous secret after a rotation).
+    /// URLs will always be signed with the HMAC secret.
+    #[clap(long, env = "SEARPROXY_HMAC_VERIFY_SECRETS", value_delimiter = ',')]
+    pub hmac_verify_secrets: Vec<String>,
     /// Enable IMG element rewriting with "lazy" loading.
     /// Since this can be used to measure the clients scroll position, it's disabled by default.
     #[clap(long, env = "SEARPROXY_LAZY_IMAGES")]
     pub lazy_images: bool,
-    /// <IPv4 / IPv6>:port or socket to listen on.
-    #[clap(short, long, env = "SEARPROXY_LISTEN")]
-    pub listen: String,
+    /// <IPv4 / IPv6>:port or Unix socket path to listen on.
+    /// Can be repeated, e.g. a Unix socket for the reverse proxy and a loopback port for health checks.
+    #[clap(
+        short,
+        long,
+        env = "SEARPROXY_LISTEN",
+        value_delimiter = ',',
+        required = true
+    )]
+    pub listen: Vec<String>,
+    /// Remove existing Unix socket files on startup, if no process accepts connections on them anymore.
+    #[clap(long, env = "SEARPROXY_REMOVE_STALE_SOCKETS")]
+    pub remove_stale_sockets: bool,
+    /// Group (name or id) which will own the Unix socket files.
+    #[clap(long, env = "SEARPROXY_SOCKET_GROUP")]
+    pub socket_group: Option<String>,
+    /// Octal permissions of the Unix socket files (e.g. "660"), otherwise those depend on the umask.
+    #[clap(long, env = "SEARPROXY_SOCKET_MODE", value_parser = parse_socket_mode)]
+    pub socket_mode: Option<u32>,
     /// Log level to use. Keep in mind that this can include PII.
     /// Possible values include: "off", "error", "warn", "info", "debug", "trace".
     #[clap(short = 'v', long, env = "SEARPROXY_LOG_LEVEL", default_value_t = log::LevelFilter::Warn)]
     pub log_level: log::LevelFilter,
+    /// Maximum size in bytes of responses which are forwarded without rewriting (e.g. images).
+    #[clap(long, env = "SEARPROXY_MAX_PASSTHROUGH_SIZE")]
+    pub max_passthrough_size: Option<u64>,
+    /// Maximum size in bytes of documents which will be rewritten (HTML, CSS), "0" disables the limit.
+    #[clap(long, env = "SEARPROXY_MAX_REWRITE_SIZE", default_value_t = 10_485_760)]
+    pub max_rewrite_size: u64,
+    /// Minimum average throughput in bytes per second while receiving a response body.
+    /// It's only enforced after the first 5 seconds.
+    #[clap(long, env = "SEARPROXY_MIN_THROUGHPUT")]
+    pub min_throughput: Option<u64>,
+    /// Rewrite URLs into the shorter path form "p/<base64url hash>/<base64url URL>".
+    #[clap(long, env = "SEARPROXY_PATH_URLS")]
+    pub path_urls: bool,
     /// Permitted IP (v4, v6) ranges
     /// Possible values include: "none", "global", "private", "local".
     #[clap(short = 'r', long, env = "SEARPROXY_PERMITTED_IP_RANGE", default_value_t = PermittedIpRange::Global)]
     pub permitted_ip_range: PermittedIpRange,
+    /// Ports which may be requested.
+    #[clap(long, env = "SEARPROXY_ALLOWED_PORTS", value_delimiter = ',', default_values_t = DEFAULT_ALLOWED_PORTS)]
+    pub allowed_ports: Vec<u16>,
+    /// URL schemes which may be requested.
+    #[clap(long, env = "SEARPROXY_ALLOWED_SCHEMES", value_delimiter = ',', default_values_t = DEFAULT_ALLOWED_SCHEMES.map(String::from))]
+    pub allowed_schemes: Vec<String>,
     /// Use a HTTP(s) / SOCKS5 proxy for outgoing HTTP(s) requests.
     /// Examples: "http://exam.ple", "https://exam.ple", "socks5://exam.ple", "socks5h://exam.ple"
     #[clap(short, long, env = "HTTP_PROXY")]
     pub proxy_address: Option<String>,
+    /// Cache policy for responses which aren't HTML documents (e.g. images, stylesheets without expiring links).
+    /// Possible values include: "no-cache", "upstream", "max-age=<seconds>".
+    #[clap(long, env = "SEARPROXY_CACHE_POLICY", default_value_t = CachePolicy::NoCache)]
+    pub cache_policy: CachePolicy,
+    /// Maximum size in bytes of the in-memory response cache, "0" disables the cache.
+    /// Only responses which aren't rewritten (i.e. HTML documents and stylesheets) and are cacheable
+    /// according to their `Cache-Control` / `Expires` headers will be stored.
+    #[clap(long, env = "SEARPROXY_RESPONSE_CACHE_SIZE", default_value_t = 0)]
+    pub response_cache_size: u64,
+    /// Maximum number of responses in the response cache.
+    #[clap(long, env = "SEARPROXY_RESPONSE_CACHE_ENTRIES", default_value_t = 1024)]
+    pub response_cache_entries: usize,
+    /// Maximum size in bytes of a single response in the response cache.
+    #[clap(
+        long,
+        env = "SEARPROXY_RESPONSE_CACHE_ENTRY_SIZE",
+        default_value_t = 1_048_576
+    )]
+    pub response_cache_entry_size: u64,
+    /// Lifetime in seconds of short links, which replace rewritten URLs above the link threshold.
+    /// "0" disables short links.
+    #[clap(long, env = "SEARPROXY_LINK_TTL", default_value_t = 0)]
+    pub link_ttl: u64,
+    /// Target URL length in bytes above which rewritten URLs are replaced by short links.
+    #[clap(long, env = "SEARPROXY_LINK_THRESHOLD", default_value_t = 1024)]
+    pub link_threshold: usize,
+    /// File to persist short links to, so they survive a restart.
+    #[clap(long, env = "SEARPROXY_LINK_STORE_FILE")]
+    pub link_store_file: Option<std::path::PathBuf>,
+    /// Maximum number of short links, the links closest to their TTL are evicted first.
+    #[clap(long, env = "SEARPROXY_LINK_STORE_ENTRIES", default_value_t = 100_000)]
+    pub link_store_entries: usize,
     /// Timeout in seconds to wait for until the connection is established.
     #[clap(long, env = "SEARPROXY_CONNECT_TIMEOUT", default_value_t = 5)]
     pub connect_timeout: u8,
+    /// Timeout in seconds to wait for the next chunk of a response body, "0" disables the timeout.
+    #[clap(long, env = "SEARPROXY_READ_IDLE_TIMEOUT", default_value_t = 30)]
+    pub read_idle_timeout: u16,
     /// Timeout in seconds to wait for a request to complete.
     #[clap(short = 't', long, env = "SEARPROXY_REQUEST_TIMEOUT")]
     pub request_timeout: Option<u16>,
+    /// Lifetime in seconds of URLs which are rewritten by this proxy, "0" disables the expiry.
+    /// The expiry is covered by the hash, so expired URLs will be rejected.
+    #[clap(long, env = "SEARPROXY_URL_LIFETIME", default_value_t = 0)]
+    pub url_lifetime: u64,
+    /// Reject URLs without an expiry, e.g. URLs which were signed by SearXNG.
+    /// Requires a URL lifetime, since rewritten URLs would be rejected otherwise.
+    #[clap(long, env = "SEARPROXY_REQUIRE_URL_EXPIRY")]
+    pub require_url_expiry: bool,
     /// Worker thread count for handling incoming HTTP requests.
     #[clap(short = 'w', long, env = "SEARPROXY_WORKER_COUNT", default_value_t = 0)]
     pub worker_count: u8,
 }
 
+impl Cli {
+    /**
+     * Checks the constraints between options once their values are resolved.
+     * Values of the config file are defaults, which clap's `conflicts_with` ignores, and it would reject disabled flags
+     * (e.g. `--encrypt-urls=false`) as well.
+     **/
+    pub fn check_constraints(&self) -> Result<(), clap::Error> {
+        use clap::CommandFactory;
+
+        if self.require_url_expiry && self.url_lifetime == 0 {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--require-url-expiry requires a --url-lifetime greater than 0",
+            ));
+        }
+
+        if self.encrypt_urls && self.path_urls {
+            return Err(Self::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--encrypt-urls can't be combined with --path-urls",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_socket_mode(input: &str) -> Result<u32, String> {
+    match u32::from_str_radix(input, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(String::from("expected octal permissions, e.g. \"660\"")),
+    }
+}
+
+/// Commands use the same options (and environment variables) as the server, e.g. `--hmac-secret`.
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Validate the options (including the listener and request client) without starting the server,
+    /// and print the effective config with secrets redacted.
+    CheckConfig,
+    #[clap(flatten)]
+    Proxy(ProxyCommand),
+}
+
+/// Commands which run with the shared values (e.g. the HMAC keys) set, the same way the server does.
+#[derive(clap::Subcommand, Debug)]
+pub enum ProxyCommand {
+    /// Print the proxy URL for the given URL, as it would be rewritten by the server.
+    Sign {
+        /// Absolute target URL.
+        url: String,
+    },
+    /// Sanitize the document from stdin like a fetched response and print the result to stdout.
+    Rewrite {
+        /// URL the document was fetched from, relative URLs are resolved against it.
+        #[clap(long)]
+        base_url: url::Url,
+        /// The input is a stylesheet instead of an HTML document.
+        #[clap(long)]
+        css: bool,
+        /// Print the "Content-Security-Policy" header the server would send with the output to stderr.
+        /// Inline styles are allowed by a nonce instead of their hashes, "--hashes" prints the same header.
+        #[clap(long, alias = "hashes")]
+        csp: bool,
+    },
+    /// Check whether the given proxy URL is valid and which key it was signed with.
+    Verify {
+        /// Proxy URL, either absolute or relative to the SearProxy base URL (e.g. "./?url=...&hash=...").
+        proxy_url: String,
+    },
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{Cli, Command, ProxyCommand};
 
     #[test]
     fn verify_cli() {
@@ -70,4 +262,23 @@ mod tests {
 
         Cli::command().debug_assert()
     }
+
+    #[test]
+    fn rewrite_hashes_alias() {
+        use clap::Parser;
+
+        let args = Cli::try_parse_from([
+            "searproxy",
+            "rewrite",
+            "--base-url",
+            "https://example.com/",
+            "--hashes",
+        ])
+        .unwrap();
+
+        assert!(matches!(
+            args.command,
+            Some(Command::Proxy(ProxyCommand::Rewrite { csp: true, .. }))
+        ));
+    }
 }
@@ -0,0 +1,271 @@
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigFileError {
+    #[error("File couldn't be read")]
+    Read(#[from] std::io::Error),
+    #[error("TOML couldn't be parsed")]
+    Parse(#[from] toml::de::Error),
+    #[error("Unknown option `{0}`")]
+    UnknownOption(String),
+    #[error("Unsupported value for option `{0}`")]
+    UnsupportedValue(String),
+}
+
+/**
+ * TOML configuration file, which uses the same option names as the CLI (e.g. `hmac_secret`, `listen`).
+ * Rules can be given as lists of lines (`access_rules`, `cidr_rules`), which use the syntax of the rules files.
+ * Relative paths (e.g. `hmac_secret_file`) are resolved against the directory of the file.
+ **/
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    pub access_rules: Vec<String>,
+    pub cidr_rules: Vec<String>,
+    base_dir: std::path::PathBuf,
+    options: toml::Table,
+}
+
+impl ConfigFile {
+    pub fn load(path: &std::path::Path) -> Result<Self, ConfigFileError> {
+        Ok(Self {
+            base_dir: path
+                .parent()
+                .map(std::path::Path::to_path_buf)
+                .unwrap_or_default(),
+            ..Self::parse(&std::fs::read_to_string(path)?)?
+        })
+    }
+
+    pub fn parse(input: &str) -> Result<Self, ConfigFileError> {
+        let mut options: toml::Table = toml::from_str(input)?;
+
+        Ok(Self {
+            access_rules: take_lines(&mut options, "access_rules")?,
+            cidr_rules: take_lines(&mut options, "cidr_rules")?,
+            base_dir: std::path::PathBuf::new(),
+            options,
+        })
+    }
+
+    /**
+     * Sets the file values as defaults of the CLI options, so environment variables and flags take precedence.
+     * Flags which are enabled by the file can be disabled again by passing `false` (e.g. `--path-urls=false`).
+     **/
+    pub fn apply_defaults(
+        &self,
+        mut command: clap::Command,
+    ) -> Result<clap::Command, ConfigFileError> {
+        for (key, value) in &self.options {
+            let Some(is_path) = command
+                .get_arguments()
+                .find(|arg| key != "config" && arg.get_id() == key.as_str())
+                .map(|arg| {
+                    arg.get_value_parser().type_id() == std::any::TypeId::of::<std::path::PathBuf>()
+                })
+            else {
+                return Err(ConfigFileError::UnknownOption(key.clone()));
+            };
+            let values = match value {
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(scalar_to_string)
+                    .collect::<Option<Vec<_>>>(),
+                value => scalar_to_string(value).map(|value| vec![value]),
+            }
+            .ok_or_else(|| ConfigFileError::UnsupportedValue(key.clone()))?;
+            let values: Vec<std::ffi::OsString> = if is_path {
+                values
+                    .into_iter()
+                    .map(|value| self.base_dir.join(value).into_os_string())
+                    .collect()
+            } else {
+                values.into_iter().map(std::ffi::OsString::from).collect()
+            };
+
+            command = command.mut_arg(key, |arg| {
+                let arg = if matches!(arg.get_action(), clap::ArgAction::SetTrue) {
+                    // a default of `true` couldn't be overridden by the flag otherwise
+                    arg.action(clap::ArgAction::Set)
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("true")
+                        .value_parser(clap::builder::BoolishValueParser::new())
+                } else {
+                    arg
+                };
+
+                arg.default_values(values).required(false)
+            });
+        }
+
+        Ok(command)
+    }
+}
+
+fn scalar_to_string(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn take_lines(options: &mut toml::Table, key: &str) -> Result<Vec<String>, ConfigFileError> {
+    match options.remove(key) {
+        Some(toml::Value::Array(items)) => items
+            .into_iter()
+            .map(|item| match item {
+                toml::Value::String(line) => Ok(line),
+                _ => Err(ConfigFileError::UnsupportedValue(String::from(key))),
+            })
+            .collect(),
+        Some(_) => Err(ConfigFileError::UnsupportedValue(String::from(key))),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::{ConfigFile, ConfigFileError};
+    use crate::model::Cli;
+
+    const CONFIG_FILE: &str = r#"
+hmac_secret = "ZXhhbXBsZQ=="
+listen = "127.0.0.1:8080"
+url_lifetime = 3600
+encrypt_urls = true
+allowed_ports = [443, 8443]
+access_rules = ["deny *.example.com blocked"]
+"#;
+
+    fn parse_args(config_file: &ConfigFile, args: &[&str]) -> Cli {
+        let matches = config_file
+            .apply_defaults(Cli::command())
+            .unwrap()
+            .try_get_matches_from(std::iter::once("searproxy").chain(args.iter().copied()))
+            .unwrap();
+
+        Cli::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn file_values_as_defaults() {
+        let config_file = ConfigFile::parse(CONFIG_FILE).unwrap();
+        let args = parse_args(&config_file, &[]);
+
+        assert_eq!(args.hmac_secret.as_deref(), Some("ZXhhbXBsZQ=="));
+        assert_eq!(args.listen, ["127.0.0.1:8080"]);
+        assert_eq!(args.url_lifetime, 3600);
+        assert!(args.encrypt_urls);
+        assert_eq!(args.allowed_ports, [443, 8443]);
+        assert_eq!(config_file.access_rules, ["deny *.example.com blocked"]);
+    }
+
+    #[test]
+    fn flags_take_precedence() {
+        let config_file = ConfigFile::parse(CONFIG_FILE).unwrap();
+        let args = parse_args(
+            &config_file,
+            &["--listen", "[::1]:8080", "--url-lifetime", "60"],
+        );
+
+        assert_eq!(args.listen, ["[::1]:8080"]);
+        assert_eq!(args.url_lifetime, 60);
+    }
+
+    #[test]
+    fn conflicting_file_values() {
+        let config_file = ConfigFile::parse(CONFIG_FILE).unwrap();
+
+        assert!(parse_args(&config_file, &[]).check_constraints().is_ok());
+        assert!(
+            parse_args(&config_file, &["--path-urls"])
+                .check_constraints()
+                .is_err()
+        );
+        assert!(
+            parse_args(
+                &ConfigFile::parse(
+                    "listen = \"127.0.0.1:8080\"\nencrypt_urls = true\npath_urls = true"
+                )
+                .unwrap(),
+                &[]
+            )
+            .check_constraints()
+            .is_err()
+        );
+        assert!(
+            parse_args(&config_file, &["--path-urls", "--encrypt-urls=false"])
+                .check_constraints()
+                .is_ok()
+        );
+        assert!(
+            parse_args(
+                &config_file,
+                &["--require-url-expiry", "--url-lifetime", "0"]
+            )
+            .check_constraints()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn disable_file_flags() {
+        let config_file = ConfigFile::parse(
+            "listen = \"127.0.0.1:8080\"\nencrypt_urls = true\nlazy_images = false",
+        )
+        .unwrap();
+
+        assert!(!parse_args(&config_file, &["--encrypt-urls=false"]).encrypt_urls);
+        assert!(parse_args(&config_file, &["--encrypt-urls"]).encrypt_urls);
+        assert!(parse_args(&config_file, &["--lazy-images"]).lazy_images);
+        assert!(parse_args(&config_file, &["--encrypt-urls", "check-config"]).encrypt_urls);
+    }
+
+    #[test]
+    fn resolve_relative_paths() {
+        let config_file = ConfigFile {
+            base_dir: std::path::PathBuf::from("/etc/searproxy"),
+            ..ConfigFile::parse(
+                r#"
+listen = "127.0.0.1:8080"
+hmac_secret_file = "secret"
+access_rules_file = "/var/lib/searproxy/rules"
+"#,
+            )
+            .unwrap()
+        };
+        let args = parse_args(&config_file, &[]);
+
+        assert_eq!(
+            args.hmac_secret_file.as_deref(),
+            Some(std::path::Path::new("/etc/searproxy/secret"))
+        );
+        assert_eq!(
+            args.access_rules_file.as_deref(),
+            Some(std::path::Path::new("/var/lib/searproxy/rules"))
+        );
+    }
+
+    #[test]
+    fn reject_unknown_options() {
+        assert!(matches!(
+            ConfigFile::parse("hmac_secrets = \"abc\"")
+                .unwrap()
+                .apply_defaults(Cli::command()),
+            Err(ConfigFileError::UnknownOption(_))
+        ));
+        assert!(matches!(
+            ConfigFile::parse("[listen]\naddress = \"127.0.0.1:8080\"")
+                .unwrap()
+                .apply_defaults(Cli::command()),
+            Err(ConfigFileError::UnsupportedValue(_))
+        ));
+        assert!(matches!(
+            ConfigFile::parse("access_rules = \"allow *\""),
+            Err(ConfigFileError::UnsupportedValue(_))
+        ));
+    }
+}
@@ -1,7 +1,72 @@
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+use crate::utilities::SignedUrl;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
 pub struct IndexHttpArgs {
-    #[serde(alias = "mortyurl")]
+    #[serde(alias = "mortyurl", skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
-    #[serde(alias = "mortyhash")]
+    #[serde(alias = "mortyhash", skip_serializing_if = "Option::is_none")]
     pub hash: Option<String>,
+    /// Unix timestamp (in seconds) after which the URL won't be accepted anymore, it's covered by the hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// Encrypted URL (and expiry), which takes precedence over the plain URL and hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opaque: Option<String>,
+    /// Short link id, which has to be resolved through the link store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl IndexHttpArgs {
+    pub fn signed_url(&self) -> Option<SignedUrl<'_>> {
+        match (
+            self.opaque.as_deref(),
+            self.url.as_deref(),
+            self.hash.as_deref(),
+        ) {
+            (Some(opaque_url), _, _) => Some(SignedUrl::Opaque(opaque_url)),
+            (None, Some(url), Some(hash)) => Some(SignedUrl::Plain {
+                url,
+                hash,
+                expires: self.expires,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexHttpArgs;
+    use crate::utilities::SignedUrl;
+
+    #[test]
+    fn signed_url_forms() {
+        let plain: IndexHttpArgs =
+            serde_qs::from_str("mortyurl=https%3A%2F%2Fexample.com%2F&mortyhash=abc").unwrap();
+        let opaque: IndexHttpArgs =
+            serde_qs::from_str("opaque=abc&url=https%3A%2F%2Fexample.com%2F&hash=abc").unwrap();
+
+        assert!(matches!(
+            plain.signed_url(),
+            Some(SignedUrl::Plain {
+                url: "https://example.com/",
+                hash: "abc",
+                expires: None
+            })
+        ));
+        assert!(matches!(
+            opaque.signed_url(),
+            Some(SignedUrl::Opaque("abc"))
+        ));
+        assert!(IndexHttpArgs::default().signed_url().is_none());
+        assert_eq!(
+            serde_qs::to_string(&IndexHttpArgs {
+                opaque: Some(String::from("abc")),
+                ..IndexHttpArgs::default()
+            })
+            .unwrap(),
+            "opaque=abc"
+        );
+    }
 }
@@ -0,0 +1,196 @@
+use std::{
+    fmt::{Display, Formatter},
+    net::IpAddr,
+    str::FromStr,
+};
+
+/// IP (v4, v6) network in CIDR notation, e.g. "10.20.0.0/16" or "2001:db8::/32".
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IpCidr {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IpRuleAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IpRule {
+    pub action: IpRuleAction,
+    pub cidr: IpCidr,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IpRuleParseError {
+    #[error("Invalid IP address `{0}`")]
+    Address(String),
+    #[error("Invalid prefix length `{0}`")]
+    PrefixLength(String),
+    #[error("Unknown rule action `{0}` (expected \"allow\" or \"deny\")")]
+    Action(String),
+    #[error("Invalid rule `{1}` on line {0}")]
+    Line(usize, String),
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+
+                network.to_bits() & mask == ip.to_bits() & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+
+                network.to_bits() & mask == ip.to_bits() & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip_v6)) => ip_v6
+                .to_ipv4_mapped()
+                .is_some_and(|ip_v4| self.contains(IpAddr::V4(ip_v4))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl Display for IpCidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = IpRuleParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address_str, prefix_len_opt) = match value.trim().split_once('/') {
+            Some((address_str, prefix_len_str)) => (address_str, Some(prefix_len_str)),
+            None => (value.trim(), None),
+        };
+        let address = IpAddr::from_str(address_str)
+            .map_err(|_| IpRuleParseError::Address(String::from(address_str)))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len_opt {
+            Some(prefix_len_str) => u8::from_str(prefix_len_str)
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| IpRuleParseError::PrefixLength(String::from(prefix_len_str)))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+impl Display for IpRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.action {
+            IpRuleAction::Allow => write!(f, "allow {}", self.cidr),
+            IpRuleAction::Deny => write!(f, "deny {}", self.cidr),
+        }
+    }
+}
+
+impl FromStr for IpRule {
+    type Err = IpRuleParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (action_str, cidr_str) = value
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| IpRuleParseError::Action(String::from(value.trim())))?;
+        let action = match action_str {
+            "allow" => IpRuleAction::Allow,
+            "deny" => IpRuleAction::Deny,
+            _ => return Err(IpRuleParseError::Action(String::from(action_str))),
+        };
+
+        Ok(Self {
+            action,
+            cidr: IpCidr::from_str(cidr_str)?,
+        })
+    }
+}
+
+/**
+ * Parses a list of rules with one rule per line ("allow <CIDR>" / "deny <CIDR>").
+ * Empty lines and lines starting with "#" will be ignored.
+ **/
+pub fn parse_ip_rules(input: &str) -> Result<Vec<IpRule>, IpRuleParseError> {
+    input
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| {
+            IpRule::from_str(line)
+                .map_err(|err| IpRuleParseError::Line(index + 1, format!("{line}: {err}")))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        str::FromStr,
+    };
+
+    use super::{IpCidr, IpRuleAction, parse_ip_rules};
+
+    #[test]
+    fn cidr_contains_v4() {
+        let cidr = IpCidr::from_str("10.20.0.0/16").unwrap();
+
+        assert!(cidr.contains(IpAddr::V4(Ipv4Addr::new(10, 20, 0, 1))));
+        assert!(cidr.contains(IpAddr::V4(Ipv4Addr::new(10, 20, 255, 255))));
+        assert!(cidr.contains(IpAddr::V6(Ipv4Addr::new(10, 20, 1, 1).to_ipv6_mapped())));
+        assert!(!cidr.contains(IpAddr::V4(Ipv4Addr::new(10, 21, 0, 1))));
+        assert!(!cidr.contains(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn cidr_contains_v6() {
+        let cidr = IpCidr::from_str("2001:db8::/32").unwrap();
+
+        assert!(cidr.contains(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1))));
+        assert!(!cidr.contains(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1))));
+        assert!(!cidr.contains(IpAddr::V4(Ipv4Addr::new(32, 1, 13, 184))));
+    }
+
+    #[test]
+    fn cidr_parse() {
+        assert_eq!(
+            IpCidr::from_str("192.168.1.1").unwrap().to_string(),
+            "192.168.1.1/32"
+        );
+        assert_eq!(IpCidr::from_str("::/0").unwrap().to_string(), "::/0");
+        assert!(IpCidr::from_str("10.0.0.0/33").is_err());
+        assert!(IpCidr::from_str("10.0.0/8").is_err());
+        assert!(IpCidr::from_str("10.0.0.0/").is_err());
+    }
+
+    #[test]
+    fn rules_parse() {
+        let rules = parse_ip_rules(
+            "# internal wiki\nallow 10.20.0.0/16\n\n  deny   203.0.113.0/24  \ndeny 2001:db8::/32\n",
+        )
+        .unwrap();
+
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].action, IpRuleAction::Allow);
+        assert_eq!(rules[1].to_string(), "deny 203.0.113.0/24");
+        assert_eq!(rules[2].to_string(), "deny 2001:db8::/32");
+        assert!(parse_ip_rules("allow 10.0.0.0/8\npermit 10.0.0.0/8").is_err());
+    }
+}
@@ -1,11 +1,26 @@
-pub use app_state::AppState;
-pub use cli::Cli;
-pub use config::{Config, SocketListener};
+pub use access_control::{
+    AccessControl, AccessError, DEFAULT_ALLOWED_PORTS, DEFAULT_ALLOWED_SCHEMES, HostRule,
+    parse_host_rules,
+};
+pub use app_state::{AppState, AppStateError};
+pub use cache_policy::CachePolicy;
+pub use cli::{Cli, Command, ProxyCommand};
+pub use config::{
+    Config, ConfigError, LinkStoreOptions, ResponseCacheLimits, SocketListener, UnixSocketOptions,
+};
+pub use config_file::{ConfigFile, ConfigFileError};
 pub use index_http_query::IndexHttpArgs;
 pub use ip_range::PermittedIpRange;
+pub use ip_rule::{IpCidr, IpRule, IpRuleAction, parse_ip_rules};
+pub use sign_request::{SignRequest, SignResponse, SignedProxyUrl};
 
+mod access_control;
 mod app_state;
+mod cache_policy;
 mod cli;
 mod config;
+mod config_file;
 mod index_http_query;
 mod ip_range;
+mod ip_rule;
+mod sign_request;
@@ -0,0 +1,29 @@
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct SignRequest {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub urls: Vec<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct SignResponse {
+    /// Unix timestamp (in seconds) after which the proxy URLs won't be accepted anymore.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    pub urls: Vec<SignedProxyUrl>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct SignedProxyUrl {
+    pub url: String,
+    /// Proxy URL relative to the index route, e.g. "./?url=...&hash=...".
+    pub proxy_url: String,
+}
+
+impl SignRequest {
+    /// Returns the single URL (if any), followed by the list of URLs.
+    pub fn into_urls(self) -> Vec<String> {
+        self.url.into_iter().chain(self.urls).collect()
+    }
+}
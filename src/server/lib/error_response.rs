@@ -1,6 +1,9 @@
 use std::borrow::Cow;
 
-use crate::utilities::{ClientError, ClientResponseBody};
+use crate::{
+    model::AccessError,
+    utilities::{BodyLimitError, ClientError, ClientResponseBody, UrlCipherError},
+};
 
 #[derive(serde::Serialize)]
 pub struct ErrorMessage<'name, 'description> {
@@ -11,10 +14,42 @@ pub struct ErrorMessage<'name, 'description> {
 pub fn get_error_response(
     error_detail: ClientError,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
+    // errors of coalesced fetches are shared by every request which waited for them
+    let error_detail = match &error_detail {
+        ClientError::Shared(shared_err) => shared_err.as_ref(),
+        err => err,
+    };
+    let content_range_opt = match error_detail {
+        ClientError::RangeNotSatisfiable(content_range_opt) => {
+            content_range_opt.as_ref().and_then(|value| {
+                actix_web::http::header::HeaderValue::from_bytes(value.as_ref()).ok()
+            })
+        }
+        _ => None,
+    };
     let mut response = actix_web::HttpResponse::with_body(
         match error_detail {
-            ClientError::InvalidHash => actix_web::http::StatusCode::UNAUTHORIZED,
+            ClientError::InvalidHash
+            | ClientError::UrlWithoutExpiry
+            | ClientError::UrlCipher(UrlCipherError::Base64(_))
+            | ClientError::UrlCipher(UrlCipherError::Decrypt)
+            | ClientError::UrlCipher(UrlCipherError::Utf8(_)) => {
+                actix_web::http::StatusCode::UNAUTHORIZED
+            }
+            ClientError::UrlExpired(_) | ClientError::LinkNotFound => {
+                actix_web::http::StatusCode::GONE
+            }
+            ClientError::AccessDenied(_) => actix_web::http::StatusCode::FORBIDDEN,
+            ClientError::BodyLimit(BodyLimitError::Size(_)) | ClientError::PartialDocument => {
+                actix_web::http::StatusCode::BAD_GATEWAY
+            }
+            ClientError::BodyLimit(_) => actix_web::http::StatusCode::GATEWAY_TIMEOUT,
+            ClientError::RangeNotSatisfiable(_) => {
+                actix_web::http::StatusCode::RANGE_NOT_SATISFIABLE
+            }
             ClientError::Hex(_)
+            | ClientError::Base64(_)
+            | ClientError::Utf8String(_)
             | ClientError::BadRequest
             | ClientError::IpRangeDenied(_)
             | ClientError::ResolveHostname(_) => actix_web::http::StatusCode::BAD_REQUEST,
@@ -28,6 +63,10 @@ pub fn get_error_response(
     );
     let headers = response.headers_mut();
 
+    if let Some(content_range) = content_range_opt {
+        headers.insert(actix_web::http::header::CONTENT_RANGE, content_range);
+    }
+
     headers.insert(
         actix_web::http::header::CACHE_CONTROL,
         crate::utilities::HEADER_VALUE_NO_CACHE.clone(),
@@ -41,24 +80,48 @@ pub fn get_error_response(
     response
 }
 
-fn get_error_message(error_detail: ClientError) -> Option<ErrorMessage<'static, 'static>> {
+fn get_error_message(error_detail: &ClientError) -> Option<ErrorMessage<'static, 'static>> {
     match error_detail {
         ClientError::InvalidHash => Some(ErrorMessage {
             name: Cow::Borrowed("Invalid hash"),
             description: Cow::Borrowed("The given URL and hash combination doesn't match."),
         }),
+        ClientError::UrlCipher(
+            UrlCipherError::Base64(_) | UrlCipherError::Decrypt | UrlCipherError::Utf8(_),
+        ) => Some(ErrorMessage {
+            name: Cow::Borrowed("Invalid link"),
+            description: Cow::Borrowed("The given encrypted URL couldn't be decrypted."),
+        }),
+        ClientError::UrlExpired(_) => Some(ErrorMessage {
+            name: Cow::Borrowed("Link expired"),
+            description: Cow::Borrowed(
+                "This link is no longer valid, please open it again from the original page.",
+            ),
+        }),
+        ClientError::LinkNotFound => Some(ErrorMessage {
+            name: Cow::Borrowed("Link expired"),
+            description: Cow::Borrowed(
+                "This short link is no longer available, please open it again from the original page.",
+            ),
+        }),
+        ClientError::UrlWithoutExpiry => Some(ErrorMessage {
+            name: Cow::Borrowed("Link expired"),
+            description: Cow::Borrowed(
+                "Links without an expiry are no longer accepted, please open it again from the original page.",
+            ),
+        }),
         ClientError::UnexpectedStatusCode(status_code) => Some(ErrorMessage {
             name: Cow::Borrowed("Unexpected status code"),
             description: Cow::Owned(format!("Origin returned status code: '{status_code}'")),
         }),
-        ClientError::MimeParse(_) => Some(ErrorMessage {
-            name: Cow::Borrowed("Invalid media type"),
-            description: Cow::Borrowed("Origin returned invalid media type"),
-        }),
         ClientError::Hex(_) => Some(ErrorMessage {
             name: Cow::Borrowed("Invalid hash"),
             description: Cow::Borrowed("The given hash must be valid hexadecimal."),
         }),
+        ClientError::Base64(_) | ClientError::Utf8String(_) => Some(ErrorMessage {
+            name: Cow::Borrowed("Invalid link"),
+            description: Cow::Borrowed("The given hash and URL must be valid Base64."),
+        }),
         ClientError::BadRequest => Some(ErrorMessage {
             name: Cow::Borrowed("Bad request"),
             description: Cow::Owned(error_detail.to_string()),
@@ -79,6 +142,35 @@ fn get_error_message(error_detail: ClientError) -> Option<ErrorMessage<'static,
                 "The requested host \"{host}\" couldn't be resolved."
             )),
         }),
+        ClientError::AccessDenied(AccessError::Host(host, reason_opt)) => Some(ErrorMessage {
+            name: Cow::Borrowed("Request blocked"),
+            description: match reason_opt {
+                Some(reason) => Cow::Owned(reason.clone()),
+                None => Cow::Owned(format!(
+                    "The requested host \"{host}\" is blocked by the service provider."
+                )),
+            },
+        }),
+        ClientError::AccessDenied(AccessError::Port(port)) => Some(ErrorMessage {
+            name: Cow::Borrowed("Request blocked"),
+            description: Cow::Owned(format!(
+                "Requests to port \"{port}\" are not permitted by the service provider."
+            )),
+        }),
+        ClientError::AccessDenied(AccessError::Scheme(scheme)) => Some(ErrorMessage {
+            name: Cow::Borrowed("Request blocked"),
+            description: Cow::Owned(format!(
+                "Requests using the scheme \"{scheme}\" are not permitted by the service provider."
+            )),
+        }),
+        ClientError::RangeNotSatisfiable(_) => Some(ErrorMessage {
+            name: Cow::Borrowed("Range not satisfiable"),
+            description: Cow::Borrowed("The requested range of the resource can't be served."),
+        }),
+        ClientError::BodyLimit(limit_err) => Some(ErrorMessage {
+            name: Cow::Borrowed("Response limit exceeded"),
+            description: Cow::Owned(limit_err.to_string()),
+        }),
         _ => None,
     }
 }
@@ -1,25 +1,37 @@
 use actix_web::http::header::HeaderValue;
 
 use crate::{
+    model::CachePolicy,
     server::lib::get_content_security_policy,
     utilities::{
-        ClientRedirect, ClientResponse, ClientResponseBody, FetchResult, FormRequest,
-        fetch_validate_url,
+        CacheHeaders, ClientRedirect, ClientResponse, ClientResponseBody, FetchResult, FormRequest,
+        SignedUrl, fetch_validate_url, get_shared_state,
     },
 };
 
 pub async fn fetch_url(
     response: actix_web::HttpResponse<ClientResponseBody>,
-    url: &str,
-    hash: &str,
+    signed_url: SignedUrl<'_>,
     headers: &actix_web::http::header::HeaderMap,
     request_body: Option<FormRequest>,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
-    match fetch_validate_url(url, hash, headers, request_body).await {
+    // every link of the response is rewritten with the same values, even if the config is reloaded meanwhile
+    let shared_state = get_shared_state();
+    let config_opt = shared_state.config.as_deref();
+    let cache_policy = config_opt.map_or(CachePolicy::NoCache, |config| config.cache_policy);
+
+    match fetch_validate_url(&shared_state, signed_url, headers, request_body).await {
         Ok(fetch_result) => match fetch_result {
-            FetchResult::Response(client_res) => handle_client_response(response, client_res),
-            FetchResult::Redirect(client_redirect) => {
-                handle_client_redirect(response, client_redirect)
+            FetchResult::Response(client_res) => {
+                handle_client_response(response, *client_res, cache_policy)
+            }
+            FetchResult::Redirect(client_redirect) => handle_client_redirect(
+                response,
+                client_redirect,
+                config_opt.is_some_and(|config| config.follow_redirects),
+            ),
+            FetchResult::NotModified(cache_headers) => {
+                handle_not_modified(response, cache_headers, cache_policy)
             }
         },
         Err(err) => {
@@ -32,34 +44,54 @@ pub async fn fetch_url(
 fn handle_client_response(
     mut response: actix_web::HttpResponse<ClientResponseBody>,
     client_res: ClientResponse,
+    cache_policy: CachePolicy,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
-    response = response.set_body(match client_res.body {
-        crate::utilities::BodyType::Complete(body) => actix_web::body::EitherBody::Right { body },
-        crate::utilities::BodyType::Stream(stream) => actix_web::body::EitherBody::Left {
-            body: if let Some(body_size) = client_res.content_length {
-                actix_web::body::EitherBody::Left {
-                    body: actix_web::body::SizedStream::new(body_size, stream),
-                }
-            } else {
-                actix_web::body::EitherBody::Right {
-                    body: actix_web::body::BodyStream::new(stream),
-                }
-            },
+    response = response.set_body(actix_web::body::EitherBody::Left {
+        body: if let Some(body_size) = client_res.content_length {
+            actix_web::body::EitherBody::Left {
+                body: actix_web::body::SizedStream::new(body_size, client_res.body),
+            }
+        } else {
+            actix_web::body::EitherBody::Right {
+                body: actix_web::body::BodyStream::new(client_res.body),
+            }
         },
     });
 
+    if client_res.status_code == reqwest::StatusCode::PARTIAL_CONTENT {
+        *response.status_mut() = actix_web::http::StatusCode::PARTIAL_CONTENT;
+    }
+
     let headers = response.headers_mut();
 
+    insert_upstream_headers(
+        headers,
+        [
+            (
+                actix_web::http::header::ACCEPT_RANGES,
+                client_res.accept_ranges,
+            ),
+            (
+                actix_web::http::header::CONTENT_RANGE,
+                client_res.content_range,
+            ),
+        ],
+    );
+
+    if let Some(cache_headers) = client_res.cache_headers {
+        insert_cache_headers(headers, cache_headers, cache_policy);
+    }
+
     if let Some(value) = client_res.content_disposition {
         if let Ok(header_value) = HeaderValue::from_bytes(value.as_ref()) {
             headers.insert(actix_web::http::header::CONTENT_DISPOSITION, header_value);
         }
     }
 
-    if let Some(style_hashes) = client_res.style_hashes {
+    if let Some(style_nonce) = client_res.style_nonce.as_deref() {
         headers.insert(
             actix_web::http::header::CONTENT_SECURITY_POLICY,
-            get_content_security_policy(Some(style_hashes)),
+            get_content_security_policy(Some(style_nonce)),
         );
     }
 
@@ -70,22 +102,97 @@ fn handle_client_response(
     response
 }
 
+fn handle_not_modified(
+    mut response: actix_web::HttpResponse<ClientResponseBody>,
+    cache_headers: CacheHeaders,
+    cache_policy: CachePolicy,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    *response.status_mut() = actix_web::http::StatusCode::NOT_MODIFIED;
+    insert_cache_headers(response.headers_mut(), cache_headers, cache_policy);
+
+    response
+}
+
+/**
+ * Forwards the upstream validators (and `Vary`) and sets `Cache-Control` according to the configured `CachePolicy`.
+ * `Age` is only forwarded along with the upstream freshness headers, it includes the time spent in the response cache.
+ **/
+fn insert_cache_headers(
+    headers: &mut actix_web::http::header::HeaderMap,
+    cache_headers: CacheHeaders,
+    cache_policy: CachePolicy,
+) {
+    insert_upstream_headers(
+        headers,
+        [
+            (actix_web::http::header::ETAG, cache_headers.etag),
+            (
+                actix_web::http::header::LAST_MODIFIED,
+                cache_headers.last_modified,
+            ),
+            (actix_web::http::header::VARY, cache_headers.vary),
+        ],
+    );
+
+    match cache_policy {
+        // the base response is already marked as "no-cache"
+        CachePolicy::NoCache => {}
+        CachePolicy::MaxAge(_) => {
+            if let Ok(header_value) = HeaderValue::from_str(&cache_policy.to_string()) {
+                headers.insert(actix_web::http::header::CACHE_CONTROL, header_value);
+            }
+        }
+        CachePolicy::Upstream => {
+            if cache_headers.cache_control.is_some() || cache_headers.expires.is_some() {
+                headers.remove(actix_web::http::header::CACHE_CONTROL);
+            }
+
+            insert_upstream_headers(
+                headers,
+                [
+                    (
+                        actix_web::http::header::CACHE_CONTROL,
+                        cache_headers.cache_control,
+                    ),
+                    (actix_web::http::header::EXPIRES, cache_headers.expires),
+                    (actix_web::http::header::AGE, cache_headers.age),
+                ],
+            );
+        }
+    }
+}
+
+fn insert_upstream_headers<const N: usize>(
+    headers: &mut actix_web::http::header::HeaderMap,
+    upstream_headers: [(
+        actix_web::http::header::HeaderName,
+        Option<reqwest::header::HeaderValue>,
+    ); N],
+) {
+    for (header_name, value_opt) in upstream_headers {
+        if let Some(header_value) =
+            value_opt.and_then(|value| HeaderValue::from_bytes(value.as_ref()).ok())
+        {
+            headers.insert(header_name, header_value);
+        }
+    }
+}
+
 fn handle_client_redirect(
     mut response: actix_web::HttpResponse<ClientResponseBody>,
     client_redirect: ClientRedirect,
+    follow_redirects: bool,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
     let header_value_res = HeaderValue::try_from(client_redirect.internal_url.as_str());
 
-    if let Ok(header_value) = header_value_res {
-        if let Some(config) = crate::utilities::GLOBAL_CONFIG.get() {
-            if config.follow_redirects {
-                response
-                    .headers_mut()
-                    .insert(actix_web::http::header::LOCATION, header_value);
-                *response.status_mut() = actix_web::http::StatusCode::TEMPORARY_REDIRECT;
-                return response;
-            }
-        }
+    if let Ok(header_value) = header_value_res
+        && follow_redirects
+    {
+        response
+            .headers_mut()
+            .insert(actix_web::http::header::LOCATION, header_value);
+        *response.status_mut() = actix_web::http::StatusCode::TEMPORARY_REDIRECT;
+        return response;
     }
 
     response.headers_mut().insert(
@@ -1,7 +1,15 @@
 pub use content_security_policy::get_content_security_policy;
 pub use error_response::{ErrorMessage, get_error_response};
 pub use fetch_url::fetch_url;
+#[cfg(unix)]
+pub use systemd::{InheritedListener, get_watchdog_interval, notify, take_inherited_listeners};
+#[cfg(unix)]
+pub use unix_socket::{bind_unix_socket, find_group_id, is_stale_socket};
 
 mod content_security_policy;
 mod error_response;
 mod fetch_url;
+#[cfg(unix)]
+mod systemd;
+#[cfg(unix)]
+mod unix_socket;
@@ -0,0 +1,230 @@
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+/// File descriptors passed by systemd start at 3 (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Inherited file descriptors must only be taken (and closed) once.
+static LISTEN_FDS_TAKEN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub enum InheritedListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/**
+ * Takes the sockets passed by systemd socket activation ("LISTEN_FDS"), along with their names ("LISTEN_FDNAMES").
+ * Subsequent calls (and processes which the sockets weren't passed to) get no sockets.
+ **/
+pub fn take_inherited_listeners() -> std::io::Result<Vec<(String, InheritedListener)>> {
+    let listen_fds = get_listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::env::var("LISTEN_FDNAMES").ok().as_deref(),
+        std::process::id(),
+    );
+
+    if listen_fds.is_empty() || LISTEN_FDS_TAKEN.swap(true, std::sync::atomic::Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    listen_fds
+        .into_iter()
+        .map(|(fd, name)| Ok((name, inherit_listener(take_fd(fd))?)))
+        .collect()
+}
+
+/// Sends the state (e.g. "READY=1") to the service manager, returns `false` if there's no "NOTIFY_SOCKET".
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(notify_socket) => {
+            notify_socket_path(std::path::Path::new(&notify_socket), state).map(|()| true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Interval in which "WATCHDOG=1" has to be sent, which is half of the watchdog timeout ("WATCHDOG_USEC").
+pub fn get_watchdog_interval() -> Option<std::time::Duration> {
+    get_watchdog_timeout(
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::process::id(),
+    )
+    .map(|timeout| timeout / 2)
+}
+
+fn get_listen_fds(
+    listen_pid_opt: Option<&str>,
+    listen_fds_opt: Option<&str>,
+    listen_fdnames_opt: Option<&str>,
+    pid: u32,
+) -> Vec<(RawFd, String)> {
+    // the variables are inherited by child processes, which the sockets weren't meant for
+    if listen_pid_opt.and_then(|listen_pid| listen_pid.parse().ok()) != Some(pid) {
+        return Vec::new();
+    }
+
+    let Some(listen_fds) = listen_fds_opt.and_then(|listen_fds| listen_fds.parse::<RawFd>().ok())
+    else {
+        return Vec::new();
+    };
+    let mut names = listen_fdnames_opt.unwrap_or_default().split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(listen_fds))
+        .map(|fd| {
+            (
+                fd,
+                // systemd names sockets "unknown" by default
+                String::from(
+                    names
+                        .next()
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or("unknown"),
+                ),
+            )
+        })
+        .collect()
+}
+
+fn get_watchdog_timeout(
+    watchdog_pid_opt: Option<&str>,
+    watchdog_usec_opt: Option<&str>,
+    pid: u32,
+) -> Option<std::time::Duration> {
+    if let Some(watchdog_pid) = watchdog_pid_opt
+        && watchdog_pid.parse() != Ok(pid)
+    {
+        return None;
+    }
+
+    watchdog_usec_opt?
+        .parse()
+        .ok()
+        .filter(|usec| *usec > 0)
+        .map(std::time::Duration::from_micros)
+}
+
+#[allow(unsafe_code)]
+fn take_fd(fd: RawFd) -> OwnedFd {
+    // SAFETY: the file descriptors in "LISTEN_FDS" are open and owned by this process (checked by "LISTEN_PID"),
+    // and `LISTEN_FDS_TAKEN` guarantees that they're only taken once.
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+/// Sockets can be passed as TCP or Unix sockets, which is determined by their address family.
+fn inherit_listener(fd: OwnedFd) -> std::io::Result<InheritedListener> {
+    let tcp_listener = std::net::TcpListener::from(fd);
+
+    if tcp_listener.local_addr().is_ok() {
+        return Ok(InheritedListener::Tcp(tcp_listener));
+    }
+
+    let unix_listener = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp_listener));
+
+    unix_listener.local_addr()?;
+
+    Ok(InheritedListener::Unix(unix_listener))
+}
+
+fn notify_socket_path(notify_socket: &std::path::Path, state: &str) -> std::io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+
+    // names starting with "@" are abstract sockets (Linux only)
+    #[cfg(target_os = "linux")]
+    if let Some(name) = notify_socket
+        .as_os_str()
+        .as_encoded_bytes()
+        .strip_prefix(b"@")
+    {
+        use std::os::linux::net::SocketAddrExt;
+
+        return socket
+            .send_to_addr(
+                state.as_bytes(),
+                &std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+            )
+            .map(drop);
+    }
+
+    socket.send_to(state.as_bytes(), notify_socket).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::OwnedFd;
+
+    use super::{
+        InheritedListener, get_listen_fds, get_watchdog_timeout, inherit_listener,
+        notify_socket_path,
+    };
+
+    #[test]
+    fn listen_fds() {
+        assert_eq!(
+            get_listen_fds(Some("42"), Some("3"), Some("http:http:metrics"), 42),
+            [
+                (3, String::from("http")),
+                (4, String::from("http")),
+                (5, String::from("metrics"))
+            ]
+        );
+        assert_eq!(
+            get_listen_fds(Some("42"), Some("2"), None, 42),
+            [(3, String::from("unknown")), (4, String::from("unknown"))]
+        );
+        assert!(get_listen_fds(Some("41"), Some("1"), None, 42).is_empty());
+        assert!(get_listen_fds(None, Some("1"), None, 42).is_empty());
+        assert!(get_listen_fds(Some("42"), None, None, 42).is_empty());
+    }
+
+    #[test]
+    fn watchdog_timeout() {
+        assert_eq!(
+            get_watchdog_timeout(None, Some("30000000"), 42),
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert_eq!(
+            get_watchdog_timeout(Some("42"), Some("500"), 42),
+            Some(std::time::Duration::from_micros(500))
+        );
+        assert_eq!(get_watchdog_timeout(Some("41"), Some("500"), 42), None);
+        assert_eq!(get_watchdog_timeout(None, Some("0"), 42), None);
+        assert_eq!(get_watchdog_timeout(None, None, 42), None);
+    }
+
+    #[test]
+    fn inherit_tcp_and_unix_listeners() {
+        let tcp_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp_listener.local_addr().unwrap();
+        let socket_path =
+            std::env::temp_dir().join(format!("searproxy-inherit-{}.sock", std::process::id()));
+        let unix_listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+        assert!(matches!(
+            inherit_listener(OwnedFd::from(tcp_listener)).unwrap(),
+            InheritedListener::Tcp(listener) if listener.local_addr().unwrap() == address
+        ));
+        assert!(matches!(
+            inherit_listener(OwnedFd::from(unix_listener)).unwrap(),
+            InheritedListener::Unix(_)
+        ));
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+
+    #[test]
+    fn notify_fake_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("searproxy-notify-{}.sock", std::process::id()));
+        let notify_socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+        let mut buffer = [0; 64];
+
+        notify_socket_path(&socket_path, "READY=1").unwrap();
+
+        let length = notify_socket.recv(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..length], b"READY=1");
+
+        std::fs::remove_file(socket_path).unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+
+/// Returns the id of the group with the given name, using the format of "/etc/group" ("name:password:gid:members").
+pub fn find_group_id(group_file: &str, name: &str) -> Option<u32> {
+    group_file.lines().find_map(|line| {
+        let mut fields = line.split(':');
+
+        if fields.next()? != name {
+            return None;
+        }
+
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// A socket file is stale if no process accepts connections on it anymore, e.g. after a crash.
+pub fn is_stale_socket(path: &std::path::Path) -> std::io::Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => Ok(false),
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => Ok(true),
+                Err(err) => Err(err),
+            }
+        }
+        // other files are never removed
+        Ok(_) => Ok(false),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Socket file which was created by `bind_unix_socket`, it's removed once this is dropped (e.g. after a graceful shutdown).
+pub struct UnixSocketFile {
+    path: std::path::PathBuf,
+    device: u64,
+    inode: u64,
+}
+
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        // the file may have been replaced meanwhile, e.g. by another instance after it was removed manually
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.dev() == self.device && metadata.ino() == self.inode => {
+                if let Err(err) = std::fs::remove_file(&self.path) {
+                    log::error!(
+                        "Couldn't remove socket file '{}': {err:?}",
+                        self.path.display()
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => log::error!(
+                "Couldn't check socket file '{}': {err:?}",
+                self.path.display()
+            ),
+        }
+    }
+}
+
+/**
+ * Binds to the socket path, which fails if the file already exists (unless it's stale and may be removed).
+ * The socket file is created without any permissions for group and others, which are only granted once its group is set.
+ * It's removed again if its permissions can't be set, or once the returned `UnixSocketFile` is dropped.
+ **/
+pub fn bind_unix_socket(
+    path: &std::path::Path,
+    options: &crate::model::UnixSocketOptions,
+) -> std::io::Result<(std::os::unix::net::UnixListener, UnixSocketFile)> {
+    if options.remove_stale {
+        remove_stale_socket(path)?;
+    }
+
+    let umask = set_umask(0o077);
+    let listener_res = std::os::unix::net::UnixListener::bind(path);
+
+    set_umask(umask);
+
+    let listener = listener_res?;
+    let metadata = std::fs::symlink_metadata(path)?;
+    let socket_file = UnixSocketFile {
+        path: path.to_path_buf(),
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    };
+
+    // without a configured mode, the socket gets the permissions it would have had with the previous umask
+    set_socket_permissions(path, options, 0o777 & !umask)?;
+
+    Ok((listener, socket_file))
+}
+
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    if is_stale_socket(path)? {
+        std::fs::remove_file(path)?;
+        log::info!("Removed stale socket file '{}'", path.display());
+    }
+
+    Ok(())
+}
+
+/// Changes the group before the mode, so the socket is never accessible for the wrong group.
+fn set_socket_permissions(
+    path: &std::path::Path,
+    options: &crate::model::UnixSocketOptions,
+    default_mode: u32,
+) -> std::io::Result<()> {
+    if let Some(group_id) = options.group {
+        std::os::unix::fs::chown(path, None, Some(group_id))?;
+    }
+
+    std::fs::set_permissions(
+        path,
+        std::fs::Permissions::from_mode(options.mode.unwrap_or(default_mode)),
+    )
+}
+
+/// Sets the file mode creation mask of the process and returns the previous one.
+#[allow(unsafe_code, trivial_numeric_casts)]
+fn set_umask(umask: u32) -> u32 {
+    // `mode_t` is narrower than `u32` on some platforms (e.g. macOS), but masks only use the permission bits
+    // SAFETY: `umask` only replaces the mask of the process, it has no preconditions and can't fail.
+    (unsafe { libc::umask((umask & 0o777) as libc::mode_t) }) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::{bind_unix_socket, find_group_id, is_stale_socket};
+    use crate::model::UnixSocketOptions;
+
+    #[test]
+    fn group_id() {
+        let group_file = "root:x:0:\nwww-data:x:33:\n# comment\nsearproxy:x:999:nginx,searproxy\n";
+
+        assert_eq!(find_group_id(group_file, "root"), Some(0));
+        assert_eq!(find_group_id(group_file, "searproxy"), Some(999));
+        assert_eq!(find_group_id(group_file, "www"), None);
+        assert_eq!(find_group_id(group_file, "nginx"), None);
+    }
+
+    #[test]
+    fn stale_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("searproxy-stale-{}.sock", std::process::id()));
+        let options = UnixSocketOptions {
+            mode: Some(0o660),
+            ..UnixSocketOptions::default()
+        };
+        let (listener, socket_file) = bind_unix_socket(&socket_path, &options).unwrap();
+
+        assert_eq!(
+            std::fs::metadata(&socket_path)
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o660
+        );
+        assert!(!is_stale_socket(&socket_path).unwrap());
+
+        // the socket file is left behind if the process doesn't shut down gracefully, e.g. after a crash
+        std::mem::forget(socket_file);
+        drop(listener);
+
+        assert!(is_stale_socket(&socket_path).unwrap());
+        assert!(bind_unix_socket(&socket_path, &options).is_err());
+
+        let (listener, socket_file) = bind_unix_socket(
+            &socket_path,
+            &UnixSocketOptions {
+                remove_stale: true,
+                ..options
+            },
+        )
+        .unwrap();
+
+        // sockets which are in use are never removed
+        assert!(
+            bind_unix_socket(
+                &socket_path,
+                &UnixSocketOptions {
+                    remove_stale: true,
+                    ..UnixSocketOptions::default()
+                }
+            )
+            .is_err()
+        );
+
+        drop(listener);
+        drop(socket_file);
+
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn keep_replaced_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("searproxy-private-{}.sock", std::process::id()));
+        let (listener, socket_file) =
+            bind_unix_socket(&socket_path, &UnixSocketOptions::default()).unwrap();
+
+        // a socket file which replaced this one (e.g. of another instance) isn't removed
+        std::fs::remove_file(&socket_path).unwrap();
+
+        let other_listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+        drop(listener);
+        drop(socket_file);
+
+        assert!(!is_stale_socket(&socket_path).unwrap());
+
+        drop(other_listener);
+        std::fs::remove_file(socket_path).unwrap();
+    }
+}
@@ -3,8 +3,21 @@ use crate::server::lib::get_content_security_policy;
 pub mod lib;
 mod routes;
 
+#[derive(thiserror::Error, Debug)]
+pub enum ServerError {
+    #[error("No listener is configured")]
+    MissingListener,
+    #[error("Couldn't bind to '{0}'")]
+    Bind(String, #[source] std::io::Error),
+    #[cfg(unix)]
+    #[error("Couldn't take the sockets passed by systemd")]
+    Inherit(#[source] std::io::Error),
+    #[error("Couldn't start HTTP workers")]
+    Run(#[source] std::io::Error),
+}
+
 #[actix_web::main]
-pub async fn start_http_service() {
+pub async fn start_http_service() -> Result<(), ServerError> {
     let config = crate::utilities::GLOBAL_CONFIG
         .get()
         .expect("Global config is not initialized");
@@ -39,8 +52,14 @@ pub async fn start_http_service() {
                 crate::assets::ROBOTS_FILE,
                 "text/plain"
             ))
+            .service(routes::api::handle_sign_request)
             .service(routes::index::handle_get_request)
             .service(routes::index::handle_post_request)
+            // registered before the path routes, which would match "l" as hash otherwise
+            .service(routes::index::handle_link_get_request)
+            .service(routes::index::handle_link_post_request)
+            .service(routes::index::handle_path_get_request)
+            .service(routes::index::handle_path_post_request)
     })
     .backlog(4096)
     .shutdown_timeout(5);
@@ -49,23 +68,131 @@ pub async fn start_http_service() {
         http_server = http_server.workers(config.worker_count as usize);
     }
 
-    match match &config.listen {
-        crate::model::SocketListener::Tcp(address) => http_server.bind(address),
-        #[cfg(unix)]
-        crate::model::SocketListener::Unix(path) => http_server.bind_uds(path),
-    } {
-        Ok(server_socket) => {
-            log::info!("Listening on {:?}", &config.listen);
-            server_socket
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload_on_hangup());
+
+    if config.listen.is_empty() {
+        return Err(ServerError::MissingListener);
+    }
+
+    #[cfg(unix)]
+    let mut inherited_listeners = lib::take_inherited_listeners().map_err(ServerError::Inherit)?;
+    // removed once the server has stopped, or binding another listener failed
+    #[cfg(unix)]
+    let mut socket_files = Vec::new();
+
+    for listener in &config.listen {
+        http_server = match listener {
+            crate::model::SocketListener::Tcp(address) => http_server.bind(address),
+            #[cfg(unix)]
+            // `bind_uds` would remove any existing file, even the socket of another running instance
+            crate::model::SocketListener::Unix(path) => {
+                lib::bind_unix_socket(path, &config.unix_socket).and_then(
+                    |(unix_listener, socket_file)| {
+                        socket_files.push(socket_file);
+                        http_server.listen_uds(unix_listener)
+                    },
+                )
+            }
+            #[cfg(unix)]
+            crate::model::SocketListener::Systemd(name_opt) => {
+                let (matching_listeners, remaining_listeners): (Vec<_>, Vec<_>) =
+                    std::mem::take(&mut inherited_listeners)
+                        .into_iter()
+                        .partition(|(name, _)| name_opt.as_ref().is_none_or(|n| n == name));
+
+                inherited_listeners = remaining_listeners;
+
+                if matching_listeners.is_empty() {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "no matching socket was passed by systemd",
+                    ))
+                } else {
+                    matching_listeners.into_iter().try_fold(
+                        http_server,
+                        |http_server, (_, inherited_listener)| match inherited_listener {
+                            lib::InheritedListener::Tcp(tcp_listener) => {
+                                http_server.listen(tcp_listener)
+                            }
+                            lib::InheritedListener::Unix(unix_listener) => {
+                                http_server.listen_uds(unix_listener)
+                            }
+                        },
+                    )
+                }
+            }
+        }
+        .map_err(|err| ServerError::Bind(listener.to_string(), err))?;
+
+        log::info!("Listening on {listener}");
+    }
+
+    #[cfg(unix)]
+    for (name, _) in &inherited_listeners {
+        log::warn!("Socket '{name}' passed by systemd isn't used by any listener");
+    }
+
+    let server = http_server.run();
+
+    #[cfg(unix)]
+    notify_service_manager();
+
+    let run_res = server.await;
+
+    #[cfg(unix)]
+    drop(socket_files);
+
+    run_res.map_err(ServerError::Run)
+}
+
+/// Reports readiness to the service manager (if there's one) and keeps its watchdog alive.
+#[cfg(unix)]
+fn notify_service_manager() {
+    match lib::notify("READY=1") {
+        Ok(true) => log::debug!("Readiness reported to the service manager"),
+        Ok(false) => return,
+        Err(err) => {
+            log::error!("Couldn't report readiness to the service manager: {err:?}");
+            return;
         }
+    }
+
+    if let Some(watchdog_interval) = lib::get_watchdog_interval() {
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(watchdog_interval);
+
+            loop {
+                interval.tick().await;
+
+                if let Err(err) = lib::notify("WATCHDOG=1") {
+                    log::error!("Couldn't send watchdog keepalive: {err:?}");
+                }
+            }
+        });
+    }
+}
+
+/// Reloads the config on every SIGHUP, running requests keep using the previous values.
+#[cfg(unix)]
+async fn reload_on_hangup() {
+    use actix_web::rt::signal::unix::{SignalKind, signal};
+
+    let mut hangup_signal = match signal(SignalKind::hangup()) {
+        Ok(hangup_signal) => hangup_signal,
         Err(err) => {
-            log::error!("Couldn't bind to '{:?}'", &config.listen);
-            panic!("{err:?}");
+            log::error!("Couldn't register SIGHUP handler, config reload is unavailable: {err:?}");
+            return;
+        }
+    };
+
+    while hangup_signal.recv().await.is_some() {
+        log::info!("SIGHUP received, reloading config");
+
+        if let Err(err) = actix_web::rt::task::spawn_blocking(crate::reload_shared_values).await {
+            log::error!("Config reload failed: {err:?}");
         }
     }
-    .run()
-    .await
-    .expect("Couldn't start HTTP workers");
 }
 
 fn get_default_headers_middleware() -> actix_web::middleware::DefaultHeaders {
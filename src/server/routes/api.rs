@@ -0,0 +1,209 @@
+use sha2::Digest;
+
+use crate::{
+    model::{SignRequest, SignResponse, SignedProxyUrl},
+    utilities::{HEADER_VALUE_NO_CACHE, get_proxy_url, get_shared_state, get_url_expiry},
+};
+
+/// Maximum size in bytes of a sign request body.
+const MAX_REQUEST_SIZE: usize = 65_536;
+/// Maximum number of URLs which can be signed at once.
+const MAX_URL_COUNT: usize = 100;
+
+#[derive(serde::Serialize)]
+struct ApiError<'message> {
+    error: &'message str,
+}
+
+/**
+ * Signs the given URLs the same way `rewrite_url` does, for callers which don't have the HMAC secret.
+ * The body is only read once the caller is authorized.
+ **/
+#[actix_web::post("/api/sign")]
+pub async fn handle_sign_request(
+    http_request: actix_web::HttpRequest,
+    payload: actix_web::web::Payload,
+) -> actix_web::HttpResponse {
+    let shared_state = get_shared_state();
+    let api_tokens = shared_state
+        .config
+        .as_deref()
+        .map_or(&[][..], |config| config.api_tokens.as_slice());
+
+    if !is_authorized(http_request.headers(), api_tokens) {
+        let mut response =
+            get_error_response(actix_web::http::StatusCode::UNAUTHORIZED, "Unauthorized");
+
+        response.headers_mut().insert(
+            actix_web::http::header::WWW_AUTHENTICATE,
+            actix_web::http::header::HeaderValue::from_static("Bearer"),
+        );
+
+        return response;
+    }
+
+    let sign_request = match actix_web::web::JsonBody::<SignRequest>::new(
+        &http_request,
+        &mut payload.into_inner(),
+        None,
+        true,
+    )
+    .limit(MAX_REQUEST_SIZE)
+    .await
+    {
+        Ok(sign_request) => sign_request,
+        Err(
+            actix_web::error::JsonPayloadError::Overflow { .. }
+            | actix_web::error::JsonPayloadError::OverflowKnownLength { .. },
+        ) => {
+            return get_error_response(
+                actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body too large",
+            );
+        }
+        Err(err) => {
+            log::debug!("invalid sign request: {err:?}");
+
+            return get_error_response(
+                actix_web::http::StatusCode::BAD_REQUEST,
+                "Invalid request body",
+            );
+        }
+    };
+    let urls = sign_request.into_urls();
+
+    if urls.is_empty() {
+        return get_error_response(actix_web::http::StatusCode::BAD_REQUEST, "Missing URL");
+    } else if urls.len() > MAX_URL_COUNT {
+        return get_error_response(
+            actix_web::http::StatusCode::BAD_REQUEST,
+            &format!("Too many URLs (at most {MAX_URL_COUNT})"),
+        );
+    }
+
+    let expires = get_url_expiry(&shared_state);
+    let mut signed_urls = Vec::with_capacity(urls.len());
+
+    for url in urls {
+        let proxy_url_res = url::Url::parse(&url)
+            .map_err(crate::utilities::RewriteUrlError::from)
+            .and_then(|target_url| {
+                // the proxy only fetches HTTP(S) URLs
+                if is_http_url(&target_url) {
+                    get_proxy_url(&shared_state, target_url, expires, "./p/").map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+
+        match proxy_url_res {
+            Ok(Some(proxy_url)) => signed_urls.push(SignedProxyUrl { url, proxy_url }),
+            Ok(None) => {
+                return get_error_response(
+                    actix_web::http::StatusCode::BAD_REQUEST,
+                    &format!("Unsupported URL scheme: {url}"),
+                );
+            }
+            Err(err) => {
+                log::debug!("failed to sign '{url}': {err:?}");
+
+                return get_error_response(
+                    actix_web::http::StatusCode::BAD_REQUEST,
+                    &format!("Invalid URL: {url}"),
+                );
+            }
+        }
+    }
+
+    actix_web::HttpResponse::Ok()
+        .insert_header((
+            actix_web::http::header::CACHE_CONTROL,
+            HEADER_VALUE_NO_CACHE.clone(),
+        ))
+        .json(SignResponse {
+            expires,
+            urls: signed_urls,
+        })
+}
+
+fn get_error_response(
+    status_code: actix_web::http::StatusCode,
+    message: &str,
+) -> actix_web::HttpResponse {
+    actix_web::HttpResponse::build(status_code)
+        .insert_header((
+            actix_web::http::header::CACHE_CONTROL,
+            HEADER_VALUE_NO_CACHE.clone(),
+        ))
+        .json(ApiError { error: message })
+}
+
+fn is_http_url(url: &url::Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+/// Compares the SHA-256 digests of the tokens, so the comparison doesn't leak the configured tokens.
+fn is_authorized(headers: &actix_web::http::header::HeaderMap, api_tokens: &[String]) -> bool {
+    let Some(token) = headers
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+    else {
+        return false;
+    };
+    let token_digest = sha2::Sha256::digest(token.as_bytes());
+
+    api_tokens
+        .iter()
+        .any(|api_token| sha2::Sha256::digest(api_token.as_bytes()) == token_digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_authorized, is_http_url};
+
+    fn get_headers(authorization: &'static str) -> actix_web::http::header::HeaderMap {
+        let mut headers = actix_web::http::header::HeaderMap::new();
+
+        headers.insert(
+            actix_web::http::header::AUTHORIZATION,
+            actix_web::http::header::HeaderValue::from_static(authorization),
+        );
+
+        headers
+    }
+
+    #[test]
+    fn bearer_token() {
+        let api_tokens = [String::from("first"), String::from("second")];
+
+        assert!(is_authorized(&get_headers("Bearer first"), &api_tokens));
+        assert!(is_authorized(&get_headers("Bearer second"), &api_tokens));
+        assert!(!is_authorized(&get_headers("Bearer third"), &api_tokens));
+        assert!(!is_authorized(&get_headers("Basic first"), &api_tokens));
+        assert!(!is_authorized(&get_headers("Bearer "), &[String::new()]));
+        assert!(!is_authorized(
+            &actix_web::http::header::HeaderMap::new(),
+            &api_tokens
+        ));
+    }
+
+    #[test]
+    fn http_urls_only() {
+        for (url, expected) in [
+            ("https://example.com/", true),
+            ("http://example.com/", true),
+            ("ftp://example.com/", false),
+            ("file:///etc/passwd", false),
+            ("javascript:alert(1)", false),
+        ] {
+            assert_eq!(
+                is_http_url(&url::Url::parse(url).unwrap()),
+                expected,
+                "{url}"
+            );
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use crate::{
     server::lib::fetch_url,
-    utilities::{ClientError, ClientResponseBody, FormRequest},
+    utilities::{
+        ClientError, ClientResponseBody, FormRequest, LINK_STORE, SignedUrl, get_path_url,
+        get_proxy_url, get_shared_state,
+    },
 };
 
 #[actix_web::get("/")]
@@ -8,17 +11,19 @@ pub async fn handle_get_request(
     query: actix_web::web::Query<crate::model::IndexHttpArgs>,
     http_request: actix_web::HttpRequest,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
+    if let Some(redirect_response) = get_path_redirect(&query) {
+        return redirect_response;
+    }
+
+    if let Some(link_id) = query.link.as_deref() {
+        return fetch_link(link_id, http_request.headers(), None).await;
+    }
+
     let response = get_base_response();
 
-    match query.url.as_deref() {
+    match query.signed_url() {
         None => render_index(response),
-        Some(url) => {
-            if let Some(hash) = query.hash.as_deref() {
-                fetch_url(response, url, hash, http_request.headers(), None).await
-            } else {
-                render_index(response)
-            }
-        }
+        Some(signed_url) => fetch_url(response, signed_url, http_request.headers(), None).await,
     }
 }
 
@@ -26,38 +31,207 @@ pub async fn handle_get_request(
 pub async fn handle_post_request(
     query: actix_web::web::Query<crate::model::IndexHttpArgs>,
     http_request: actix_web::HttpRequest,
-    mut body: actix_web::web::Form<std::collections::HashMap<String, String>>,
+    body: actix_web::web::Form<std::collections::HashMap<String, String>>,
 ) -> actix_web::HttpResponse<ClientResponseBody> {
-    let response = get_base_response();
+    if let Some(redirect_response) = get_path_redirect(&query) {
+        return redirect_response;
+    }
 
-    if let Some(url) = query.url.as_deref() {
-        if let Some(hash) = query.hash.as_deref() {
-            let origin_method = body
-                .remove("_searproxy_origin_method")
-                .map(|m| m.to_ascii_uppercase());
-            let method = origin_method.as_deref().unwrap_or("GET");
-
-            return fetch_url(
-                response,
-                url,
-                hash,
-                http_request.headers(),
-                Some(FormRequest {
-                    body: body.into_inner(),
-                    method: if method.trim() == "GET" {
-                        reqwest::Method::GET
-                    } else {
-                        reqwest::Method::POST
-                    },
-                }),
-            )
-            .await;
-        }
+    if let Some(link_id) = query.link.as_deref() {
+        return fetch_link(
+            link_id,
+            http_request.headers(),
+            Some(get_form_request(body.into_inner())),
+        )
+        .await;
+    }
+
+    if let Some(signed_url) = query.signed_url() {
+        return fetch_url(
+            get_base_response(),
+            signed_url,
+            http_request.headers(),
+            Some(get_form_request(body.into_inner())),
+        )
+        .await;
     }
 
     crate::server::lib::get_error_response(ClientError::BadRequest)
 }
 
+#[actix_web::get("/p/l/{id}")]
+pub async fn handle_link_get_request(
+    path: actix_web::web::Path<String>,
+    http_request: actix_web::HttpRequest,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    fetch_link(&path, http_request.headers(), None).await
+}
+
+#[actix_web::post("/p/l/{id}")]
+pub async fn handle_link_post_request(
+    path: actix_web::web::Path<String>,
+    http_request: actix_web::HttpRequest,
+    body: actix_web::web::Form<std::collections::HashMap<String, String>>,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    fetch_link(
+        &path,
+        http_request.headers(),
+        Some(get_form_request(body.into_inner())),
+    )
+    .await
+}
+
+#[actix_web::get("/p/{hash}/{url}")]
+pub async fn handle_path_get_request(
+    path: actix_web::web::Path<(String, String)>,
+    query: actix_web::web::Query<crate::model::IndexHttpArgs>,
+    http_request: actix_web::HttpRequest,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    let (hash, url) = path.into_inner();
+
+    fetch_url(
+        get_base_response(),
+        SignedUrl::Path {
+            url: &url,
+            hash: &hash,
+            expires: query.expires,
+        },
+        http_request.headers(),
+        None,
+    )
+    .await
+}
+
+#[actix_web::post("/p/{hash}/{url}")]
+pub async fn handle_path_post_request(
+    path: actix_web::web::Path<(String, String)>,
+    query: actix_web::web::Query<crate::model::IndexHttpArgs>,
+    http_request: actix_web::HttpRequest,
+    body: actix_web::web::Form<std::collections::HashMap<String, String>>,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    let (hash, url) = path.into_inner();
+
+    fetch_url(
+        get_base_response(),
+        SignedUrl::Path {
+            url: &url,
+            hash: &hash,
+            expires: query.expires,
+        },
+        http_request.headers(),
+        Some(get_form_request(body.into_inner())),
+    )
+    .await
+}
+
+fn get_form_request(mut body: std::collections::HashMap<String, String>) -> FormRequest {
+    let origin_method = body
+        .remove("_searproxy_origin_method")
+        .map(|m| m.to_ascii_uppercase());
+    let method = origin_method.as_deref().unwrap_or("GET");
+
+    FormRequest {
+        method: if method.trim() == "GET" {
+            reqwest::Method::GET
+        } else {
+            reqwest::Method::POST
+        },
+        body,
+    }
+}
+
+/// Resolves the short link id through the link store, before the stored URL is fetched.
+async fn fetch_link(
+    link_id: &str,
+    headers: &actix_web::http::header::HeaderMap,
+    request_body: Option<FormRequest>,
+) -> actix_web::HttpResponse<ClientResponseBody> {
+    let Some((url, expires)) = LINK_STORE
+        .get()
+        .and_then(|link_store| link_store.get(link_id))
+    else {
+        return crate::server::lib::get_error_response(ClientError::LinkNotFound);
+    };
+
+    fetch_url(
+        get_base_response(),
+        SignedUrl::Stored { url: &url, expires },
+        headers,
+        request_body,
+    )
+    .await
+}
+
+/**
+ * Redirects signed URLs and short links to their path form (if enabled), since links within proxied pages
+ * are rewritten relative to the path route. The "307" status code preserves the method and body of form requests.
+ **/
+fn get_path_redirect(
+    query: &crate::model::IndexHttpArgs,
+) -> Option<actix_web::HttpResponse<ClientResponseBody>> {
+    let shared_state = get_shared_state();
+
+    if !shared_state
+        .config
+        .as_deref()
+        .is_some_and(|config| config.path_urls)
+    {
+        return None;
+    }
+
+    let path_url = if let Some(link_id) = query.link.as_deref() {
+        // link ids are URL safe Base64, anything else can't be resolved anyway
+        if !link_id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        {
+            return None;
+        }
+
+        format!("./p/l/{link_id}")
+    } else if let Some(SignedUrl::Plain { url, hash, expires }) = query.signed_url() {
+        match hex::decode(hash) {
+            Ok(hash_bytes) => get_path_url("./p/", &hash_bytes, url, expires),
+            Err(err) => {
+                return Some(crate::server::lib::get_error_response(ClientError::Hex(
+                    err,
+                )));
+            }
+        }
+    } else if let Some(SignedUrl::Opaque(opaque_url)) = query.signed_url() {
+        // opaque URLs have no path form, but they're only created with URL encryption (which excludes path URLs)
+        let path_url_res = shared_state
+            .url_cipher
+            .as_deref()
+            .ok_or(ClientError::UrlCipherInstance)
+            .and_then(|url_cipher| Ok(url_cipher.decrypt(opaque_url)?))
+            .and_then(|(url, expires_opt)| {
+                Ok(get_proxy_url(
+                    &shared_state,
+                    url::Url::parse(&url)?,
+                    expires_opt,
+                    "./p/",
+                )?)
+            });
+
+        match path_url_res {
+            Ok(path_url) => path_url,
+            Err(err) => return Some(crate::server::lib::get_error_response(err)),
+        }
+    } else {
+        return None;
+    };
+    let location = actix_web::http::header::HeaderValue::try_from(path_url).ok()?;
+    let mut response = get_base_response();
+
+    response
+        .headers_mut()
+        .insert(actix_web::http::header::LOCATION, location);
+    *response.status_mut() = actix_web::http::StatusCode::TEMPORARY_REDIRECT;
+
+    Some(response)
+}
+
 fn get_base_response() -> actix_web::HttpResponse<ClientResponseBody> {
     let mut response = actix_web::HttpResponse::<ClientResponseBody>::with_body(
         actix_web::http::StatusCode::OK,
@@ -3,10 +3,10 @@ pub use HeaderTemplate as Header;
 use crate::templates::base::self_ref;
 
 markup::define! {
-    HeaderTemplate(url: std::rc::Rc<url::Url>) {
+    HeaderTemplate(url: std::rc::Rc<url::Url>, path_urls: bool) {
         div {
             h1 {
-                @self_ref("./", "SearProxy")
+                @self_ref(index_url(*path_urls), "SearProxy")
                 " is neither the owner nor the author of this content."
             }
             p {
@@ -17,3 +17,8 @@ markup::define! {
         }
     }
 }
+
+/// Pages which were served by the path route ("p/<hash>/<url>") are two levels below the index.
+fn index_url(path_urls: bool) -> &'static str {
+    if path_urls { "../../" } else { "./" }
+}
@@ -6,7 +6,8 @@ mod redirect;
 
 pub enum Template<'error_name, 'error_description> {
     Error(Option<crate::server::lib::ErrorMessage<'error_name, 'error_description>>),
-    Header(std::rc::Rc<url::Url>),
+    /// Header of proxied pages, with the original URL and whether the path route is used.
+    Header(std::rc::Rc<url::Url>, bool),
     Index,
     Redirect(crate::utilities::ClientRedirect),
 }
@@ -14,7 +15,7 @@ pub enum Template<'error_name, 'error_description> {
 pub fn render_template_string(template: Template<'_, '_>) -> String {
     match template {
         Template::Error(error_detail) => error::error(&error_detail).to_string(),
-        Template::Header(url) => header::Header { url }.to_string(),
+        Template::Header(url, path_urls) => header::Header { url, path_urls }.to_string(),
         Template::Index => index::index().to_string(),
         Template::Redirect(url) => redirect::redirect(url).to_string(),
     }
@@ -0,0 +1,256 @@
+use std::time::Duration;
+
+use actix_web::rt::time::Instant;
+use futures_util::StreamExt;
+
+use crate::utilities::client::ClientError;
+
+/// Minimum throughput will only be enforced after this period, to allow for a slow start.
+const THROUGHPUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum BodyLimitError {
+    #[error("The response exceeds the maximum size of {0} bytes")]
+    Size(u64),
+    #[error("The response didn't send any data for {0} seconds")]
+    IdleTimeout(u64),
+    #[error("The response was sent slower than {0} bytes per second")]
+    Throughput(u64),
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BodyTimeLimits {
+    pub idle_timeout: Option<Duration>,
+    /// Bytes per second.
+    pub min_throughput: Option<u64>,
+}
+
+struct GuardState<S> {
+    limits: BodyTimeLimits,
+    received: u64,
+    started: Instant,
+    stream: S,
+}
+
+/**
+ * Fails the stream if no chunk arrives within the idle timeout or if the average throughput
+ * (after `THROUGHPUT_GRACE_PERIOD`) drops below the minimum throughput.
+ **/
+pub fn guard_body_stream<S, E>(
+    stream: S,
+    limits: BodyTimeLimits,
+) -> impl futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, E>> + Unpin + 'static,
+    E: Into<ClientError>,
+{
+    Box::pin(futures_util::stream::unfold(
+        Some(GuardState {
+            limits,
+            received: 0,
+            started: Instant::now(),
+            stream,
+        }),
+        |state_opt| async move {
+            let mut state = state_opt?;
+            let next_chunk = match next_deadline(&state) {
+                Some((deadline, err)) => {
+                    let duration = deadline.saturating_duration_since(Instant::now());
+
+                    match actix_web::rt::time::timeout(duration, state.stream.next()).await {
+                        Ok(next_chunk) => next_chunk,
+                        Err(_) => return Some((Err(ClientError::from(err)), None)),
+                    }
+                }
+                None => state.stream.next().await,
+            };
+
+            match next_chunk? {
+                Ok(chunk) => {
+                    state.received += chunk.len() as u64;
+                    Some((Ok(chunk), Some(state)))
+                }
+                Err(err) => Some((Err(err.into()), None)),
+            }
+        },
+    ))
+    // the body may be polled again after it ended (e.g. after reading the prefix of a short document)
+    .fuse()
+}
+
+/// Fails the stream as soon as more than `max_size` bytes (including the already `consumed` ones) were received.
+pub fn limit_body_size<S>(
+    stream: S,
+    max_size: Option<u64>,
+    consumed: u64,
+) -> impl futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static,
+{
+    stream.scan(Some(consumed), move |received_opt, chunk_res| {
+        let Some(received) = received_opt else {
+            return futures_util::future::ready(None);
+        };
+        let item = chunk_res.and_then(|chunk| {
+            *received += chunk.len() as u64;
+
+            match max_size {
+                Some(max_size) if *received > max_size => {
+                    Err(ClientError::from(BodyLimitError::Size(max_size)))
+                }
+                _ => Ok(chunk),
+            }
+        });
+
+        // end the stream after the first error
+        if item.is_err() {
+            *received_opt = None;
+        }
+
+        futures_util::future::ready(Some(item))
+    })
+}
+
+/// Returns the earliest point in time at which the next chunk has to be received.
+fn next_deadline<S>(state: &GuardState<S>) -> Option<(Instant, BodyLimitError)> {
+    let idle_deadline_opt = state.limits.idle_timeout.map(|idle_timeout| {
+        (
+            Instant::now() + idle_timeout,
+            BodyLimitError::IdleTimeout(idle_timeout.as_secs()),
+        )
+    });
+    // the time at which the average throughput would drop below the minimum
+    let throughput_deadline_opt = state
+        .limits
+        .min_throughput
+        .filter(|min_throughput| *min_throughput > 0)
+        .map(|min_throughput| {
+            (
+                state.started
+                    + THROUGHPUT_GRACE_PERIOD.max(Duration::from_millis(
+                        state.received.saturating_mul(1000) / min_throughput,
+                    )),
+                BodyLimitError::Throughput(min_throughput),
+            )
+        });
+
+    match (idle_deadline_opt, throughput_deadline_opt) {
+        (Some(idle_deadline), Some(throughput_deadline)) => {
+            if idle_deadline.0 <= throughput_deadline.0 {
+                Some(idle_deadline)
+            } else {
+                Some(throughput_deadline)
+            }
+        }
+        (idle_deadline_opt, throughput_deadline_opt) => {
+            idle_deadline_opt.or(throughput_deadline_opt)
+        }
+    }
+}
+
+/// Rejects a response upfront, if its announced length already exceeds `max_size`.
+pub fn verify_content_length(
+    content_length_opt: Option<u64>,
+    max_size_opt: Option<u64>,
+) -> Result<(), BodyLimitError> {
+    match (content_length_opt, max_size_opt) {
+        (Some(content_length), Some(max_size)) if content_length > max_size => {
+            Err(BodyLimitError::Size(max_size))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::StreamExt;
+
+    use super::{
+        BodyLimitError, BodyTimeLimits, guard_body_stream, limit_body_size, verify_content_length,
+    };
+    use crate::utilities::ClientError;
+
+    fn chunks(
+        count: usize,
+    ) -> impl futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin {
+        futures_util::stream::iter(
+            (0..count).map(|_| Ok::<_, ClientError>(bytes::Bytes::from_static(b"0123456789"))),
+        )
+    }
+
+    #[actix_web::test]
+    async fn size_within_limit() {
+        let results: Vec<_> = limit_body_size(chunks(3), Some(30), 0).collect().await;
+
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[actix_web::test]
+    async fn size_exceeded() {
+        let results: Vec<_> = limit_body_size(chunks(5), Some(30), 5).collect().await;
+
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[2],
+            Err(ClientError::BodyLimit(BodyLimitError::Size(30)))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn idle_timeout() {
+        let stream = chunks(1).chain(futures_util::stream::pending());
+        let results: Vec<_> = guard_body_stream(
+            stream,
+            BodyTimeLimits {
+                idle_timeout: Some(Duration::from_millis(20)),
+                min_throughput: None,
+            },
+        )
+        .collect()
+        .await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(matches!(
+            results[1],
+            Err(ClientError::BodyLimit(BodyLimitError::IdleTimeout(_)))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn guard_passthrough() {
+        let results: Vec<_> = guard_body_stream(
+            chunks(4),
+            BodyTimeLimits {
+                idle_timeout: Some(Duration::from_secs(1)),
+                min_throughput: Some(1),
+            },
+        )
+        .collect()
+        .await;
+
+        assert_eq!(results.len(), 4);
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[actix_web::test]
+    async fn guard_poll_after_end() {
+        let mut stream = guard_body_stream(chunks(1), BodyTimeLimits::default());
+
+        assert!(stream.next().await.is_some());
+        assert!(stream.next().await.is_none());
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn content_length() {
+        assert!(verify_content_length(Some(10), Some(10)).is_ok());
+        assert!(verify_content_length(Some(11), None).is_ok());
+        assert!(verify_content_length(None, Some(10)).is_ok());
+        assert!(verify_content_length(Some(11), Some(10)).is_err());
+    }
+}
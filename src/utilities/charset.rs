@@ -0,0 +1,129 @@
+use encoding_rs::Encoding;
+
+/// Amount of bytes to look at for a `<meta>` charset declaration (as defined by the HTML spec).
+pub const CHARSET_PRESCAN_LENGTH: usize = 1024;
+
+static META_CHARSET_REGEX: once_cell::sync::Lazy<regex::bytes::Regex> =
+    once_cell::sync::Lazy::new(|| {
+        regex::bytes::Regex::new(
+            r#"(?i)<meta[^>]+charset\s*=\s*["']?\s*(?P<charset>[a-z0-9_:.\-]+)"#,
+        )
+        .expect("RegExp compilation failed")
+    });
+
+/**
+ * Determines the encoding of an HTML document in the following order:
+ * - byte order mark
+ * - `charset` parameter of the `Content-Type` header
+ * - `<meta charset>` / `<meta http-equiv="content-type">` within the first 1024 bytes
+ * - UTF-8
+ **/
+pub fn detect_html_encoding(content_type: &mime::Mime, prefix: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(prefix) {
+        return encoding;
+    }
+
+    if let Some(encoding) = content_type
+        .get_param(mime::CHARSET)
+        .and_then(|charset| Encoding::for_label(charset.as_str().as_bytes()))
+    {
+        return encoding;
+    }
+
+    let prescan = &prefix[..prefix.len().min(CHARSET_PRESCAN_LENGTH)];
+
+    if let Some(encoding) = META_CHARSET_REGEX
+        .captures(prescan)
+        .and_then(|capture| capture.name("charset"))
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+    {
+        // a document can't declare itself as UTF-16 from within, since it would've had to be
+        // decoded already (see: https://html.spec.whatwg.org/#prescan-a-byte-stream-to-determine-its-encoding)
+        return if encoding == encoding_rs::UTF_16BE || encoding == encoding_rs::UTF_16LE {
+            encoding_rs::UTF_8
+        } else if encoding == encoding_rs::X_USER_DEFINED {
+            encoding_rs::WINDOWS_1252
+        } else {
+            encoding
+        };
+    }
+
+    encoding_rs::UTF_8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect_html_encoding;
+
+    #[test]
+    fn detect_default() {
+        assert_eq!(
+            detect_html_encoding(&mime::TEXT_HTML, b"<html><body></body></html>"),
+            encoding_rs::UTF_8
+        );
+    }
+
+    #[test]
+    fn detect_bom() {
+        assert_eq!(
+            detect_html_encoding(
+                &"text/html; charset=shift_jis".parse().unwrap(),
+                b"\xFE\xFF\x00<"
+            ),
+            encoding_rs::UTF_16BE
+        );
+    }
+
+    #[test]
+    fn detect_content_type() {
+        assert_eq!(
+            detect_html_encoding(
+                &"text/html; charset=Shift_JIS".parse().unwrap(),
+                b"<meta charset=\"windows-1251\">"
+            ),
+            encoding_rs::SHIFT_JIS
+        );
+    }
+
+    #[test]
+    fn detect_meta_charset() {
+        assert_eq!(
+            detect_html_encoding(
+                &mime::TEXT_HTML,
+                b"<html><head><meta charset=\"windows-1251\"></head></html>"
+            ),
+            encoding_rs::WINDOWS_1251
+        );
+    }
+
+    #[test]
+    fn detect_meta_http_equiv() {
+        assert_eq!(
+            detect_html_encoding(
+                &mime::TEXT_HTML,
+                b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=ISO-8859-2\">"
+            ),
+            encoding_rs::ISO_8859_2
+        );
+    }
+
+    #[test]
+    fn detect_meta_utf_16() {
+        assert_eq!(
+            detect_html_encoding(&mime::TEXT_HTML, b"<meta charset=utf-16>"),
+            encoding_rs::UTF_8
+        );
+    }
+
+    #[test]
+    fn ignore_meta_after_prescan() {
+        let mut document = b" ".repeat(super::CHARSET_PRESCAN_LENGTH);
+
+        document.extend_from_slice(b"<meta charset=\"windows-1251\">");
+
+        assert_eq!(
+            detect_html_encoding(&mime::TEXT_HTML, &document),
+            encoding_rs::UTF_8
+        );
+    }
+}
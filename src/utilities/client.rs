@@ -1,20 +1,40 @@
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    rc::Rc,
+    sync::Arc,
+};
 
+use base64::Engine;
 use futures_util::StreamExt;
 
 use crate::{
-    model::PermittedIpRange,
+    model::{AccessError, IpRule, IpRuleAction, PermittedIpRange},
     utilities::{
-        GLOBAL_CONFIG,
+        IN_FLIGHT_REQUESTS, RESPONSE_CACHE, SharedState,
+        body_limit::{
+            BodyLimitError, BodyTimeLimits, guard_body_stream, limit_body_size,
+            verify_content_length,
+        },
+        charset::{CHARSET_PRESCAN_LENGTH, detect_html_encoding},
+        get_unix_timestamp,
+        ip_scope::{IpScope, classify_ip_v4, classify_ip_v6},
+        media_type::{
+            MediaType, SNIFF_LENGTH, classify_media_type, is_navigable_request, parse_content_type,
+            requires_sniffing,
+        },
+        resolver::{ResolveError, resolve_permitted},
+        response_cache::ResponseCache,
         rewrite_css::{CssRewrite, RewriteCssError},
         rewrite_html::HtmlRewrite,
-        rewrite_html::HtmlRewriteResult,
         rewrite_url::rewrite_url,
+        shared::{BASE64_ENGINE, BASE64_URL_ENGINE},
+        singleflight::FlightKey,
+        url_cipher::UrlCipherError,
     },
 };
 
 pub type ClientResponseStream =
-    std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>;
+    std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<bytes::Bytes, ClientError>>>>;
 
 pub type ClientResponseBody = actix_web::body::EitherBody<
     actix_web::body::EitherBody<
@@ -30,22 +50,34 @@ pub enum ClientError {
     HmacInstance,
     #[error("Hex decode failed")]
     Hex(#[from] hex::FromHexError),
+    #[error("Base64 decode failed")]
+    Base64(#[from] base64::DecodeError),
     #[error("Request client is uninitialized")]
     RequestClient,
     #[error("HTTP request failed")]
     Request(#[from] reqwest::Error),
     #[error("HMAC hash is invalid")]
     InvalidHash,
+    #[error("URL expired at {0}")]
+    UrlExpired(u64),
+    #[error("URL without expiry")]
+    UrlWithoutExpiry,
+    #[error("URL cipher uninitialized")]
+    UrlCipherInstance,
+    #[error("Short link not found")]
+    LinkNotFound,
+    #[error("Opaque URL is invalid")]
+    UrlCipher(#[from] UrlCipherError),
     #[error("HTTP request failed with status code: {0}")]
     UnexpectedStatusCode(u16),
     #[error("String decode failed")]
     StringDecode(#[from] reqwest::header::ToStrError),
     #[error("URL parsing failed")]
     UrlParse(#[from] url::ParseError),
-    #[error("MIME parsing failed")]
-    MimeParse(#[from] mime::FromStrError),
     #[error("UTF-8 decoding failed")]
     Utf8Decode(#[from] std::str::Utf8Error),
+    #[error("Failed to create UTF-8 string")]
+    Utf8String(#[from] std::string::FromUtf8Error),
     #[error("URL rewriting failed")]
     UrlRewrite(#[from] crate::utilities::rewrite_url::RewriteUrlError),
     #[error("HTML rewriting failed")]
@@ -56,22 +88,110 @@ pub enum ClientError {
     BadRequest,
     #[error("Server returned 3XX status code without a 'Location' header")]
     RedirectWithoutLocation,
+    #[error("Random number generation failed")]
+    Random(#[from] getrandom::Error),
     #[error("The IP `{0}` is not within the permitted range(s)")]
     IpRangeDenied(String),
     #[error("Can't resolve hostname `{0}`")]
     ResolveHostname(String),
+    #[error("Request denied by access control")]
+    AccessDenied(#[from] AccessError),
+    #[error("Upstream response limit exceeded")]
+    BodyLimit(#[from] BodyLimitError),
+    #[error("Requested range not satisfiable")]
+    RangeNotSatisfiable(Option<reqwest::header::HeaderValue>),
+    #[error("Upstream returned a partial document, which can't be rewritten")]
+    PartialDocument,
+    #[error("Request fell too far behind the other requests for the same upstream response")]
+    SlowReader,
+    /// Error of a coalesced upstream fetch, which is shared by every waiting request.
+    #[error(transparent)]
+    Shared(std::sync::Arc<ClientError>),
+}
+
+impl From<ResolveError> for ClientError {
+    fn from(err: ResolveError) -> Self {
+        match err {
+            ResolveError::Hostname(hostname) => Self::ResolveHostname(hostname),
+            ResolveError::IpRangeDenied(hostname) => Self::IpRangeDenied(hostname),
+        }
+    }
 }
 
 pub enum FetchResult {
-    Response(ClientResponse),
+    Response(Box<ClientResponse>),
     Redirect(ClientRedirect),
+    NotModified(CacheHeaders),
 }
 
-pub enum BodyType {
-    Complete(bytes::Bytes),
-    Stream(
-        std::pin::Pin<Box<dyn futures_util::Stream<Item = Result<bytes::Bytes, reqwest::Error>>>>,
-    ),
+/// Result of an upstream fetch, before an HTML document was rewritten (see `UpstreamResult::into_fetch_result`).
+pub enum UpstreamResult {
+    Fetched(FetchResult),
+    Document(UpstreamDocument),
+}
+
+/// HTML document whose (size limited) body is rewritten for each request, since each one gets its own CSP nonce.
+pub struct UpstreamDocument {
+    pub base_url: url::Url,
+    pub body: ClientResponseStream,
+    pub content_type: mime::Mime,
+}
+
+impl UpstreamResult {
+    /// Rewrites the document with a new style nonce, using the current request's view of the shared values.
+    async fn into_fetch_result(
+        self,
+        shared_state: &Arc<SharedState>,
+    ) -> Result<FetchResult, ClientError> {
+        let document = match self {
+            Self::Fetched(fetch_result) => return Ok(fetch_result),
+            Self::Document(document) => document,
+        };
+        let style_nonce = create_style_nonce()?;
+
+        Ok(FetchResult::Response(Box::new(ClientResponse {
+            accept_ranges: None,
+            body: transform_html(
+                shared_state.clone(),
+                Rc::new(document.base_url),
+                document.body,
+                Vec::new(),
+                &document.content_type,
+                style_nonce.clone(),
+            )
+            .await?,
+            cache_headers: None,
+            content_disposition: None,
+            content_length: None,
+            content_range: None,
+            // the rewritten document is always UTF-8 encoded
+            content_type: mime::TEXT_HTML_UTF_8,
+            rewritten: true,
+            status_code: reqwest::StatusCode::OK,
+            style_nonce: Some(style_nonce),
+        })))
+    }
+}
+
+/// Target URL of a request, either signed with a hash or encrypted (see `UrlCipher`).
+pub enum SignedUrl<'url> {
+    Plain {
+        url: &'url str,
+        hash: &'url str,
+        expires: Option<u64>,
+    },
+    /// URL and binary hash, both encoded as URL safe Base64 (see `get_path_url`).
+    Path {
+        url: &'url str,
+        hash: &'url str,
+        expires: Option<u64>,
+    },
+    Opaque(&'url str),
+    /// URL which was resolved from a short link, it's trusted since only this proxy stores links.
+    Stored {
+        url: &'url str,
+        expires: Option<u64>,
+    },
 }
 
 pub struct FormRequest {
@@ -79,39 +199,164 @@ pub struct FormRequest {
     pub method: reqwest::Method,
 }
 
+/// Upstream validators and caching headers of a response.
+#[derive(Clone, Default)]
+pub struct CacheHeaders {
+    pub age: Option<reqwest::header::HeaderValue>,
+    pub cache_control: Option<reqwest::header::HeaderValue>,
+    pub etag: Option<reqwest::header::HeaderValue>,
+    pub expires: Option<reqwest::header::HeaderValue>,
+    pub last_modified: Option<reqwest::header::HeaderValue>,
+    pub vary: Option<reqwest::header::HeaderValue>,
+}
+
 pub struct ClientResponse {
-    pub body: BodyType,
+    pub accept_ranges: Option<reqwest::header::HeaderValue>,
+    pub body: ClientResponseStream,
+    /// `None` for responses which must not be cached (i.e. HTML documents and stylesheets with expiring links).
+    pub cache_headers: Option<CacheHeaders>,
     pub content_disposition: Option<reqwest::header::HeaderValue>,
     pub content_length: Option<u64>,
+    pub content_range: Option<reqwest::header::HeaderValue>,
     pub content_type: mime::Mime,
-    pub style_hashes: Option<Vec<String>>,
+    /// Rewritten bodies contain signed links, which depend on the keys and settings at the time of the request.
+    pub rewritten: bool,
+    /// Either "200 OK" or "206 Partial Content".
+    pub status_code: reqwest::StatusCode,
+    pub style_nonce: Option<Rc<str>>,
 }
 
+#[derive(Clone)]
 pub struct ClientRedirect {
     pub external_url: String,
     pub internal_url: String,
     pub status_code: reqwest::StatusCode,
 }
 
+/// Incremental rewriters whose output can be forwarded while the upstream body is still being read.
+trait BodyRewriter {
+    fn write(&mut self, chunk: &[u8]) -> Result<(), ClientError>;
+    fn take_output(&mut self) -> Vec<u8>;
+    fn end(self) -> Result<Vec<u8>, ClientError>;
+}
+
+impl BodyRewriter for HtmlRewrite<'static> {
+    fn write(&mut self, chunk: &[u8]) -> Result<(), ClientError> {
+        Ok(HtmlRewrite::write(self, chunk)?)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        HtmlRewrite::take_output(self)
+    }
+
+    fn end(self) -> Result<Vec<u8>, ClientError> {
+        Ok(HtmlRewrite::end(self)?.html)
+    }
+}
+
+impl BodyRewriter for CssRewrite {
+    fn write(&mut self, chunk: &[u8]) -> Result<(), ClientError> {
+        Ok(CssRewrite::write(self, chunk)?)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        CssRewrite::take_output(self)
+    }
+
+    fn end(self) -> Result<Vec<u8>, ClientError> {
+        Ok(CssRewrite::end(self)?)
+    }
+}
+
 static FALLBACK_ACCEPT_LANGUAGE: actix_web::http::header::HeaderValue =
     actix_web::http::header::HeaderValue::from_static("en");
+/// Client request headers which will be forwarded to validate cached responses.
+const CONDITIONAL_REQUEST_HEADERS: [(
+    actix_web::http::header::HeaderName,
+    reqwest::header::HeaderName,
+); 3] = [
+    (
+        actix_web::http::header::IF_MODIFIED_SINCE,
+        reqwest::header::IF_MODIFIED_SINCE,
+    ),
+    (
+        actix_web::http::header::IF_NONE_MATCH,
+        reqwest::header::IF_NONE_MATCH,
+    ),
+    (actix_web::http::header::IF_RANGE, reqwest::header::IF_RANGE),
+];
+
+impl CacheHeaders {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        Self {
+            age: headers.get(reqwest::header::AGE).cloned(),
+            cache_control: headers.get(reqwest::header::CACHE_CONTROL).cloned(),
+            etag: headers.get(reqwest::header::ETAG).cloned(),
+            expires: headers.get(reqwest::header::EXPIRES).cloned(),
+            last_modified: headers.get(reqwest::header::LAST_MODIFIED).cloned(),
+            vary: headers.get(reqwest::header::VARY).cloned(),
+        }
+    }
 
+    /// Rewritten responses differ from the upstream representation, so only a weak `ETag` is valid for them.
+    fn into_weak(mut self) -> Self {
+        self.etag = self.etag.and_then(|etag| {
+            if etag.as_bytes().starts_with(b"W/") {
+                Some(etag)
+            } else {
+                reqwest::header::HeaderValue::from_bytes(&[b"W/", etag.as_bytes()].concat()).ok()
+            }
+        });
+        self
+    }
+}
+
+/// Verifies the signed URL and fetches it with the shared values of the request.
 pub async fn fetch_validate_url(
-    url: &str,
-    hash: &str,
+    shared_state: &Arc<SharedState>,
+    signed_url: SignedUrl<'_>,
     headers: &actix_web::http::header::HeaderMap,
     request_body_opt: Option<FormRequest>,
 ) -> Result<FetchResult, ClientError> {
-    use hmac::Mac;
     use std::str::FromStr;
 
-    let mut hmac = match crate::utilities::HMAC.get() {
-        Some(instance) => instance.clone(),
+    let config_opt = shared_state.config.as_deref();
+    let hmac_keys = match shared_state.hmac.as_deref() {
+        Some(instance) => instance,
         None => return Err(ClientError::HmacInstance),
     };
+    let (url, expires_opt, hash_opt) = match signed_url {
+        SignedUrl::Plain { url, hash, expires } => (
+            std::borrow::Cow::Borrowed(url),
+            expires,
+            Some(hex::decode(hash)?),
+        ),
+        SignedUrl::Path { url, hash, expires } => (
+            std::borrow::Cow::Owned(String::from_utf8(BASE64_URL_ENGINE.decode(url)?)?),
+            expires,
+            Some(BASE64_URL_ENGINE.decode(hash)?),
+        ),
+        SignedUrl::Opaque(opaque_url) => {
+            let (url, expires_opt) = match shared_state.url_cipher.as_deref() {
+                Some(url_cipher) => url_cipher.decrypt(opaque_url)?,
+                None => return Err(ClientError::UrlCipherInstance),
+            };
+
+            (std::borrow::Cow::Owned(url), expires_opt, None)
+        }
+        SignedUrl::Stored { url, expires } => (std::borrow::Cow::Borrowed(url), expires, None),
+    };
+    let url = url.as_ref();
     let mut next_url = url::Url::from_str(url)?;
+    // form submissions and partial requests won't be cached
+    let cacheable =
+        request_body_opt.is_none() && !headers.contains_key(actix_web::http::header::RANGE);
+
+    if let Some(config) = config_opt {
+        config.access_control.verify_url(&next_url)?;
+    }
 
-    validate_request_host(&next_url)?;
+    validate_request_host(config_opt, &next_url).await?;
 
     let (method, request_body) = match request_body_opt {
         Some(payload) => {
@@ -124,36 +369,137 @@ pub async fn fetch_validate_url(
         }
         None => (reqwest::Method::GET, None),
     };
-    let hash_bytes = hex::decode(hash)?;
 
-    hmac.update(url.as_bytes());
+    // opaque URLs are already authenticated by their decryption, stored URLs by the link store
+    if let Some(hash_bytes) = hash_opt
+        && !hmac_keys.verify_url(url, expires_opt, &hash_bytes)
+    {
+        if log::log_enabled!(log::Level::Info) {
+            log::info!(
+                "rejecting request for: '{}' (invalid hash: {})",
+                url,
+                hex::encode(hash_bytes)
+            );
+        }
 
-    if hmac.verify_slice(&hash_bytes).is_ok() {
-        log::debug!("{} '{}'", method, next_url.as_str());
-        return fetch_transform_url(method, next_url, headers, request_body).await;
+        return Err(ClientError::InvalidHash);
     }
 
-    if log::log_enabled!(log::Level::Info) {
-        log::info!(
-            "rejecting request for: '{}' (invalid hash: {})",
-            url,
-            hex::encode(hash_bytes)
-        );
+    verify_url_expiry(
+        expires_opt,
+        config_opt.is_some_and(|config| config.require_url_expiry),
+        get_unix_timestamp(),
+    )?;
+    log::debug!("{} '{}'", method, next_url.as_str());
+
+    if cacheable {
+        return match RESPONSE_CACHE.get() {
+            Some(response_cache) => {
+                fetch_cached_url(shared_state, response_cache, url, next_url, headers).await
+            }
+            None => fetch_coalesced_url(shared_state, url, next_url, headers).await,
+        };
     }
 
-    Err(ClientError::InvalidHash)
+    fetch_upstream_url(shared_state, method, next_url, headers, request_body)
+        .await?
+        .into_fetch_result(shared_state)
+        .await
 }
 
-async fn fetch_transform_url(
+/// Rejects expired URLs and (if an expiry is required) URLs without an expiry.
+pub fn verify_url_expiry(
+    expires_opt: Option<u64>,
+    require_expiry: bool,
+    now: u64,
+) -> Result<(), ClientError> {
+    match expires_opt {
+        Some(expires) if expires < now => Err(ClientError::UrlExpired(expires)),
+        None if require_expiry => Err(ClientError::UrlWithoutExpiry),
+        _ => Ok(()),
+    }
+}
+
+/// Answers the request from the response cache if possible, otherwise the response will be stored (if cacheable).
+async fn fetch_cached_url(
+    shared_state: &Arc<SharedState>,
+    response_cache: &'static ResponseCache,
+    key: &str,
+    url: url::Url,
+    headers: &actix_web::http::header::HeaderMap,
+) -> Result<FetchResult, ClientError> {
+    if let Some(cached_response) = response_cache.get(key) {
+        log::info!("cache hit: '{}'", key);
+
+        return Ok(if cached_response.is_not_modified(headers) {
+            FetchResult::NotModified(cached_response.cache_headers())
+        } else {
+            FetchResult::Response(Box::new(cached_response.to_client_response()))
+        });
+    }
+
+    log::info!("cache miss: '{}'", key);
+
+    let generation = response_cache.generation();
+
+    Ok(
+        match fetch_coalesced_url(shared_state, key, url, headers).await? {
+            FetchResult::Response(client_response) => FetchResult::Response(Box::new(
+                response_cache.store(generation, String::from(key), *client_response),
+            )),
+            fetch_result => fetch_result,
+        },
+    )
+}
+
+/// Shares the upstream fetch with concurrent requests for the same URL, unless the request is conditional.
+async fn fetch_coalesced_url(
+    shared_state: &Arc<SharedState>,
+    key: &str,
+    url: url::Url,
+    headers: &actix_web::http::header::HeaderMap,
+) -> Result<FetchResult, ClientError> {
+    if CONDITIONAL_REQUEST_HEADERS
+        .iter()
+        .any(|(header_name, _)| headers.contains_key(header_name))
+    {
+        return fetch_upstream_url(shared_state, reqwest::Method::GET, url, headers, None)
+            .await?
+            .into_fetch_result(shared_state)
+            .await;
+    }
+
+    let flight_headers = headers.clone();
+    let flight_shared_state = shared_state.clone();
+
+    IN_FLIGHT_REQUESTS
+        .fetch(FlightKey::new(key, headers), async move {
+            fetch_upstream_url(
+                &flight_shared_state,
+                reqwest::Method::GET,
+                url,
+                &flight_headers,
+                None,
+            )
+            .await
+        })
+        .await?
+        .into_fetch_result(shared_state)
+        .await
+}
+
+async fn fetch_upstream_url(
+    shared_state: &Arc<SharedState>,
     method: reqwest::Method,
     url: url::Url,
     headers: &actix_web::http::header::HeaderMap,
     request_body: Option<std::collections::HashMap<String, String>>,
-) -> Result<FetchResult, ClientError> {
-    let request_client = match crate::utilities::REQUEST_CLIENT.get() {
+) -> Result<UpstreamResult, ClientError> {
+    let request_client = match shared_state.request_client.as_deref() {
         Some(client) => client,
         None => return Err(ClientError::RequestClient),
     };
+    let conditional = method == reqwest::Method::GET;
     let mut request = request_client
         .request(method, url.clone())
         .header(reqwest::header::ACCEPT, "*/*")
@@ -165,30 +511,87 @@ async fn fetch_transform_url(
                 .as_ref(),
         );
 
-    if let Some(range) = headers.get(actix_web::http::header::RANGE) {
-        request = request.header(reqwest::header::RANGE, range.as_ref())
+    if conditional {
+        for (header_name, upstream_header_name) in CONDITIONAL_REQUEST_HEADERS {
+            // "If-Range" is only forwarded along with the range
+            if header_name != actix_web::http::header::IF_RANGE
+                && let Some(value) = headers.get(header_name)
+            {
+                request = request.header(upstream_header_name, value.as_ref());
+            }
+        }
     }
 
     if let Some(payload) = request_body {
         request = request.form(&payload);
     }
 
-    let response = request.send().await?;
+    // documents are rewritten as a whole, so a partial document has to be requested again without the range
+    let mut full_request_opt = None;
+
+    if let Some(range) = headers.get(actix_web::http::header::RANGE) {
+        full_request_opt = request.try_clone();
+        request = request.header(reqwest::header::RANGE, range.as_ref());
+
+        if conditional && let Some(if_range) = headers.get(actix_web::http::header::IF_RANGE) {
+            request = request.header(reqwest::header::IF_RANGE, if_range.as_ref());
+        }
+    }
+
+    match (
+        send_upstream_request(shared_state, request, &url, headers).await,
+        full_request_opt,
+    ) {
+        (Err(ClientError::PartialDocument), Some(full_request)) => {
+            log::debug!(
+                "refetching partial document without range: '{}'",
+                url.as_str()
+            );
+            send_upstream_request(shared_state, full_request, &url, headers).await
+        }
+        (fetch_result, _) => fetch_result,
+    }
+}
+
+async fn send_upstream_request(
+    shared_state: &Arc<SharedState>,
+    request: reqwest::RequestBuilder,
+    url: &url::Url,
+    headers: &actix_web::http::header::HeaderMap,
+) -> Result<UpstreamResult, ClientError> {
+    let response = request.send().await.map_err(map_request_error)?;
     let status_code = response.status();
 
     if status_code.is_success() {
-        return Ok(FetchResult::Response(transform_response(response).await?));
+        return transform_response(shared_state, response, headers).await;
+    }
+
+    if status_code == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(UpstreamResult::Fetched(FetchResult::NotModified(
+            CacheHeaders::from_headers(response.headers()),
+        )));
+    }
+
+    if status_code == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        return Err(ClientError::RangeNotSatisfiable(
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_RANGE)
+                .cloned(),
+        ));
     }
 
     if status_code.is_redirection() {
         return if let Some(location) = response.headers().get(reqwest::header::LOCATION) {
             let redirect_url = location.to_str()?;
 
-            Ok(FetchResult::Redirect(ClientRedirect {
-                external_url: url.join(redirect_url)?.to_string(),
-                internal_url: String::from(rewrite_url(&url, redirect_url)?),
-                status_code,
-            }))
+            Ok(UpstreamResult::Fetched(FetchResult::Redirect(
+                ClientRedirect {
+                    external_url: url.join(redirect_url)?.to_string(),
+                    internal_url: String::from(rewrite_url(shared_state, url, redirect_url)?),
+                    status_code,
+                },
+            )))
         } else {
             Err(ClientError::RedirectWithoutLocation)
         };
@@ -205,80 +608,262 @@ fn append_form_params(url: &mut url::Url, params: std::collections::HashMap<Stri
     }
 }
 
-async fn transform_response(response: reqwest::Response) -> Result<ClientResponse, ClientError> {
-    let headers = response.headers();
-    let content_type: mime::Mime = match headers.get(reqwest::header::CONTENT_TYPE) {
-        Some(value) => value.to_str()?.parse()?,
-        None => mime::TEXT_PLAIN,
-    };
+async fn transform_response(
+    shared_state: &Arc<SharedState>,
+    response: reqwest::Response,
+    headers: &actix_web::http::header::HeaderMap,
+) -> Result<UpstreamResult, ClientError> {
+    let response_headers = response.headers();
+    let content_type_opt = response_headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(parse_content_type);
+    let content_length = response_headers
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(parse_content_length);
+    let content_disposition = response_headers
+        .get(reqwest::header::CONTENT_DISPOSITION)
+        .cloned();
+    let accept_ranges = response_headers
+        .get(reqwest::header::ACCEPT_RANGES)
+        .cloned();
+    let content_range = response_headers
+        .get(reqwest::header::CONTENT_RANGE)
+        .cloned();
+    let cache_headers = CacheHeaders::from_headers(response_headers);
+    let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let base_url = response.url().clone();
+    // the body of a partial response doesn't start at the beginning, so it can't be sniffed
+    let navigable = is_navigable_request(headers) && !partial;
+    let (time_limits, max_rewrite_size, max_passthrough_size, links_expire) =
+        match shared_state.config.as_deref() {
+            Some(config) => (
+                BodyTimeLimits {
+                    idle_timeout: config
+                        .read_idle_timeout
+                        .map(|idle_timeout| std::time::Duration::from_secs(idle_timeout as u64)),
+                    min_throughput: config.min_throughput,
+                },
+                config.max_rewrite_size,
+                config.max_passthrough_size,
+                config.url_lifetime.is_some() || config.link_store.is_some(),
+            ),
+            None => (BodyTimeLimits::default(), None, None, false),
+        };
+    let mut stream = guard_body_stream(response.bytes_stream(), time_limits);
+    let mut prefix = Vec::new();
 
-    Ok(
-        if content_type == mime::TEXT_HTML || content_type == mime::TEXT_HTML_UTF_8 {
-            let rewritten_html = transform_html(response).await?;
+    if requires_sniffing(content_type_opt.as_ref(), navigable) {
+        read_prefix(&mut stream, &mut prefix, SNIFF_LENGTH).await?;
+    }
+
+    let consumed = prefix.len() as u64;
+
+    let media_type = classify_media_type(content_type_opt.as_ref(), navigable, &prefix);
+
+    // a partial document can't be rewritten
+    if partial && matches!(media_type, MediaType::Html | MediaType::Css) {
+        return Err(ClientError::PartialDocument);
+    }
+
+    let client_response = match media_type {
+        MediaType::Html => {
+            verify_content_length(content_length, max_rewrite_size)?;
+
+            return Ok(UpstreamResult::Document(UpstreamDocument {
+                base_url,
+                body: Box::pin(
+                    futures_util::stream::iter(
+                        (!prefix.is_empty()).then(|| Ok(bytes::Bytes::from(prefix))),
+                    )
+                    .chain(limit_body_size(stream, max_rewrite_size, consumed)),
+                ),
+                content_type: content_type_opt.unwrap_or(mime::TEXT_HTML),
+            }));
+        }
+        MediaType::Css => {
+            verify_content_length(content_length, max_rewrite_size)?;
 
             ClientResponse {
-                body: BodyType::Complete(bytes::Bytes::from(rewritten_html.html)),
-                content_disposition: None,
-                content_length: None,
-                content_type,
-                style_hashes: Some(rewritten_html.style_hashes),
-            }
-        } else if content_type == mime::TEXT_CSS || content_type == mime::TEXT_CSS_UTF_8 {
-            ClientResponse {
-                body: BodyType::Complete(transform_css(response).await?),
+                accept_ranges: None,
+                body: transform_css(
+                    shared_state.clone(),
+                    Rc::new(base_url),
+                    limit_body_size(stream, max_rewrite_size, consumed),
+                    prefix,
+                )?,
+                // the browser must not reuse (or revalidate) a stylesheet whose links may have expired
+                cache_headers: (!links_expire).then(|| cache_headers.into_weak()),
                 content_disposition: None,
                 content_length: None,
-                content_type,
-                style_hashes: None,
+                content_range: None,
+                content_type: content_type_opt.unwrap_or(mime::TEXT_CSS),
+                rewritten: true,
+                status_code: reqwest::StatusCode::OK,
+                style_nonce: None,
             }
-        } else {
-            let content_length =
-                if let Some(body_size) = headers.get(reqwest::header::CONTENT_LENGTH) {
-                    parse_content_length(body_size)
-                } else {
-                    None
-                };
+        }
+        MediaType::Passthrough(content_type) => {
+            verify_content_length(content_length, max_passthrough_size)?;
 
             ClientResponse {
-                content_disposition: headers.get(reqwest::header::CONTENT_DISPOSITION).cloned(),
-                body: BodyType::Stream(Box::pin(response.bytes_stream())),
+                accept_ranges,
+                body: Box::pin(
+                    futures_util::stream::iter(
+                        (!prefix.is_empty()).then(|| Ok(bytes::Bytes::from(prefix))),
+                    )
+                    .chain(limit_body_size(
+                        stream,
+                        max_passthrough_size,
+                        consumed,
+                    )),
+                ),
+                cache_headers: Some(cache_headers),
+                content_disposition,
                 content_length,
+                content_range,
                 content_type,
-                style_hashes: None,
+                rewritten: false,
+                status_code: if partial {
+                    reqwest::StatusCode::PARTIAL_CONTENT
+                } else {
+                    reqwest::StatusCode::OK
+                },
+                style_nonce: None,
             }
-        },
-    )
+        }
+    };
+
+    Ok(UpstreamResult::Fetched(FetchResult::Response(Box::new(
+        client_response,
+    ))))
 }
 
-async fn transform_html(response: reqwest::Response) -> Result<HtmlRewriteResult, ClientError> {
-    let mut rewriter = HtmlRewrite::new(std::rc::Rc::new(response.url().clone()));
-    let mut stream = response.bytes_stream();
+async fn transform_html<S>(
+    shared_state: Arc<SharedState>,
+    base_url: Rc<url::Url>,
+    mut stream: S,
+    mut prefix: Vec<u8>,
+    content_type: &mime::Mime,
+    style_nonce: Rc<str>,
+) -> Result<ClientResponseStream, ClientError>
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static,
+{
+    // the encoding has to be known before the rewriter can be created
+    read_prefix(&mut stream, &mut prefix, CHARSET_PRESCAN_LENGTH).await?;
+
+    let mut rewriter = HtmlRewrite::with_encoding(
+        shared_state,
+        base_url,
+        Some(style_nonce),
+        detect_html_encoding(content_type, &prefix),
+    );
+
+    rewriter.write(&prefix)?;
+
+    Ok(rewrite_body_stream(stream, rewriter))
+}
+
+fn transform_css<S>(
+    shared_state: Arc<SharedState>,
+    base_url: Rc<url::Url>,
+    stream: S,
+    prefix: Vec<u8>,
+) -> Result<ClientResponseStream, ClientError>
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static,
+{
+    let mut rewriter = CssRewrite::new(shared_state, base_url);
+
+    rewriter.write(&prefix)?;
+
+    Ok(rewrite_body_stream(stream, rewriter))
+}
 
-    while let Some(chunk_res) = stream.next().await {
-        rewriter.write(chunk_res?.as_ref())?;
+/// Reads from `stream` until `prefix` contains at least `length` bytes or the stream has ended.
+async fn read_prefix<S>(
+    stream: &mut S,
+    prefix: &mut Vec<u8>,
+    length: usize,
+) -> Result<(), ClientError>
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin,
+{
+    while prefix.len() < length {
+        match stream.next().await {
+            Some(chunk_res) => prefix.extend_from_slice(chunk_res?.as_ref()),
+            None => break,
+        }
     }
 
-    Ok(rewriter.end()?)
+    Ok(())
 }
 
-async fn transform_css(response: reqwest::Response) -> Result<bytes::Bytes, ClientError> {
-    let mut rewriter = CssRewrite::new(std::rc::Rc::new(response.url().clone()));
-    let mut stream = response.bytes_stream();
+/// Feeds the upstream body into `rewriter` and yields every chunk of rewritten output as soon as
+/// it's available, so the client receives the document while it's still being fetched.
+fn rewrite_body_stream<S, R>(stream: S, rewriter: R) -> ClientResponseStream
+where
+    S: futures_util::Stream<Item = Result<bytes::Bytes, ClientError>> + Unpin + 'static,
+    R: BodyRewriter + 'static,
+{
+    Box::pin(futures_util::stream::unfold(
+        Some((stream, rewriter)),
+        |state| async move {
+            let (mut stream, mut rewriter) = state?;
+
+            while let Some(chunk_res) = stream.next().await {
+                let output_res = chunk_res.and_then(|chunk| {
+                    rewriter.write(chunk.as_ref())?;
+                    Ok(rewriter.take_output())
+                });
+
+                match output_res {
+                    Ok(output) if output.is_empty() => continue,
+                    Ok(output) => {
+                        return Some((Ok(bytes::Bytes::from(output)), Some((stream, rewriter))));
+                    }
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
 
-    while let Some(chunk_res) = stream.next().await {
-        rewriter.write(chunk_res?.as_ref())?;
-    }
+            Some((rewriter.end().map(bytes::Bytes::from), None))
+        },
+    ))
+}
+
+/// Creates a random value for the `style-src` CSP nonce, since the response headers are sent
+/// before the rewritten `<style>` contents (and therefore their hashes) are known.
+pub fn create_style_nonce() -> Result<Rc<str>, ClientError> {
+    let mut nonce = [0u8; 18];
 
-    Ok(bytes::Bytes::from(rewriter.end()?))
+    getrandom::fill(&mut nonce)?;
+
+    Ok(Rc::from(BASE64_ENGINE.encode(nonce)))
 }
 
-fn validate_request_host(url: &url::Url) -> Result<(), ClientError> {
-    if let Some(config) = GLOBAL_CONFIG.get() {
+async fn validate_request_host(
+    config_opt: Option<&crate::model::Config<'static, 'static>>,
+    url: &url::Url,
+) -> Result<(), ClientError> {
+    if let Some(config) = config_opt {
         if let Some(host) = url.host() {
             return match host {
-                url::Host::Ipv4(ip_v4) => verify_ip_v4_range(config.permitted_ip_range, ip_v4),
-                url::Host::Ipv6(ip_v6) => verify_ip_v6_range(config.permitted_ip_range, ip_v6),
-                url::Host::Domain(hostname) => verify_hostname(config.permitted_ip_range, hostname),
+                url::Host::Ipv4(ip_v4) => {
+                    verify_ip_v4_range(config.permitted_ip_range, &config.ip_rules, ip_v4)
+                }
+                url::Host::Ipv6(ip_v6) => {
+                    verify_ip_v6_range(config.permitted_ip_range, &config.ip_rules, ip_v6)
+                }
+                // without a proxy, the `PermittedIpResolver` will validate the addresses while connecting
+                url::Host::Domain(hostname) if config.proxy_address.is_some() => resolve_permitted(
+                    String::from(hostname),
+                    config.permitted_ip_range,
+                    &config.ip_rules,
+                )
+                .await
+                .map(|_| ())
+                .map_err(ClientError::from),
+                url::Host::Domain(_) => Ok(()),
             };
         }
     }
@@ -286,81 +871,82 @@ fn validate_request_host(url: &url::Url) -> Result<(), ClientError> {
     Ok(())
 }
 
+pub fn verify_ip_range(
+    permitted_ip_range: PermittedIpRange,
+    ip_rules: &[IpRule],
+    ip: IpAddr,
+) -> Result<(), ClientError> {
+    match ip {
+        IpAddr::V4(ip_v4) => verify_ip_v4_range(permitted_ip_range, ip_rules, ip_v4),
+        IpAddr::V6(ip_v6) => verify_ip_v6_range(permitted_ip_range, ip_rules, ip_v6),
+    }
+}
+
 fn verify_ip_v4_range(
     permitted_ip_range: PermittedIpRange,
+    ip_rules: &[IpRule],
     ip: Ipv4Addr,
 ) -> Result<(), ClientError> {
-    match permitted_ip_range {
-        PermittedIpRange::None => Err(ClientError::IpRangeDenied(ip.to_string())),
-        PermittedIpRange::Global => {
-            if ip.is_link_local()
-                || ip.is_broadcast()
-                || ip.is_loopback()
-                || ip.is_private()
-                || ip.is_unspecified()
-            {
-                Err(ClientError::IpRangeDenied(ip.to_string()))
-            } else {
-                Ok(())
-            }
-        }
-        PermittedIpRange::Private => {
-            if ip.is_link_local() || ip.is_broadcast() || ip.is_loopback() || ip.is_unspecified() {
-                Err(ClientError::IpRangeDenied(ip.to_string()))
-            } else {
-                Ok(())
-            }
-        }
-        PermittedIpRange::Local => {
-            if ip.is_link_local() || ip.is_broadcast() {
-                Err(ClientError::IpRangeDenied(ip.to_string()))
-            } else {
-                Ok(())
-            }
-        }
-    }
+    verify_ip_scope(
+        permitted_ip_range,
+        ip_rules,
+        classify_ip_v4(ip),
+        IpAddr::V4(ip),
+    )
 }
 
 fn verify_ip_v6_range(
     permitted_ip_range: PermittedIpRange,
+    ip_rules: &[IpRule],
     ip: Ipv6Addr,
 ) -> Result<(), ClientError> {
-    match permitted_ip_range {
-        PermittedIpRange::None => Err(ClientError::IpRangeDenied(ip.to_string())),
-        PermittedIpRange::Global | PermittedIpRange::Private => {
-            if ip.is_loopback() || ip.is_unspecified() {
-                Err(ClientError::IpRangeDenied(ip.to_string()))
-            } else {
-                Ok(())
-            }
-        }
-        PermittedIpRange::Local => Ok(()),
-    }
+    verify_ip_scope(
+        permitted_ip_range,
+        ip_rules,
+        classify_ip_v6(ip),
+        IpAddr::V6(ip),
+    )
 }
 
-fn verify_hostname(
+/// The first operator-defined rule which contains `ip` takes precedence over the `PermittedIpRange`.
+fn verify_ip_scope(
     permitted_ip_range: PermittedIpRange,
-    hostname: &str,
+    ip_rules: &[IpRule],
+    ip_scope: IpScope,
+    ip: IpAddr,
 ) -> Result<(), ClientError> {
-    let ip_addr = if let Some(socket_addr) = (hostname, 80)
-        .to_socket_addrs()
-        .map_err(|err| {
-            log::warn!("Couldn't resolve domain name `{hostname}`, with reason: {err}");
-            ClientError::ResolveHostname(hostname.to_string())
-        })?
-        .next()
-    {
-        socket_addr.ip()
-    } else {
-        return Err(ClientError::ResolveHostname(hostname.to_string()));
+    let permitted = match ip_rules.iter().find(|ip_rule| ip_rule.cidr.contains(ip)) {
+        Some(ip_rule) => ip_rule.action == IpRuleAction::Allow,
+        None => match permitted_ip_range {
+            PermittedIpRange::None => false,
+            PermittedIpRange::Global => ip_scope == IpScope::Global,
+            PermittedIpRange::Private => matches!(ip_scope, IpScope::Global | IpScope::Private),
+            PermittedIpRange::Local => ip_scope != IpScope::Denied,
+        },
     };
 
-    match ip_addr {
-        IpAddr::V4(ip_v4) => verify_ip_v4_range(permitted_ip_range, ip_v4),
-        IpAddr::V6(ip_v6) => verify_ip_v6_range(permitted_ip_range, ip_v6),
+    if permitted {
+        Ok(())
+    } else {
+        Err(ClientError::IpRangeDenied(ip.to_string()))
     }
 }
 
+/// Maps errors of the `PermittedIpResolver` back to their `ClientError` counterpart.
+pub fn map_request_error(err: reqwest::Error) -> ClientError {
+    let mut source_opt = std::error::Error::source(&err);
+
+    while let Some(source) = source_opt {
+        if let Some(resolve_err) = source.downcast_ref::<ResolveError>() {
+            return ClientError::from(resolve_err.clone());
+        }
+
+        source_opt = source.source();
+    }
+
+    ClientError::Request(err)
+}
+
 fn parse_content_length(value: &reqwest::header::HeaderValue) -> Option<u64> {
     if let Ok(utf_8_str) = value.to_str() {
         if let Ok(content_size) = utf_8_str.parse::<u64>() {
@@ -375,24 +961,39 @@ fn parse_content_length(value: &reqwest::header::HeaderValue) -> Option<u64> {
 mod tests {
     use std::net::{Ipv4Addr, Ipv6Addr};
 
-    use crate::model::PermittedIpRange;
+    use crate::model::{PermittedIpRange, parse_ip_rules};
+
+    use futures_util::StreamExt;
 
-    use super::{verify_ip_v4_range, verify_ip_v6_range};
+    use super::{
+        ClientError, FetchResult, UpstreamDocument, UpstreamResult, verify_ip_v4_range,
+        verify_ip_v6_range, verify_url_expiry,
+    };
 
-    const IP_V4_DENIED_IP_LIST: [Ipv4Addr; 2] = [
+    const IP_V4_DENIED_IP_LIST: [Ipv4Addr; 10] = [
         Ipv4Addr::new(169, 254, 0, 0),
         Ipv4Addr::new(255, 255, 255, 255),
+        Ipv4Addr::new(192, 0, 0, 1),
+        Ipv4Addr::new(192, 0, 2, 1),
+        Ipv4Addr::new(198, 51, 100, 1),
+        Ipv4Addr::new(203, 0, 113, 1),
+        Ipv4Addr::new(192, 88, 99, 1),
+        Ipv4Addr::new(224, 0, 0, 1),
+        Ipv4Addr::new(239, 255, 255, 250),
+        Ipv4Addr::new(240, 0, 0, 1),
     ];
 
-    const IP_V4_GLOBAL_IP_LIST: [Ipv4Addr; 5] = [
+    const IP_V4_GLOBAL_IP_LIST: [Ipv4Addr; 7] = [
         Ipv4Addr::new(1, 1, 1, 1),
         Ipv4Addr::new(1, 0, 0, 1),
         Ipv4Addr::new(8, 8, 8, 8),
         Ipv4Addr::new(8, 8, 4, 4),
         Ipv4Addr::new(9, 9, 9, 9),
+        Ipv4Addr::new(192, 0, 0, 9),
+        Ipv4Addr::new(192, 175, 48, 1),
     ];
 
-    const IP_V4_PRIVATE_IP_LIST: [Ipv4Addr; 7] = [
+    const IP_V4_PRIVATE_IP_LIST: [Ipv4Addr; 11] = [
         Ipv4Addr::new(10, 0, 0, 1),
         Ipv4Addr::new(10, 1, 0, 1),
         Ipv4Addr::new(10, 10, 0, 1),
@@ -400,9 +1001,13 @@ mod tests {
         Ipv4Addr::new(172, 20, 0, 1),
         Ipv4Addr::new(192, 168, 0, 1),
         Ipv4Addr::new(192, 168, 1, 1),
+        Ipv4Addr::new(100, 64, 0, 1),
+        Ipv4Addr::new(100, 127, 255, 254),
+        Ipv4Addr::new(198, 18, 0, 1),
+        Ipv4Addr::new(198, 19, 255, 254),
     ];
 
-    const IP_V4_LOCAL_IP_LIST: [Ipv4Addr; 7] = [
+    const IP_V4_LOCAL_IP_LIST: [Ipv4Addr; 9] = [
         Ipv4Addr::new(127, 0, 0, 1),
         Ipv4Addr::new(127, 1, 0, 1),
         Ipv4Addr::new(127, 10, 0, 1),
@@ -410,113 +1015,347 @@ mod tests {
         Ipv4Addr::new(127, 20, 0, 1),
         Ipv4Addr::new(127, 168, 0, 1),
         Ipv4Addr::new(127, 200, 1, 1),
+        Ipv4Addr::new(0, 0, 0, 1),
+        Ipv4Addr::new(0, 10, 0, 1),
+    ];
+
+    const IP_V6_DENIED_IP_LIST: [Ipv6Addr; 9] = [
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 1),
+        // IPv4-mapped link-local
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0xa9fe, 1),
+        // NAT64 broadcast
+        Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0xffff, 0xffff),
+        // 6to4 documentation
+        Ipv6Addr::new(0x2002, 0xc000, 0x0201, 0, 0, 0, 0, 1),
+    ];
+
+    const IP_V6_GLOBAL_IP_LIST: [Ipv6Addr; 5] = [
+        Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111),
+        Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+        // IPv4-mapped / NAT64 / 6to4 addresses of "1.1.1.1"
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x0101, 0x0101),
+        Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0x0101, 0x0101),
+        Ipv6Addr::new(0x2002, 0x0101, 0x0101, 0, 0, 0, 0, 1),
+    ];
+
+    const IP_V6_PRIVATE_IP_LIST: [Ipv6Addr; 6] = [
+        Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0xfd12, 0x3456, 0x789a, 0, 0, 0, 0, 1),
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x0a00, 0x0001),
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x6440, 0x0001),
+        Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0xc0a8, 0x0001),
+        Ipv6Addr::new(0x2002, 0xac10, 0x0001, 0, 0, 0, 0, 1),
+    ];
+
+    const IP_V6_LOCAL_IP_LIST: [Ipv6Addr; 5] = [
+        Ipv6Addr::LOCALHOST,
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0x7f00, 0x0001),
+        Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0),
+        Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0x7f00, 0x0001),
+        Ipv6Addr::new(0x2002, 0x7f00, 0x0001, 0, 0, 0, 0, 1),
     ];
 
     #[test]
     fn ip_v4_range_none() {
-        assert!(verify_ip_v4_range(PermittedIpRange::None, Ipv4Addr::UNSPECIFIED).is_err());
+        assert!(verify_ip_v4_range(PermittedIpRange::None, &[], Ipv4Addr::UNSPECIFIED).is_err());
 
         for ip in IP_V4_DENIED_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::None, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::None, &[], ip).is_err());
         }
 
         for ip in IP_V4_GLOBAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::None, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::None, &[], ip).is_err());
         }
 
         for ip in IP_V4_PRIVATE_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::None, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::None, &[], ip).is_err());
         }
 
         for ip in IP_V4_LOCAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::None, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::None, &[], ip).is_err());
         }
     }
 
     #[test]
     fn ip_v4_range_global() {
-        assert!(verify_ip_v4_range(PermittedIpRange::Global, Ipv4Addr::UNSPECIFIED).is_err());
+        assert!(verify_ip_v4_range(PermittedIpRange::Global, &[], Ipv4Addr::UNSPECIFIED).is_err());
 
         for ip in IP_V4_DENIED_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Global, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Global, &[], ip).is_err());
         }
 
         for ip in IP_V4_GLOBAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Global, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Global, &[], ip).is_ok());
         }
 
         for ip in IP_V4_PRIVATE_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Global, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Global, &[], ip).is_err());
         }
 
         for ip in IP_V4_LOCAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Global, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Global, &[], ip).is_err());
         }
     }
 
     #[test]
     fn ip_v4_range_private() {
-        assert!(verify_ip_v4_range(PermittedIpRange::Private, Ipv4Addr::UNSPECIFIED).is_err());
+        assert!(verify_ip_v4_range(PermittedIpRange::Private, &[], Ipv4Addr::UNSPECIFIED).is_err());
 
         for ip in IP_V4_DENIED_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Private, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Private, &[], ip).is_err());
         }
 
         for ip in IP_V4_GLOBAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Private, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Private, &[], ip).is_ok());
         }
 
         for ip in IP_V4_PRIVATE_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Private, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Private, &[], ip).is_ok());
         }
 
         for ip in IP_V4_LOCAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Private, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Private, &[], ip).is_err());
         }
     }
 
     #[test]
     fn ip_v4_range_local() {
-        assert!(verify_ip_v4_range(PermittedIpRange::Local, Ipv4Addr::UNSPECIFIED).is_ok());
+        assert!(verify_ip_v4_range(PermittedIpRange::Local, &[], Ipv4Addr::UNSPECIFIED).is_ok());
 
         for ip in IP_V4_DENIED_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Local, ip).is_err());
+            assert!(verify_ip_v4_range(PermittedIpRange::Local, &[], ip).is_err());
         }
 
         for ip in IP_V4_GLOBAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Local, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Local, &[], ip).is_ok());
         }
 
         for ip in IP_V4_PRIVATE_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Local, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Local, &[], ip).is_ok());
         }
 
         for ip in IP_V4_LOCAL_IP_LIST {
-            assert!(verify_ip_v4_range(PermittedIpRange::Local, ip).is_ok());
+            assert!(verify_ip_v4_range(PermittedIpRange::Local, &[], ip).is_ok());
         }
     }
 
     #[test]
     fn ip_v6_range_none() {
-        assert!(verify_ip_v6_range(PermittedIpRange::None, Ipv6Addr::UNSPECIFIED).is_err());
-        assert!(verify_ip_v6_range(PermittedIpRange::None, Ipv6Addr::LOCALHOST).is_err());
+        assert!(verify_ip_v6_range(PermittedIpRange::None, &[], Ipv6Addr::UNSPECIFIED).is_err());
+
+        for ip in IP_V6_DENIED_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::None, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_GLOBAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::None, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_PRIVATE_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::None, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_LOCAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::None, &[], ip).is_err());
+        }
     }
 
     #[test]
     fn ip_v6_range_global() {
-        assert!(verify_ip_v6_range(PermittedIpRange::Global, Ipv6Addr::UNSPECIFIED).is_err());
-        assert!(verify_ip_v6_range(PermittedIpRange::Global, Ipv6Addr::LOCALHOST).is_err());
+        assert!(verify_ip_v6_range(PermittedIpRange::Global, &[], Ipv6Addr::UNSPECIFIED).is_err());
+
+        for ip in IP_V6_DENIED_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Global, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_GLOBAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Global, &[], ip).is_ok());
+        }
+
+        for ip in IP_V6_PRIVATE_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Global, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_LOCAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Global, &[], ip).is_err());
+        }
     }
 
     #[test]
     fn ip_v6_range_private() {
-        assert!(verify_ip_v6_range(PermittedIpRange::Private, Ipv6Addr::UNSPECIFIED).is_err());
-        assert!(verify_ip_v6_range(PermittedIpRange::Private, Ipv6Addr::LOCALHOST).is_err());
+        assert!(verify_ip_v6_range(PermittedIpRange::Private, &[], Ipv6Addr::UNSPECIFIED).is_err());
+
+        for ip in IP_V6_DENIED_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Private, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_GLOBAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Private, &[], ip).is_ok());
+        }
+
+        for ip in IP_V6_PRIVATE_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Private, &[], ip).is_ok());
+        }
+
+        for ip in IP_V6_LOCAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Private, &[], ip).is_err());
+        }
     }
 
     #[test]
     fn ip_v6_range_local() {
-        assert!(verify_ip_v6_range(PermittedIpRange::Local, Ipv6Addr::UNSPECIFIED).is_ok());
-        assert!(verify_ip_v6_range(PermittedIpRange::Local, Ipv6Addr::LOCALHOST).is_ok());
+        assert!(verify_ip_v6_range(PermittedIpRange::Local, &[], Ipv6Addr::UNSPECIFIED).is_ok());
+
+        for ip in IP_V6_DENIED_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Local, &[], ip).is_err());
+        }
+
+        for ip in IP_V6_GLOBAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Local, &[], ip).is_ok());
+        }
+
+        for ip in IP_V6_PRIVATE_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Local, &[], ip).is_ok());
+        }
+
+        for ip in IP_V6_LOCAL_IP_LIST {
+            assert!(verify_ip_v6_range(PermittedIpRange::Local, &[], ip).is_ok());
+        }
+    }
+
+    #[test]
+    fn ip_rules_in_order() {
+        let ip_rules = parse_ip_rules(
+            "deny 10.20.30.0/24\nallow 10.20.0.0/16\ndeny 1.1.1.1\nallow fd00:20::/32",
+        )
+        .unwrap();
+
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(10, 20, 0, 1)
+            )
+            .is_ok()
+        );
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(10, 20, 30, 1)
+            )
+            .is_err()
+        );
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(10, 21, 0, 1)
+            )
+            .is_err()
+        );
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(1, 1, 1, 1)
+            )
+            .is_err()
+        );
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(1, 0, 0, 1)
+            )
+            .is_ok()
+        );
+        assert!(
+            verify_ip_v6_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv6Addr::new(0xfd00, 0x20, 0, 0, 0, 0, 0, 1)
+            )
+            .is_ok()
+        );
+        assert!(
+            verify_ip_v6_range(
+                PermittedIpRange::Global,
+                &ip_rules,
+                Ipv4Addr::new(10, 20, 0, 1).to_ipv6_mapped()
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn ip_rules_instead_of_range() {
+        let ip_rules = parse_ip_rules("allow 10.20.0.0/16").unwrap();
+
+        assert!(
+            verify_ip_v4_range(
+                PermittedIpRange::None,
+                &ip_rules,
+                Ipv4Addr::new(10, 20, 0, 1)
+            )
+            .is_ok()
+        );
+
+        for ip in IP_V4_GLOBAL_IP_LIST {
+            assert!(verify_ip_v4_range(PermittedIpRange::None, &ip_rules, ip).is_err());
+        }
+    }
+
+    #[test]
+    fn url_expiry() {
+        assert!(verify_url_expiry(Some(1_000), false, 999).is_ok());
+        assert!(verify_url_expiry(Some(1_000), true, 1_000).is_ok());
+        assert!(verify_url_expiry(None, false, 1_000).is_ok());
+        assert!(matches!(
+            verify_url_expiry(Some(1_000), false, 1_001),
+            Err(ClientError::UrlExpired(1_000))
+        ));
+        assert!(matches!(
+            verify_url_expiry(None, true, 1_000),
+            Err(ClientError::UrlWithoutExpiry)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn rewrite_document_per_request() {
+        let shared_state = std::sync::Arc::new(crate::utilities::SharedState::default());
+        let mut style_nonces = Vec::new();
+
+        for _ in 0..2 {
+            let upstream_result = UpstreamResult::Document(UpstreamDocument {
+                base_url: url::Url::parse("https://example.com/").unwrap(),
+                body: Box::pin(futures_util::stream::iter([Ok(bytes::Bytes::from_static(
+                    b"<style>p{}</style>",
+                ))])),
+                content_type: mime::TEXT_HTML,
+            });
+            let Ok(FetchResult::Response(client_response)) =
+                upstream_result.into_fetch_result(&shared_state).await
+            else {
+                panic!("expected a response");
+            };
+            let style_nonce = client_response.style_nonce.clone().unwrap();
+            let body = client_response
+                .body
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await
+                .concat();
+
+            assert!(String::from_utf8(body).unwrap().contains(&*style_nonce));
+            style_nonces.push(style_nonce);
+        }
+
+        assert_ne!(style_nonces[0], style_nonces[1]);
     }
 }
@@ -0,0 +1,130 @@
+use hmac::Mac;
+
+use crate::utilities::shared::HmacInstance;
+
+/**
+ * Primary key which is used to sign (and verify) URLs, followed by previous keys which are only used for verification.
+ * This allows rotating the secret without breaking links which were signed with a previous one.
+ **/
+#[derive(Clone, Debug)]
+pub struct HmacKeys {
+    primary: HmacInstance,
+    verify_only: Vec<HmacInstance>,
+}
+
+impl HmacKeys {
+    pub fn new<'secret>(
+        primary_secret: &[u8],
+        verify_only_secrets: impl IntoIterator<Item = &'secret [u8]>,
+    ) -> Result<Self, hmac::digest::InvalidLength> {
+        Ok(Self {
+            primary: <HmacInstance as Mac>::new_from_slice(primary_secret)?,
+            verify_only: verify_only_secrets
+                .into_iter()
+                .map(<HmacInstance as Mac>::new_from_slice)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Signs the given message with the primary key.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let mut hmac = self.primary.clone();
+
+        hmac.update(message);
+        hmac.finalize().into_bytes().to_vec()
+    }
+
+    /// Signs the given URL and its expiry (if any) with the primary key.
+    pub fn sign_url(&self, url: &str, expires_opt: Option<u64>) -> Vec<u8> {
+        self.sign(&get_url_message(url, expires_opt))
+    }
+
+    /// Returns `true` if the tag matches the URL and its expiry (if any) for any of the keys.
+    pub fn verify_url(&self, url: &str, expires_opt: Option<u64>, tag: &[u8]) -> bool {
+        self.verify(&get_url_message(url, expires_opt), tag)
+    }
+
+    /// Returns the index of the key which the tag matches for the URL and its expiry (if any).
+    pub fn find_url_key(&self, url: &str, expires_opt: Option<u64>, tag: &[u8]) -> Option<usize> {
+        self.find_key(&get_url_message(url, expires_opt), tag)
+    }
+
+    /// Returns `true` if the tag matches the message for any of the keys.
+    pub fn verify(&self, message: &[u8], tag: &[u8]) -> bool {
+        match self.find_key(message, tag) {
+            Some(0) => true,
+            Some(index) => {
+                log::debug!("hash verified by verify-only key #{index}");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the index of the key which the tag matches, `0` is the primary key.
+    pub fn find_key(&self, message: &[u8], tag: &[u8]) -> Option<usize> {
+        std::iter::once(&self.primary)
+            .chain(self.verify_only.iter())
+            .position(|instance| {
+                let mut hmac = instance.clone();
+
+                hmac.update(message);
+                hmac.verify_slice(tag).is_ok()
+            })
+    }
+}
+
+/**
+ * URLs without an expiry are signed as they are (compatible with SearXNG).
+ * Otherwise the expiry is prefixed as "<unix timestamp>:", which can't be confused with a URL,
+ * since a URL scheme has to start with a letter.
+ **/
+pub fn get_url_message(url: &str, expires_opt: Option<u64>) -> std::borrow::Cow<'_, [u8]> {
+    match expires_opt {
+        Some(expires) => std::borrow::Cow::Owned(format!("{expires}:{url}").into_bytes()),
+        None => std::borrow::Cow::Borrowed(url.as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HmacKeys;
+
+    #[test]
+    fn sign_with_primary_key() {
+        let keys = HmacKeys::new(b"primary", [b"previous".as_slice()]).unwrap();
+        let primary_only = HmacKeys::new(b"primary", []).unwrap();
+
+        assert_eq!(keys.sign(b"message"), primary_only.sign(b"message"));
+    }
+
+    #[test]
+    fn verify_with_any_key() {
+        let keys =
+            HmacKeys::new(b"primary", [b"previous".as_slice(), b"older".as_slice()]).unwrap();
+        let previous_tag = HmacKeys::new(b"previous", []).unwrap().sign(b"message");
+        let older_tag = HmacKeys::new(b"older", []).unwrap().sign(b"message");
+        let unknown_tag = HmacKeys::new(b"unknown", []).unwrap().sign(b"message");
+
+        assert!(keys.verify(b"message", &keys.sign(b"message")));
+        assert!(keys.verify(b"message", &previous_tag));
+        assert!(keys.verify(b"message", &older_tag));
+        assert!(!keys.verify(b"message", &unknown_tag));
+        assert!(!keys.verify(b"other message", &previous_tag));
+        assert_eq!(keys.find_key(b"message", &keys.sign(b"message")), Some(0));
+        assert_eq!(keys.find_key(b"message", &older_tag), Some(2));
+        assert_eq!(keys.find_key(b"message", &unknown_tag), None);
+    }
+
+    #[test]
+    fn url_expiry_is_signed() {
+        let keys = HmacKeys::new(b"primary", []).unwrap();
+        let url = "https://example.com/";
+        let tag = keys.sign_url(url, Some(1_700_000_000));
+
+        assert_eq!(keys.sign_url(url, None), keys.sign(url.as_bytes()));
+        assert!(keys.verify_url(url, Some(1_700_000_000), &tag));
+        assert!(!keys.verify_url(url, Some(1_800_000_000), &tag));
+        assert!(!keys.verify_url(url, None, &tag));
+    }
+}
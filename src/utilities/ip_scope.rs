@@ -0,0 +1,167 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IpScope {
+    /// Globally reachable unicast addresses.
+    Global,
+    /// Addresses that are only reachable within a private network (e.g. RFC 1918, ULA, CGNAT).
+    Private,
+    /// Addresses of the host itself (loopback / unspecified).
+    Local,
+    /// Addresses which must never be connected to (link-local, multicast, documentation, reserved, ...).
+    Denied,
+}
+
+/// Entries of the IANA IPv4 special-purpose address registry, more specific prefixes come first.
+/// See: https://www.iana.org/assignments/iana-ipv4-special-registry/iana-ipv4-special-registry.xhtml
+const IP_V4_SPECIAL_PURPOSE: [(Ipv4Addr, u8, IpScope); 22] = [
+    (Ipv4Addr::new(0, 0, 0, 0), 32, IpScope::Local),
+    (Ipv4Addr::new(0, 0, 0, 0), 8, IpScope::Local),
+    (Ipv4Addr::new(10, 0, 0, 0), 8, IpScope::Private),
+    (Ipv4Addr::new(100, 64, 0, 0), 10, IpScope::Private),
+    (Ipv4Addr::new(127, 0, 0, 0), 8, IpScope::Local),
+    (Ipv4Addr::new(169, 254, 0, 0), 16, IpScope::Denied),
+    (Ipv4Addr::new(172, 16, 0, 0), 12, IpScope::Private),
+    (Ipv4Addr::new(192, 0, 0, 9), 32, IpScope::Global),
+    (Ipv4Addr::new(192, 0, 0, 10), 32, IpScope::Global),
+    (Ipv4Addr::new(192, 0, 0, 0), 24, IpScope::Denied),
+    (Ipv4Addr::new(192, 0, 2, 0), 24, IpScope::Denied),
+    (Ipv4Addr::new(192, 31, 196, 0), 24, IpScope::Global),
+    (Ipv4Addr::new(192, 52, 193, 0), 24, IpScope::Global),
+    (Ipv4Addr::new(192, 88, 99, 0), 24, IpScope::Denied),
+    (Ipv4Addr::new(192, 168, 0, 0), 16, IpScope::Private),
+    (Ipv4Addr::new(192, 175, 48, 0), 24, IpScope::Global),
+    (Ipv4Addr::new(198, 18, 0, 0), 15, IpScope::Private),
+    (Ipv4Addr::new(198, 51, 100, 0), 24, IpScope::Denied),
+    (Ipv4Addr::new(203, 0, 113, 0), 24, IpScope::Denied),
+    (Ipv4Addr::new(224, 0, 0, 0), 4, IpScope::Denied),
+    (Ipv4Addr::new(255, 255, 255, 255), 32, IpScope::Denied),
+    (Ipv4Addr::new(240, 0, 0, 0), 4, IpScope::Denied),
+];
+
+/// Entries of the IANA IPv6 special-purpose address registry, more specific prefixes come first.
+/// Embedded IPv4 addresses (IPv4-mapped, NAT64, 6to4) are handled by `classify_ip_v6`.
+/// See: https://www.iana.org/assignments/iana-ipv6-special-registry/iana-ipv6-special-registry.xhtml
+const IP_V6_SPECIAL_PURPOSE: [(Ipv6Addr, u8, IpScope); 16] = [
+    (Ipv6Addr::UNSPECIFIED, 128, IpScope::Local),
+    (Ipv6Addr::LOCALHOST, 128, IpScope::Local),
+    // deprecated IPv4-compatible addresses
+    (Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 96, IpScope::Denied),
+    (
+        Ipv6Addr::new(0x64, 0xff9b, 1, 0, 0, 0, 0, 0),
+        48,
+        IpScope::Private,
+    ),
+    (
+        Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 0),
+        64,
+        IpScope::Denied,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0x3, 0, 0, 0, 0, 0, 0),
+        32,
+        IpScope::Global,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0x4, 0x112, 0, 0, 0, 0, 0),
+        48,
+        IpScope::Global,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0x20, 0, 0, 0, 0, 0, 0),
+        28,
+        IpScope::Global,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0, 0, 0, 0, 0, 0, 0),
+        23,
+        IpScope::Denied,
+    ),
+    (
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0),
+        32,
+        IpScope::Denied,
+    ),
+    (
+        Ipv6Addr::new(0x3fff, 0, 0, 0, 0, 0, 0, 0),
+        20,
+        IpScope::Denied,
+    ),
+    (
+        Ipv6Addr::new(0x5f00, 0, 0, 0, 0, 0, 0, 0),
+        16,
+        IpScope::Denied,
+    ),
+    (
+        Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0),
+        7,
+        IpScope::Private,
+    ),
+    (
+        Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0),
+        10,
+        IpScope::Denied,
+    ),
+    // deprecated site-local addresses
+    (
+        Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0),
+        10,
+        IpScope::Private,
+    ),
+    (
+        Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0),
+        8,
+        IpScope::Denied,
+    ),
+];
+
+const IP_V6_NAT64_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+const IP_V6_6TO4_PREFIX: Ipv6Addr = Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0);
+/// Global unicast addresses, anything else is either listed above or unallocated.
+const IP_V6_GLOBAL_UNICAST_PREFIX: Ipv6Addr = Ipv6Addr::new(0x2000, 0, 0, 0, 0, 0, 0, 0);
+
+pub fn classify_ip_v4(ip: Ipv4Addr) -> IpScope {
+    IP_V4_SPECIAL_PURPOSE
+        .iter()
+        .find(|(network, prefix_len, _)| ip_v4_in_network(ip, *network, *prefix_len))
+        .map_or(IpScope::Global, |(_, _, scope)| *scope)
+}
+
+pub fn classify_ip_v6(ip: Ipv6Addr) -> IpScope {
+    if let Some(ip_v4) = ip.to_ipv4_mapped() {
+        return classify_ip_v4(ip_v4);
+    }
+
+    if ip_v6_in_network(ip, IP_V6_NAT64_PREFIX, 96) {
+        return classify_ip_v4(Ipv4Addr::from_bits(ip.to_bits() as u32));
+    }
+
+    if ip_v6_in_network(ip, IP_V6_6TO4_PREFIX, 16) {
+        return classify_ip_v4(Ipv4Addr::from_bits((ip.to_bits() >> 80) as u32));
+    }
+
+    if let Some((_, _, scope)) = IP_V6_SPECIAL_PURPOSE
+        .iter()
+        .find(|(network, prefix_len, _)| ip_v6_in_network(ip, *network, *prefix_len))
+    {
+        return *scope;
+    }
+
+    if ip_v6_in_network(ip, IP_V6_GLOBAL_UNICAST_PREFIX, 3) {
+        IpScope::Global
+    } else {
+        IpScope::Denied
+    }
+}
+
+fn ip_v4_in_network(ip: Ipv4Addr, network: Ipv4Addr, prefix_len: u8) -> bool {
+    let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+
+    ip.to_bits() & mask == network.to_bits() & mask
+}
+
+fn ip_v6_in_network(ip: Ipv6Addr, network: Ipv6Addr, prefix_len: u8) -> bool {
+    let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+
+    ip.to_bits() & mask == network.to_bits() & mask
+}
@@ -0,0 +1,506 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, Write},
+    sync::{Mutex, PoisonError, mpsc},
+};
+
+/// Number of HMAC bytes which are used as link id (22 characters once Base64 encoded).
+pub const LINK_ID_LENGTH: usize = 16;
+
+/// The persisted file will be compacted once it contains this many lines per stored link.
+const COMPACTION_FACTOR: usize = 4;
+/// Minimum number of persisted lines before the file will be compacted at runtime.
+const COMPACTION_MIN_LINES: usize = 1024;
+
+/**
+ * Maps short link ids to (long) target URLs, which are evicted once their TTL has passed (or the store is full).
+ * Links can be persisted to a file, which is appended to on every change and compacted on startup, as well as
+ * once it contains mostly outdated lines. The file is written by a separate thread, so requests don't wait for it.
+ **/
+pub struct LinkStore {
+    /// Maximum number of links, the links closest to their deadline are evicted first.
+    max_entries: usize,
+    /// Time to live in seconds.
+    ttl: u64,
+    state: Mutex<LinkState>,
+    writer_thread: Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+struct LinkEntry {
+    deadline: u64,
+    expires: Option<u64>,
+    url: String,
+}
+
+#[derive(Default)]
+struct LinkState {
+    /// Link ids in order of their deadline, refreshed links are contained multiple times.
+    deadlines: VecDeque<(u64, String)>,
+    entries: HashMap<String, LinkEntry>,
+    /// Number of lines in the persisted file, including outdated ones.
+    file_lines: usize,
+    writer: Option<mpsc::Sender<LinkWrite>>,
+}
+
+enum LinkWrite {
+    Append(String),
+    /// Replaces the file with the given lines.
+    Compact(String),
+}
+
+impl LinkStore {
+    /// Creates a link store, which loads (and persists to) the given file (if any).
+    pub fn new(
+        ttl: u64,
+        max_entries: usize,
+        file_path_opt: Option<&std::path::Path>,
+    ) -> std::io::Result<Self> {
+        let mut state = LinkState::default();
+        let mut writer_thread = None;
+
+        if let Some(file_path) = file_path_opt {
+            let file = state.load(
+                file_path,
+                max_entries,
+                crate::utilities::get_unix_timestamp(),
+            )?;
+            let (sender, receiver) = mpsc::channel();
+            let file_path = file_path.to_owned();
+
+            state.writer = Some(sender);
+            writer_thread = Some(
+                std::thread::Builder::new()
+                    .name(String::from("link-store"))
+                    .spawn(move || write_links(&file_path, file, &receiver))?,
+            );
+        }
+
+        Ok(Self {
+            max_entries,
+            ttl,
+            state: Mutex::new(state),
+            writer_thread: Mutex::new(writer_thread),
+        })
+    }
+
+    /**
+     * Waits until every queued line was written to the file and stops the writer thread, later changes aren't persisted.
+     * Has to be called explicitly for the global link store, since statics are never dropped.
+     **/
+    pub fn shutdown(&self) {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .writer
+            .take();
+
+        if let Some(writer_thread) = self
+            .writer_thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+            && writer_thread.join().is_err()
+        {
+            log::error!("link store writer thread panicked");
+        }
+    }
+
+    /// Stores the URL (and its expiry) for the given id, or refreshes the TTL and expiry of an existing link.
+    pub fn insert(&self, id: &str, url: &str, expires_opt: Option<u64>) {
+        self.insert_at(id, url, expires_opt, crate::utilities::get_unix_timestamp());
+    }
+
+    /// Returns the URL and its expiry (if any) of a link which hasn't been evicted yet.
+    pub fn get(&self, id: &str) -> Option<(String, Option<u64>)> {
+        self.get_at(id, crate::utilities::get_unix_timestamp())
+    }
+
+    fn insert_at(&self, id: &str, url: &str, expires_opt: Option<u64>, now: u64) {
+        let deadline = now.saturating_add(self.ttl);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        state.remove_expired(now);
+
+        match state.entries.get_mut(id) {
+            // refreshing the link on every rewrite would flood the persisted file
+            Some(entry)
+                if !is_stale(entry.deadline, deadline, now)
+                    && !is_expiry_stale(entry.expires, expires_opt, now) =>
+            {
+                return;
+            }
+            Some(entry) => {
+                entry.deadline = deadline;
+                entry.expires = expires_opt;
+            }
+            None => {
+                while state.entries.len() >= self.max_entries.max(1) && state.evict_oldest() {}
+
+                state.entries.insert(
+                    String::from(id),
+                    LinkEntry {
+                        deadline,
+                        expires: expires_opt,
+                        url: String::from(url),
+                    },
+                );
+            }
+        }
+
+        state.deadlines.push_back((deadline, String::from(id)));
+        state.append(id);
+    }
+
+    fn get_at(&self, id: &str, now: u64) -> Option<(String, Option<u64>)> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        state
+            .entries
+            .get(id)
+            .filter(|entry| entry.deadline > now)
+            .map(|entry| (entry.url.clone(), entry.expires))
+    }
+}
+
+impl Drop for LinkStore {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl LinkState {
+    fn remove_expired(&mut self, now: u64) {
+        while let Some((deadline, _)) = self.deadlines.front()
+            && *deadline <= now
+        {
+            if let Some((deadline, id)) = self.deadlines.pop_front()
+                && self
+                    .entries
+                    .get(&id)
+                    .is_some_and(|entry| entry.deadline == deadline)
+            {
+                self.entries.remove(&id);
+            }
+        }
+    }
+
+    /// Removes the link which is closest to its deadline, returns `false` if there are no links left.
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((deadline, id)) = self.deadlines.pop_front() {
+            if self
+                .entries
+                .get(&id)
+                .is_some_and(|entry| entry.deadline == deadline)
+            {
+                self.entries.remove(&id);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /**
+     * Loads the newest links which haven't expired yet and replaces the file with the compacted links.
+     * Returns the file, opened for appending.
+     **/
+    fn load(
+        &mut self,
+        file_path: &std::path::Path,
+        max_entries: usize,
+        now: u64,
+    ) -> std::io::Result<std::fs::File> {
+        match std::fs::File::open(file_path) {
+            Ok(file) => {
+                for line in std::io::BufReader::new(file).lines() {
+                    if let Some((id, entry)) = parse_link_line(&line?)
+                        && entry.deadline > now
+                    {
+                        // later lines are refreshed links
+                        self.entries.insert(id, entry);
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut deadlines: Vec<_> = self
+            .entries
+            .iter()
+            .map(|(id, entry)| (entry.deadline, id.clone()))
+            .collect();
+
+        deadlines.sort_unstable();
+        self.deadlines = deadlines.into();
+
+        while self.entries.len() > max_entries.max(1) && self.evict_oldest() {}
+
+        self.file_lines = self.entries.len();
+
+        write_compacted_file(file_path, &self.format_lines())
+    }
+
+    fn format_lines(&self) -> String {
+        self.entries
+            .iter()
+            .map(|(id, entry)| format_link_line(id, entry))
+            .collect()
+    }
+
+    /// Queues the link to be appended to the file, or the whole file to be compacted.
+    fn append(&mut self, id: &str) {
+        let Some(writer) = self.writer.as_ref() else {
+            return;
+        };
+
+        self.file_lines += 1;
+
+        let link_write =
+            if self.file_lines > COMPACTION_MIN_LINES.max(self.entries.len() * COMPACTION_FACTOR) {
+                self.file_lines = self.entries.len();
+                LinkWrite::Compact(self.format_lines())
+            } else if let Some(entry) = self.entries.get(id) {
+                LinkWrite::Append(format_link_line(id, entry))
+            } else {
+                return;
+            };
+
+        if writer.send(link_write).is_err() {
+            log::warn!("failed to persist link '{id}': writer thread stopped");
+        }
+    }
+}
+
+/// Whether less than half of the lifetime is left until the deadline (or expiry), compared to a refreshed one.
+fn is_stale(current: u64, refreshed: u64, now: u64) -> bool {
+    current.saturating_sub(now) <= refreshed.saturating_sub(now) / 2
+}
+
+fn is_expiry_stale(current_opt: Option<u64>, refreshed_opt: Option<u64>, now: u64) -> bool {
+    match (current_opt, refreshed_opt) {
+        (Some(current), Some(refreshed)) => is_stale(current, refreshed, now),
+        (current_opt, refreshed_opt) => current_opt != refreshed_opt,
+    }
+}
+
+/// Writes the queued lines in batches, until the link store was dropped.
+fn write_links(
+    file_path: &std::path::Path,
+    file: std::fs::File,
+    receiver: &mpsc::Receiver<LinkWrite>,
+) {
+    let mut file = std::io::BufWriter::new(file);
+
+    while let Ok(link_write) = receiver.recv() {
+        for link_write in std::iter::once(link_write).chain(receiver.try_iter()) {
+            let result = match link_write {
+                LinkWrite::Append(line) => file.write_all(line.as_bytes()),
+                LinkWrite::Compact(lines) => write_compacted_file(file_path, &lines)
+                    .map(|compacted_file| file = std::io::BufWriter::new(compacted_file)),
+            };
+
+            if let Err(err) = result {
+                log::warn!("failed to persist links: {err:?}");
+            }
+        }
+
+        if let Err(err) = file.flush() {
+            log::warn!("failed to persist links: {err:?}");
+        }
+    }
+}
+
+/// Atomically replaces the file with the given lines and opens it for appending.
+fn write_compacted_file(
+    file_path: &std::path::Path,
+    lines: &str,
+) -> std::io::Result<std::fs::File> {
+    let temp_file_path = file_path.with_extension("tmp");
+    let mut temp_file = get_open_options()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_file_path)?;
+
+    temp_file.write_all(lines.as_bytes())?;
+    temp_file.sync_all()?;
+    std::fs::rename(&temp_file_path, file_path)?;
+
+    get_open_options().append(true).open(file_path)
+}
+
+/// The file contains the target URLs of every link, so it's only accessible by the owner.
+fn get_open_options() -> std::fs::OpenOptions {
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut open_options = std::fs::OpenOptions::new();
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+
+    open_options
+}
+
+/// Serialized URLs can't contain whitespace, so the URL can simply be the last field.
+fn format_link_line(id: &str, entry: &LinkEntry) -> String {
+    match entry.expires {
+        Some(expires) => format!("{} {expires} {id} {}\n", entry.deadline, entry.url),
+        None => format!("{} - {id} {}\n", entry.deadline, entry.url),
+    }
+}
+
+fn parse_link_line(line: &str) -> Option<(String, LinkEntry)> {
+    let mut fields = line.splitn(4, ' ');
+    let deadline = fields.next()?.parse().ok()?;
+    let expires = match fields.next()? {
+        "-" => None,
+        expires => Some(expires.parse().ok()?),
+    };
+    let id = fields.next()?;
+    let url = fields.next()?;
+
+    Some((
+        String::from(id),
+        LinkEntry {
+            deadline,
+            expires,
+            url: String::from(url),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{COMPACTION_MIN_LINES, LinkStore};
+
+    #[test]
+    fn evict_after_ttl() {
+        let store = LinkStore::new(60, 100, None).unwrap();
+
+        store.insert_at("a", "https://example.com/a", None, 1_000);
+        store.insert_at("b", "https://example.com/b", Some(2_000), 1_030);
+
+        assert_eq!(
+            store.get_at("a", 1_059),
+            Some((String::from("https://example.com/a"), None))
+        );
+        assert_eq!(store.get_at("a", 1_060), None);
+        assert_eq!(
+            store.get_at("b", 1_060),
+            Some((String::from("https://example.com/b"), Some(2_000)))
+        );
+
+        store.insert_at("c", "https://example.com/c", None, 1_090);
+
+        assert_eq!(store.get_at("b", 1_090), None);
+        assert_eq!(store.state.lock().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn refresh_after_half_ttl() {
+        let store = LinkStore::new(60, 100, None).unwrap();
+
+        store.insert_at("a", "https://example.com/a", None, 1_000);
+        // still more than half of the TTL left, the deadline is kept
+        store.insert_at("a", "https://example.com/a", None, 1_020);
+
+        assert_eq!(store.get_at("a", 1_060), None);
+
+        store.insert_at("a", "https://example.com/a", None, 1_040);
+        // the outdated deadline doesn't evict the refreshed link
+        store.insert_at("b", "https://example.com/b", None, 1_070);
+
+        assert!(store.get_at("a", 1_099).is_some());
+        assert_eq!(store.state.lock().unwrap().deadlines.len(), 2);
+    }
+
+    #[test]
+    fn refresh_expiry() {
+        let store = LinkStore::new(600, 100, None).unwrap();
+
+        store.insert_at("a", "https://example.com/a", Some(1_060), 1_000);
+        // more than half of the URL lifetime left
+        store.insert_at("a", "https://example.com/a", Some(1_080), 1_020);
+
+        assert_eq!(store.get_at("a", 1_020).unwrap().1, Some(1_060));
+
+        store.insert_at("a", "https://example.com/a", Some(1_100), 1_040);
+
+        assert_eq!(store.get_at("a", 1_040).unwrap().1, Some(1_100));
+    }
+
+    #[test]
+    fn evict_oldest_when_full() {
+        let store = LinkStore::new(60, 2, None).unwrap();
+
+        store.insert_at("a", "https://example.com/a", None, 1_000);
+        store.insert_at("b", "https://example.com/b", None, 1_010);
+        store.insert_at("c", "https://example.com/c", None, 1_020);
+
+        assert_eq!(store.get_at("a", 1_020), None);
+        assert!(store.get_at("b", 1_020).is_some());
+        assert!(store.get_at("c", 1_020).is_some());
+    }
+
+    #[test]
+    fn compact_at_runtime() {
+        let file_path = std::env::temp_dir().join(format!(
+            "searproxy-links-compact-{}.txt",
+            std::process::id()
+        ));
+        let now = crate::utilities::get_unix_timestamp();
+        let store = LinkStore::new(60, 100, Some(&file_path)).unwrap();
+
+        // every insert refreshes the link, since it was inserted at least half of the TTL ago
+        for index in 0..(COMPACTION_MIN_LINES as u64 + 10) {
+            store.insert_at("a", "https://example.com/a", None, now + index * 30);
+        }
+
+        drop(store);
+
+        let line_count = std::fs::read_to_string(&file_path).unwrap().lines().count();
+
+        assert!(line_count < COMPACTION_MIN_LINES, "{line_count} lines");
+
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(
+                &std::fs::metadata(&file_path).unwrap().permissions()
+            ) & 0o777,
+            0o600
+        );
+
+        std::fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn persist_links() {
+        let file_path =
+            std::env::temp_dir().join(format!("searproxy-links-{}.txt", std::process::id()));
+        let now = crate::utilities::get_unix_timestamp();
+        let store = LinkStore::new(60, 100, Some(&file_path)).unwrap();
+
+        store.insert("a", "https://example.com/a?b=c", Some(now + 10));
+        store.insert_at("b", "https://example.com/b", None, now - 60);
+        // the global link store is never dropped
+        store.shutdown();
+        store.insert("c", "https://example.com/c", None);
+        drop(store);
+
+        let store = LinkStore::new(60, 100, Some(&file_path)).unwrap();
+
+        assert_eq!(
+            store.get("a"),
+            Some((String::from("https://example.com/a?b=c"), Some(now + 10)))
+        );
+        assert_eq!(store.get("b"), None);
+        // inserted after the shutdown
+        assert_eq!(store.get("c"), None);
+        assert_eq!(
+            std::fs::read_to_string(&file_path).unwrap().lines().count(),
+            1
+        );
+
+        std::fs::remove_file(file_path).unwrap();
+    }
+}
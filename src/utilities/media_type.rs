@@ -0,0 +1,203 @@
+/// Amount of bytes to look at while sniffing (as defined by the MIME sniffing spec).
+pub const SNIFF_LENGTH: usize = 1445;
+
+const SNIFF_WHITESPACE_BYTES: [u8; 5] = [0x09, 0x0A, 0x0C, 0x0D, 0x20];
+const SNIFF_TAG_TERMINATING_BYTES: [u8; 2] = [0x20, 0x3E];
+/// Patterns of the "rules for identifying an unknown MIME type", which require a sanitized rendering.
+/// See: https://mimesniff.spec.whatwg.org/#rules-for-identifying-an-unknown-mime-type
+const SNIFF_SCRIPTABLE_PATTERNS: [&[u8]; 18] = [
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<script",
+    b"<iframe",
+    b"<h1",
+    b"<div",
+    b"<font",
+    b"<table",
+    b"<a",
+    b"<style",
+    b"<title",
+    b"<b",
+    b"<body",
+    b"<br",
+    b"<p",
+    b"<!--",
+    b"<?xml",
+];
+/// `Sec-Fetch-Dest` values of requests which will be rendered as a document by the browser.
+const NAVIGABLE_FETCH_DESTINATIONS: [&str; 5] = ["document", "embed", "frame", "iframe", "object"];
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum MediaType {
+    Html,
+    Css,
+    /// Content which will be forwarded as is, with the given media type.
+    Passthrough(mime::Mime),
+}
+
+/// Parses a `Content-Type` header value, while tolerating whitespace around parameter separators.
+pub fn parse_content_type(value: &reqwest::header::HeaderValue) -> Option<mime::Mime> {
+    let value = value.to_str().ok()?;
+
+    value.parse().ok().or_else(|| {
+        value
+            .split(';')
+            .map(str::trim)
+            .collect::<Vec<&str>>()
+            .join("; ")
+            .parse()
+            .ok()
+    })
+}
+
+/// Returns `true` if the given request headers indicate that the response will be rendered as a document.
+pub fn is_navigable_request(headers: &actix_web::http::header::HeaderMap) -> bool {
+    match headers
+        .get("sec-fetch-dest")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(destination) => {
+            NAVIGABLE_FETCH_DESTINATIONS.contains(&destination.trim().to_ascii_lowercase().as_str())
+        }
+        // browsers without "Fetch Metadata" support
+        None => true,
+    }
+}
+
+/// Returns `true` if the given content type doesn't carry enough information and the body has to be sniffed.
+pub fn requires_sniffing(content_type_opt: Option<&mime::Mime>, navigable: bool) -> bool {
+    navigable
+        && content_type_opt.is_none_or(|content_type| {
+            content_type.essence_str() == mime::APPLICATION_OCTET_STREAM.essence_str()
+        })
+}
+
+/**
+ * Classifies a response by its media type essence (type / subtype).
+ * Content without a usable media type will be sniffed (see `requires_sniffing`),
+ * if that isn't possible or doesn't yield a result, it will be forwarded as "application/octet-stream".
+ **/
+pub fn classify_media_type(
+    content_type_opt: Option<&mime::Mime>,
+    navigable: bool,
+    prefix: &[u8],
+) -> MediaType {
+    if requires_sniffing(content_type_opt, navigable) {
+        return if is_scriptable(prefix) {
+            MediaType::Html
+        } else {
+            MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM)
+        };
+    }
+
+    match content_type_opt {
+        Some(content_type) => match content_type.essence_str() {
+            "text/html" | "application/xhtml+xml" => MediaType::Html,
+            "text/css" => MediaType::Css,
+            _ => MediaType::Passthrough(content_type.clone()),
+        },
+        None => MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM),
+    }
+}
+
+fn is_scriptable(prefix: &[u8]) -> bool {
+    let start = prefix
+        .iter()
+        .position(|byte| !SNIFF_WHITESPACE_BYTES.contains(byte))
+        .unwrap_or(prefix.len());
+    let content = &prefix[start..];
+
+    SNIFF_SCRIPTABLE_PATTERNS.iter().any(|pattern| {
+        content.len() > pattern.len()
+            && content[..pattern.len()].eq_ignore_ascii_case(pattern)
+            && (pattern.starts_with(b"<!--")
+                || pattern.starts_with(b"<?xml")
+                || SNIFF_TAG_TERMINATING_BYTES.contains(&content[pattern.len()]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MediaType, classify_media_type, parse_content_type};
+
+    fn parse(value: &'static str) -> Option<mime::Mime> {
+        parse_content_type(&reqwest::header::HeaderValue::from_static(value))
+    }
+
+    #[test]
+    fn classify_html() {
+        for value in [
+            "text/html",
+            "text/html; charset=iso-8859-1",
+            "text/html;charset=UTF-8",
+            "TEXT/HTML ; charset=UTF-8",
+            "application/xhtml+xml",
+        ] {
+            assert_eq!(
+                classify_media_type(parse(value).as_ref(), false, b""),
+                MediaType::Html,
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn classify_css() {
+        for value in ["text/css", "text/css; charset=utf-8", "Text/CSS"] {
+            assert_eq!(
+                classify_media_type(parse(value).as_ref(), false, b""),
+                MediaType::Css,
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn classify_passthrough() {
+        assert_eq!(
+            classify_media_type(parse("image/png").as_ref(), true, b"<html>"),
+            MediaType::Passthrough(mime::IMAGE_PNG)
+        );
+    }
+
+    #[test]
+    fn sniff_navigable() {
+        assert_eq!(
+            classify_media_type(None, true, b"\n  <!DOCTYPE html><html></html>"),
+            MediaType::Html
+        );
+        assert_eq!(
+            classify_media_type(
+                parse("application/octet-stream").as_ref(),
+                true,
+                b"<p>paragraph</p>"
+            ),
+            MediaType::Html
+        );
+        assert_eq!(
+            classify_media_type(parse("invalid").as_ref(), true, b"<script>"),
+            MediaType::Html
+        );
+        assert_eq!(
+            classify_media_type(None, true, b"<pre>"),
+            MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM)
+        );
+        assert_eq!(
+            classify_media_type(None, true, b"\x89PNG\r\n\x1a\n"),
+            MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM)
+        );
+    }
+
+    #[test]
+    fn skip_sniff_non_navigable() {
+        assert_eq!(
+            classify_media_type(None, false, b"<html>"),
+            MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM)
+        );
+        assert_eq!(
+            classify_media_type(parse("application/octet-stream").as_ref(), false, b"<html>"),
+            MediaType::Passthrough(mime::APPLICATION_OCTET_STREAM)
+        );
+    }
+}
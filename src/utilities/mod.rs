@@ -1,17 +1,39 @@
+pub use body_limit::BodyLimitError;
+pub use charset::detect_html_encoding;
 pub use client::{
-    BodyType, ClientError, ClientRedirect, ClientResponse, ClientResponseBody, FetchResult,
-    FormRequest, fetch_validate_url,
+    CacheHeaders, ClientError, ClientRedirect, ClientResponse, ClientResponseBody, FetchResult,
+    FormRequest, SignedUrl, create_style_nonce, fetch_validate_url, verify_url_expiry,
 };
+pub use hmac_keys::HmacKeys;
+pub use link_store::LinkStore;
+pub use resolver::PermittedIpResolver;
+pub use response_cache::ResponseCache;
+pub use rewrite_css::CssRewrite;
+pub use rewrite_html::HtmlRewrite;
+pub use rewrite_url::{RewriteUrlError, get_path_url, get_proxy_url, get_url_expiry};
 #[cfg(test)]
 pub use shared::test_setup_hmac;
 pub use shared::{
-    BASE64_ENGINE, GLOBAL_CONFIG, HEADER_VALUE_CONTENT_HTML, HEADER_VALUE_NO_CACHE, HMAC,
-    HmacInstance, REQUEST_CLIENT,
+    BASE64_ENGINE, BASE64_URL_ENGINE, GLOBAL_CONFIG, HEADER_VALUE_CONTENT_HTML,
+    HEADER_VALUE_NO_CACHE, HMAC, IN_FLIGHT_REQUESTS, LINK_STORE, RESPONSE_CACHE, SharedState,
+    URL_CIPHER, get_shared_state, get_unix_timestamp, replace_shared_state,
 };
+pub use singleflight::Singleflight;
+pub use url_cipher::{UrlCipher, UrlCipherError};
 
+mod body_limit;
+mod charset;
 mod client;
+mod hmac_keys;
+mod ip_scope;
+mod link_store;
 pub mod macros;
+mod media_type;
+mod resolver;
+mod response_cache;
 mod rewrite_css;
 mod rewrite_html;
 mod rewrite_url;
 mod shared;
+mod singleflight;
+mod url_cipher;
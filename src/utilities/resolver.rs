@@ -0,0 +1,156 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+};
+
+use crate::{
+    model::{IpRule, PermittedIpRange},
+    utilities::client::verify_ip_range,
+};
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum ResolveError {
+    #[error("Can't resolve hostname `{0}`")]
+    Hostname(String),
+    #[error("None of the addresses of `{0}` are within the permitted range(s)")]
+    IpRangeDenied(String),
+}
+
+/// DNS resolver which only returns addresses that are within the permitted IP range(s).
+/// Since the connector uses exactly these addresses, a second (possibly different) DNS answer
+/// can't be used to bypass the range check.
+pub struct PermittedIpResolver {
+    exempt_hostname: Option<String>,
+    ip_rules: Arc<[IpRule]>,
+    permitted_ip_range: PermittedIpRange,
+}
+
+impl PermittedIpResolver {
+    /// The `exempt_hostname` (i.e. the configured proxy) will be resolved without any range check.
+    pub fn new(
+        permitted_ip_range: PermittedIpRange,
+        ip_rules: Arc<[IpRule]>,
+        exempt_hostname: Option<String>,
+    ) -> Self {
+        Self {
+            exempt_hostname,
+            ip_rules,
+            permitted_ip_range,
+        }
+    }
+}
+
+impl reqwest::dns::Resolve for PermittedIpResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let hostname = String::from(name.as_str());
+        let (permitted_ip_range, ip_rules) =
+            if self.exempt_hostname.as_deref() == Some(name.as_str()) {
+                (PermittedIpRange::Local, Arc::from([]))
+            } else {
+                (self.permitted_ip_range, self.ip_rules.clone())
+            };
+
+        Box::pin(async move {
+            let addresses = resolve_permitted(hostname, permitted_ip_range, &ip_rules).await?;
+            let addrs: reqwest::dns::Addrs = Box::new(addresses.into_iter());
+
+            Ok(addrs)
+        })
+    }
+}
+
+/// Resolves `hostname` without blocking the current thread and returns every address which is
+/// permitted by `ip_rules` or within `permitted_ip_range`.
+pub async fn resolve_permitted(
+    hostname: String,
+    permitted_ip_range: PermittedIpRange,
+    ip_rules: &[IpRule],
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    let resolved_addresses = actix_web::rt::task::spawn_blocking({
+        let hostname = hostname.clone();
+
+        move || (hostname.as_str(), 0).to_socket_addrs()
+    })
+    .await
+    .map_err(|_| ResolveError::Hostname(hostname.clone()))?
+    .map_err(|err| {
+        log::warn!("Couldn't resolve domain name `{hostname}`, with reason: {err}");
+        ResolveError::Hostname(hostname.clone())
+    })?;
+    let mut permitted_addresses = Vec::new();
+
+    for address in resolved_addresses {
+        if verify_ip_range(permitted_ip_range, ip_rules, address.ip()).is_ok() {
+            permitted_addresses.push(address);
+        } else {
+            log::info!(
+                "rejecting address `{}` of `{hostname}` (not within permitted range)",
+                address.ip()
+            );
+        }
+    }
+
+    if permitted_addresses.is_empty() {
+        return Err(ResolveError::IpRangeDenied(hostname));
+    }
+
+    Ok(permitted_addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::model::PermittedIpRange;
+
+    use super::{PermittedIpResolver, ResolveError, resolve_permitted};
+
+    #[actix_web::test]
+    async fn resolve_localhost_denied() {
+        assert!(matches!(
+            resolve_permitted(String::from("localhost"), PermittedIpRange::Global, &[]).await,
+            Err(ResolveError::IpRangeDenied(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn resolve_localhost_permitted() {
+        let addresses = resolve_permitted(String::from("localhost"), PermittedIpRange::Local, &[])
+            .await
+            .unwrap();
+
+        assert!(addresses.iter().all(|address| address.ip().is_loopback()));
+    }
+
+    #[actix_web::test]
+    async fn connect_localhost_denied() {
+        let client = reqwest::Client::builder()
+            .dns_resolver(Arc::new(PermittedIpResolver::new(
+                PermittedIpRange::Global,
+                Arc::from([]),
+                None,
+            )))
+            .build()
+            .unwrap();
+        let err = client.get("http://localhost:1/").send().await.unwrap_err();
+
+        assert!(matches!(
+            crate::utilities::client::map_request_error(err),
+            crate::utilities::ClientError::IpRangeDenied(_)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn resolve_localhost_allowed_by_rule() {
+        let ip_rules = crate::model::parse_ip_rules("allow 127.0.0.0/8\nallow ::1").unwrap();
+        let addresses = resolve_permitted(
+            String::from("localhost"),
+            PermittedIpRange::Global,
+            &ip_rules,
+        )
+        .await
+        .unwrap();
+
+        assert!(!addresses.is_empty());
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures_util::StreamExt;
+
+use crate::utilities::client::{CacheHeaders, ClientResponse, ClientResponseStream};
+
+/// Upper bound of the freshness lifetime (one year), longer lifetimes would overflow `Instant`.
+const MAX_FRESHNESS_LIFETIME: Duration = Duration::from_secs(31_536_000);
+
+/// Bounded LRU cache for upstream responses which don't need to be rewritten per request.
+pub struct ResponseCache {
+    max_entries: usize,
+    max_entry_size: usize,
+    max_size: usize,
+    state: Mutex<CacheState>,
+}
+
+pub struct CachedResponse {
+    body: bytes::Bytes,
+    cache_headers: CacheHeaders,
+    content_disposition: Option<reqwest::header::HeaderValue>,
+    content_type: mime::Mime,
+    expires_at: Instant,
+    stored_at: Instant,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, (u64, Arc<CachedResponse>)>,
+    /// Incremented whenever the cache is cleared, responses fetched before won't be stored.
+    generation: u64,
+    /// Last access "tick" to key, the first entry is the least recently used one.
+    recently_used: BTreeMap<u64, String>,
+    size: usize,
+    tick: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize, max_entries: usize, max_entry_size: usize) -> Self {
+        Self {
+            max_entries,
+            max_entry_size: max_entry_size.min(max_size),
+            max_size,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Returns a fresh entry for `key` and marks it as recently used.
+    pub fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let (last_tick, entry) = state.entries.get(key).cloned()?;
+
+        if entry.expires_at <= Instant::now() {
+            state.remove(key);
+            return None;
+        }
+
+        state.tick += 1;
+
+        let tick = state.tick;
+
+        state.recently_used.remove(&last_tick);
+        state.recently_used.insert(tick, String::from(key));
+
+        if let Some((entry_tick, _)) = state.entries.get_mut(key) {
+            *entry_tick = tick;
+        }
+
+        Some(entry)
+    }
+
+    #[cfg(test)]
+    pub fn insert(&self, key: String, response: CachedResponse) {
+        self.insert_locked(
+            &mut self.state.lock().unwrap_or_else(PoisonError::into_inner),
+            key,
+            response,
+        );
+    }
+
+    /// Current generation of the cache, which has to be passed to `store` before the response is fetched.
+    pub fn generation(&self) -> u64 {
+        self.state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .generation
+    }
+
+    /// Removes all entries, e.g. after the config was reloaded. Responses which are being stored will be dropped.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        *state = CacheState {
+            generation: state.generation + 1,
+            tick: state.tick,
+            ..CacheState::default()
+        };
+    }
+
+    fn insert_locked(&self, state: &mut CacheState, key: String, response: CachedResponse) {
+        if response.body.len() > self.max_entry_size {
+            return;
+        }
+
+        state.remove(&key);
+
+        while !state.entries.is_empty()
+            && (state.entries.len() >= self.max_entries
+                || state.size + response.body.len() > self.max_size)
+        {
+            state.remove_least_recently_used();
+        }
+
+        if self.max_entries == 0 {
+            return;
+        }
+
+        state.tick += 1;
+
+        let tick = state.tick;
+
+        state.size += response.body.len();
+        state.recently_used.insert(tick, key.clone());
+        state.entries.insert(key, (tick, Arc::new(response)));
+    }
+
+    /**
+     * Forwards the body of `client_response` and stores a copy in this cache, once it has been received completely.
+     * Responses which aren't cacheable (see `freshness_lifetime`), rewritten or too large will be returned unchanged,
+     * as well as responses whose `generation` is outdated once they have been received.
+     **/
+    pub fn store(
+        &'static self,
+        generation: u64,
+        key: String,
+        mut client_response: ClientResponse,
+    ) -> ClientResponse {
+        // the signed links of rewritten bodies may expire, or their key may be rotated
+        if client_response.rewritten {
+            log::debug!("cache skip (rewritten): '{key}'");
+            return client_response;
+        }
+
+        let Some(cache_headers) = client_response.cache_headers.as_ref() else {
+            return client_response;
+        };
+        let Some(lifetime) = freshness_lifetime(cache_headers) else {
+            log::debug!("cache skip (not cacheable): '{key}'");
+            return client_response;
+        };
+
+        if client_response.status_code != reqwest::StatusCode::OK
+            || client_response
+                .content_length
+                .is_some_and(|content_length| content_length > self.max_entry_size as u64)
+        {
+            return client_response;
+        }
+
+        let mut template_opt = Some((
+            key,
+            cache_headers.clone(),
+            client_response.content_disposition.clone(),
+            client_response.content_type.clone(),
+            lifetime,
+        ));
+        let mut buffer_opt = Some(Vec::new());
+        let body: ClientResponseStream = Box::pin(
+            client_response
+                .body
+                .map(Some)
+                .chain(futures_util::stream::once(async { None }))
+                .filter_map(move |chunk_res_opt| {
+                    let item = match chunk_res_opt {
+                        Some(Ok(chunk)) => {
+                            if let Some(buffer) = buffer_opt.as_mut() {
+                                if buffer.len() + chunk.len() > self.max_entry_size {
+                                    buffer_opt = None;
+                                } else {
+                                    buffer.extend_from_slice(chunk.as_ref());
+                                }
+                            }
+
+                            Some(Ok(chunk))
+                        }
+                        Some(Err(err)) => {
+                            buffer_opt = None;
+                            Some(Err(err))
+                        }
+                        // the body was received completely
+                        None => {
+                            if let (Some(buffer), Some(template)) =
+                                (buffer_opt.take(), template_opt.take())
+                            {
+                                let (
+                                    key,
+                                    cache_headers,
+                                    content_disposition,
+                                    content_type,
+                                    lifetime,
+                                ) = template;
+
+                                let mut state =
+                                    self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+                                if state.generation == generation {
+                                    self.insert_locked(
+                                        &mut state,
+                                        key,
+                                        CachedResponse {
+                                            body: bytes::Bytes::from(buffer),
+                                            cache_headers,
+                                            content_disposition,
+                                            content_type,
+                                            expires_at: Instant::now() + lifetime,
+                                            stored_at: Instant::now(),
+                                        },
+                                    );
+                                } else {
+                                    log::debug!("cache skip (cleared): '{key}'");
+                                }
+                            }
+
+                            None
+                        }
+                    };
+
+                    futures_util::future::ready(item)
+                }),
+        );
+
+        client_response.body = body;
+        client_response
+    }
+}
+
+impl CachedResponse {
+    /// The stored cache headers, with the time this entry spent in the cache added to `Age` (RFC 9111 section 5.1).
+    pub fn cache_headers(&self) -> CacheHeaders {
+        let age = get_age(&self.cache_headers).saturating_add(self.stored_at.elapsed());
+
+        CacheHeaders {
+            age: Some(reqwest::header::HeaderValue::from(age.as_secs())),
+            ..self.cache_headers.clone()
+        }
+    }
+
+    /// Checks whether the client already holds this representation (weak `If-None-Match` comparison).
+    pub fn is_not_modified(&self, headers: &actix_web::http::header::HeaderMap) -> bool {
+        let (Some(etag), Some(if_none_match)) = (
+            self.cache_headers.etag.as_ref(),
+            headers.get(actix_web::http::header::IF_NONE_MATCH),
+        ) else {
+            return false;
+        };
+        let etag = strip_weak_prefix(etag.as_bytes());
+
+        if_none_match
+            .as_bytes()
+            .split(|byte| *byte == b',')
+            .any(|tag| {
+                let tag = tag.trim_ascii();
+
+                tag == b"*" || strip_weak_prefix(tag) == etag
+            })
+    }
+
+    pub fn to_client_response(&self) -> ClientResponse {
+        let body = self.body.clone();
+
+        ClientResponse {
+            accept_ranges: None,
+            body: Box::pin(futures_util::stream::once(async move { Ok(body) })),
+            cache_headers: Some(self.cache_headers()),
+            content_disposition: self.content_disposition.clone(),
+            content_length: Some(self.body.len() as u64),
+            content_range: None,
+            content_type: self.content_type.clone(),
+            rewritten: false,
+            status_code: reqwest::StatusCode::OK,
+            style_nonce: None,
+        }
+    }
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &str) {
+        if let Some((tick, entry)) = self.entries.remove(key) {
+            self.recently_used.remove(&tick);
+            self.size -= entry.body.len();
+        }
+    }
+
+    fn remove_least_recently_used(&mut self) {
+        if let Some((_, key)) = self.recently_used.pop_first()
+            && let Some((_, entry)) = self.entries.remove(&key)
+        {
+            self.size -= entry.body.len();
+        }
+    }
+}
+
+fn strip_weak_prefix(etag: &[u8]) -> &[u8] {
+    etag.strip_prefix(b"W/").unwrap_or(etag)
+}
+
+/**
+ * Determines how long a response may be reused by a shared cache, based on its `Cache-Control` / `Expires` and `Age` headers.
+ * Responses without explicit freshness information won't be cached, the lifetime is capped at `MAX_FRESHNESS_LIFETIME`.
+ **/
+pub fn freshness_lifetime(cache_headers: &CacheHeaders) -> Option<Duration> {
+    if cache_headers.vary.as_ref().is_some_and(|vary| {
+        vary.to_str().map_or(true, |vary| {
+            vary.split(',').map(str::trim).any(|field| {
+                field == "*"
+                    || field.eq_ignore_ascii_case("accept-language")
+                    || field.eq_ignore_ascii_case("range")
+            })
+        })
+    }) {
+        return None;
+    }
+
+    let mut max_age_opt = None;
+    let mut shared_max_age_opt = None;
+
+    if let Some(cache_control) = cache_headers.cache_control.as_ref() {
+        for directive in cache_control.to_str().ok()?.split(',') {
+            let (name, value_opt) = match directive.trim().split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+
+            if name.eq_ignore_ascii_case("no-store")
+                || name.eq_ignore_ascii_case("no-cache")
+                || name.eq_ignore_ascii_case("private")
+            {
+                return None;
+            } else if name.eq_ignore_ascii_case("max-age") {
+                max_age_opt = value_opt.and_then(|value| value.parse::<u64>().ok());
+            } else if name.eq_ignore_ascii_case("s-maxage") {
+                shared_max_age_opt = value_opt.and_then(|value| value.parse::<u64>().ok());
+            }
+        }
+    }
+
+    let lifetime = match shared_max_age_opt.or(max_age_opt) {
+        Some(max_age) => Duration::from_secs(max_age),
+        None => {
+            let expires =
+                httpdate::parse_http_date(cache_headers.expires.as_ref()?.to_str().ok()?).ok()?;
+
+            expires.duration_since(SystemTime::now()).ok()?
+        }
+    };
+
+    lifetime
+        .checked_sub(get_age(cache_headers))
+        .filter(|lifetime| !lifetime.is_zero())
+        .map(|lifetime| lifetime.min(MAX_FRESHNESS_LIFETIME))
+}
+
+/// Time the response already spent in upstream caches, according to its `Age` header.
+fn get_age(cache_headers: &CacheHeaders) -> Duration {
+    cache_headers
+        .age
+        .as_ref()
+        .and_then(|age| age.to_str().ok()?.parse::<u64>().ok())
+        .map_or(Duration::ZERO, Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use futures_util::StreamExt;
+
+    use super::{CachedResponse, MAX_FRESHNESS_LIFETIME, ResponseCache, freshness_lifetime};
+    use crate::utilities::client::{CacheHeaders, ClientResponse};
+
+    fn cache_headers(cache_control: &'static str) -> CacheHeaders {
+        CacheHeaders {
+            cache_control: Some(reqwest::header::HeaderValue::from_static(cache_control)),
+            ..CacheHeaders::default()
+        }
+    }
+
+    fn cached_response(body: &'static [u8]) -> CachedResponse {
+        CachedResponse {
+            body: bytes::Bytes::from_static(body),
+            cache_headers: CacheHeaders::default(),
+            content_disposition: None,
+            content_type: mime::IMAGE_PNG,
+            expires_at: Instant::now() + Duration::from_secs(60),
+            stored_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn freshness() {
+        assert_eq!(
+            freshness_lifetime(&cache_headers("public, max-age=600")),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            freshness_lifetime(&cache_headers("max-age=600, s-maxage=60")),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness_lifetime(&CacheHeaders {
+                age: Some(reqwest::header::HeaderValue::from_static("100")),
+                ..cache_headers("max-age=600")
+            }),
+            Some(Duration::from_secs(500))
+        );
+        assert_eq!(freshness_lifetime(&cache_headers("max-age=0")), None);
+        assert_eq!(
+            freshness_lifetime(&cache_headers("max-age=18446744073709551615")),
+            Some(MAX_FRESHNESS_LIFETIME)
+        );
+        assert_eq!(
+            freshness_lifetime(&cache_headers("max-age=600, private")),
+            None
+        );
+        assert_eq!(freshness_lifetime(&cache_headers("no-store")), None);
+        assert_eq!(freshness_lifetime(&CacheHeaders::default()), None);
+        assert_eq!(
+            freshness_lifetime(&CacheHeaders {
+                vary: Some(reqwest::header::HeaderValue::from_static("Accept-Language")),
+                ..cache_headers("max-age=600")
+            }),
+            None
+        );
+        assert_eq!(
+            freshness_lifetime(&CacheHeaders {
+                expires: Some(reqwest::header::HeaderValue::from_static(
+                    "Thu, 01 Jan 1970 00:00:00 GMT"
+                )),
+                ..CacheHeaders::default()
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn add_cached_age() {
+        let aged_response = CachedResponse {
+            cache_headers: CacheHeaders {
+                age: Some(reqwest::header::HeaderValue::from_static("100")),
+                vary: Some(reqwest::header::HeaderValue::from_static("Accept-Encoding")),
+                ..cache_headers("max-age=600")
+            },
+            stored_at: Instant::now() - Duration::from_secs(30),
+            ..cached_response(b"a")
+        };
+        let client_cache_headers = aged_response.to_client_response().cache_headers.unwrap();
+
+        assert_eq!(client_cache_headers.age.unwrap(), "130");
+        assert_eq!(client_cache_headers.vary.unwrap(), "Accept-Encoding");
+        assert_eq!(
+            CachedResponse {
+                stored_at: Instant::now() - Duration::from_secs(5),
+                ..cached_response(b"a")
+            }
+            .cache_headers()
+            .age
+            .unwrap(),
+            "5"
+        );
+    }
+
+    #[test]
+    fn not_modified() {
+        let cached_response = CachedResponse {
+            cache_headers: CacheHeaders {
+                etag: Some(reqwest::header::HeaderValue::from_static("\"abc\"")),
+                ..CacheHeaders::default()
+            },
+            ..cached_response(b"a")
+        };
+        let mut headers = actix_web::http::header::HeaderMap::new();
+
+        assert!(!cached_response.is_not_modified(&headers));
+
+        headers.insert(
+            actix_web::http::header::IF_NONE_MATCH,
+            actix_web::http::header::HeaderValue::from_static("\"xyz\", W/\"abc\""),
+        );
+        assert!(cached_response.is_not_modified(&headers));
+
+        headers.insert(
+            actix_web::http::header::IF_NONE_MATCH,
+            actix_web::http::header::HeaderValue::from_static("\"xyz\""),
+        );
+        assert!(!cached_response.is_not_modified(&headers));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = ResponseCache::new(1024, 2, 1024);
+
+        cache.insert(String::from("a"), cached_response(b"a"));
+        cache.insert(String::from("b"), cached_response(b"b"));
+        assert!(cache.get("a").is_some());
+        cache.insert(String::from("c"), cached_response(b"c"));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+    }
+
+    #[test]
+    fn evict_by_size() {
+        let cache = ResponseCache::new(8, 16, 8);
+
+        cache.insert(String::from("a"), cached_response(b"0123"));
+        cache.insert(String::from("b"), cached_response(b"4567"));
+        cache.insert(String::from("c"), cached_response(b"89"));
+        cache.insert(String::from("d"), cached_response(b"0123456789"));
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_none());
+    }
+
+    #[test]
+    fn skip_expired() {
+        let cache = ResponseCache::new(1024, 16, 1024);
+
+        cache.insert(
+            String::from("a"),
+            CachedResponse {
+                expires_at: Instant::now(),
+                ..cached_response(b"a")
+            },
+        );
+
+        assert!(cache.get("a").is_none());
+    }
+
+    #[actix_web::test]
+    async fn store_streamed_body() {
+        let cache: &'static ResponseCache = Box::leak(Box::new(ResponseCache::new(1024, 16, 1024)));
+        let client_response = cache.store(
+            cache.generation(),
+            String::from("a"),
+            ClientResponse {
+                accept_ranges: None,
+                body: Box::pin(futures_util::stream::iter([
+                    Ok(bytes::Bytes::from_static(b"01234")),
+                    Ok(bytes::Bytes::from_static(b"56789")),
+                ])),
+                cache_headers: Some(cache_headers("max-age=60")),
+                content_disposition: None,
+                content_length: None,
+                content_range: None,
+                content_type: mime::IMAGE_PNG,
+                rewritten: false,
+                status_code: reqwest::StatusCode::OK,
+                style_nonce: None,
+            },
+        );
+
+        assert!(cache.get("a").is_none());
+
+        let chunks: Vec<_> = client_response.body.collect().await;
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            cache.get("a").unwrap().to_client_response().content_length,
+            Some(10)
+        );
+    }
+
+    #[actix_web::test]
+    async fn skip_rewritten_body() {
+        let cache: &'static ResponseCache = Box::leak(Box::new(ResponseCache::new(1024, 16, 1024)));
+        let client_response = cache.store(
+            cache.generation(),
+            String::from("a"),
+            ClientResponse {
+                accept_ranges: None,
+                body: Box::pin(futures_util::stream::iter([Ok(bytes::Bytes::from_static(
+                    b"a{background:url(./?url=https%3A%2F%2Fexample.com%2F&hash=00)}",
+                ))])),
+                cache_headers: Some(cache_headers("max-age=60")),
+                content_disposition: None,
+                content_length: None,
+                content_range: None,
+                content_type: mime::TEXT_CSS,
+                rewritten: true,
+                status_code: reqwest::StatusCode::OK,
+                style_nonce: None,
+            },
+        );
+        let chunks: Vec<_> = client_response.body.collect().await;
+
+        assert_eq!(chunks.len(), 1);
+        assert!(cache.get("a").is_none());
+    }
+
+    #[actix_web::test]
+    async fn skip_body_after_clear() {
+        let cache: &'static ResponseCache = Box::leak(Box::new(ResponseCache::new(1024, 16, 1024)));
+
+        cache.insert(String::from("a"), cached_response(b"a"));
+
+        let generation = cache.generation();
+
+        cache.clear();
+
+        assert!(cache.get("a").is_none());
+
+        // fetched before the cache was cleared
+        let client_response = cache.store(
+            generation,
+            String::from("b"),
+            ClientResponse {
+                accept_ranges: None,
+                body: Box::pin(futures_util::stream::iter([Ok(bytes::Bytes::from_static(
+                    b"b",
+                ))])),
+                cache_headers: Some(cache_headers("max-age=60")),
+                content_disposition: None,
+                content_length: None,
+                content_range: None,
+                content_type: mime::IMAGE_PNG,
+                rewritten: false,
+                status_code: reqwest::StatusCode::OK,
+                style_nonce: None,
+            },
+        );
+        let chunks: Vec<_> = client_response.body.collect().await;
+
+        assert_eq!(chunks.len(), 1);
+        assert!(cache.get("b").is_none());
+    }
+}
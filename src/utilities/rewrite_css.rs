@@ -1,8 +1,11 @@
-use std::rc::Rc;
+use std::{rc::Rc, sync::Arc};
 
 use url::Url;
 
-use crate::utilities::rewrite_url::{RewriteUrlError, rewrite_url};
+use crate::utilities::{
+    SharedState,
+    rewrite_url::{RewriteUrlError, rewrite_url},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum RewriteCssError {
@@ -32,11 +35,12 @@ pub struct CssRewrite {
     match_start: usize,
     match_state: MatchState,
     output: Vec<u8>,
+    shared_state: Arc<SharedState>,
     url_start: usize,
 }
 
 impl CssRewrite {
-    pub fn new(base_url: Rc<Url>) -> Self {
+    pub fn new(shared_state: Arc<SharedState>, base_url: Rc<Url>) -> Self {
         Self {
             base_url,
             buffer: Vec::new(),
@@ -44,6 +48,7 @@ impl CssRewrite {
             match_start: 0,
             match_state: MatchState::None,
             output: Vec::new(),
+            shared_state,
             url_start: 0,
         }
     }
@@ -59,6 +64,11 @@ impl CssRewrite {
         }
     }
 
+    /// Returns the output produced since the last call, without waiting for the stylesheet to end.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
     pub fn end(mut self) -> Result<Vec<u8>, RewriteCssError> {
         self.parse_buffer()?;
         Ok(self.output)
@@ -111,6 +121,7 @@ impl CssRewrite {
                         _ => {
                             self.output.extend_from_slice(
                                 rewrite_url(
+                                    &self.shared_state,
                                     &self.base_url,
                                     std::str::from_utf8(
                                         &self.buffer[self.url_start..offset + index],
@@ -136,6 +147,7 @@ impl CssRewrite {
                         _ => {
                             self.output.extend_from_slice(
                                 rewrite_url(
+                                    &self.shared_state,
                                     &self.base_url,
                                     std::str::from_utf8(
                                         &self.buffer[self.url_start..offset + index],
@@ -152,6 +164,7 @@ impl CssRewrite {
                     if self.match_state != MatchState::ClosingBracket {
                         self.output.extend_from_slice(
                             rewrite_url(
+                                &self.shared_state,
                                 &self.base_url,
                                 std::str::from_utf8(&self.buffer[self.url_start..offset + index])?,
                             )?
@@ -236,10 +249,12 @@ mod tests {
 
     #[test]
     fn no_quotes_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b"url(main.css)").unwrap();
 
@@ -251,10 +266,12 @@ mod tests {
 
     #[test]
     fn single_quotes_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b"url('main.css')").unwrap();
 
@@ -266,10 +283,12 @@ mod tests {
 
     #[test]
     fn double_quotes_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b"url(\"main.css\")").unwrap();
 
@@ -281,10 +300,12 @@ mod tests {
 
     #[test]
     fn no_quotes_relative_n_5() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write("url(main.css)".repeat(5).as_bytes())
@@ -295,10 +316,12 @@ mod tests {
 
     #[test]
     fn single_quotes_relative_n_5() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write("url('main.css')".repeat(5).as_bytes())
@@ -309,10 +332,12 @@ mod tests {
 
     #[test]
     fn double_quotes_relative_n_5() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write("url(\"main.css\")".repeat(5).as_bytes())
@@ -323,10 +348,12 @@ mod tests {
 
     #[test]
     fn chunked_single_quote_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b" ".repeat(2048).as_slice()).unwrap();
         rewriter.write(b"ur").unwrap();
@@ -344,10 +371,12 @@ mod tests {
 
     #[test]
     fn simple_single_quote_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write(b"url('https://www.example.com/main.css')")
@@ -361,10 +390,12 @@ mod tests {
 
     #[test]
     fn chunked_double_quote_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b" ".repeat(2048).as_slice()).unwrap();
         rewriter.write(b"ur").unwrap();
@@ -382,10 +413,12 @@ mod tests {
 
     #[test]
     fn simple_double_quote_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write(b"url(\"https://www.example.com/main.css\")")
@@ -399,10 +432,12 @@ mod tests {
 
     #[test]
     fn chunked_no_quotes_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter.write(b" ".repeat(2048).as_slice()).unwrap();
         rewriter.write(b"ur").unwrap();
@@ -418,10 +453,12 @@ mod tests {
 
     #[test]
     fn simple_no_quotes_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter =
-            CssRewrite::new(Rc::new(url::Url::parse("https://www.example.com").unwrap()));
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
 
         rewriter
             .write(b"url(https://www.example.com/main.css)")
@@ -432,4 +469,35 @@ mod tests {
             "url(./?url=https%3A%2F%2Fwww.example.com%2Fmain.css&hash=7d40cd69599262cfe009ac148491a37e9ec47dcf2386c2807bc2255fff6d5fa3)"
         );
     }
+
+    #[test]
+    fn chunked_take_output_n_2() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = CssRewrite::new(
+            shared_state,
+            Rc::new(url::Url::parse("https://www.example.com").unwrap()),
+        );
+
+        rewriter.write(b"a{color:red}url(main.css)").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.take_output().as_slice()).unwrap(),
+            "a{color:red}url(./?url=https%3A%2F%2Fwww.example.com%2Fmain.css&hash=7d40cd69599262cfe009ac148491a37e9ec47dcf2386c2807bc2255fff6d5fa3)"
+        );
+
+        rewriter.write(b"b{background:url(").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.take_output().as_slice()).unwrap(),
+            "b{background:url("
+        );
+
+        rewriter.write(b"main.css)}").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().as_slice()).unwrap(),
+            "./?url=https%3A%2F%2Fwww.example.com%2Fmain.css&hash=7d40cd69599262cfe009ac148491a37e9ec47dcf2386c2807bc2255fff6d5fa3)}"
+        );
+    }
 }
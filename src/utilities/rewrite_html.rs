@@ -1,24 +1,21 @@
-use std::{cell::RefCell, collections::HashSet, rc::Rc};
+use std::{cell::RefCell, collections::HashSet, rc::Rc, sync::Arc};
 
-use base64::Engine;
 use lol_html::html_content::{Element, EndTag, TextChunk};
 
-use crate::utilities::{rewrite_css::CssRewrite, rewrite_url::rewrite_url, shared::BASE64_ENGINE};
+use crate::utilities::{SharedState, rewrite_css::CssRewrite, rewrite_url::rewrite_url};
 
 type CssRewriteRef = Rc<RefCell<Option<CssRewrite>>>;
 type NoScriptBuffer = Rc<RefCell<String>>;
 type OutputSink = Box<dyn Fn(&[u8])>;
-type StyleHashList = Rc<RefCell<Vec<String>>>;
 
 pub struct HtmlRewrite<'html> {
+    decoder: encoding_rs::Decoder,
     output: Rc<RefCell<Vec<u8>>>,
     rewriter: lol_html::HtmlRewriter<'html, OutputSink>,
-    style_hashes: StyleHashList,
 }
 
 pub struct HtmlRewriteResult {
     pub html: Vec<u8>,
-    pub style_hashes: Vec<String>,
 }
 
 const ALLOWED_META_EQUIV_VALUES: [&str; 3] = ["content-type", "refresh", "x-ua-compatible"];
@@ -100,53 +97,98 @@ static ALLOWED_ATTRIBUTES: once_cell::sync::Lazy<HashSet<&'static str>> =
     });
 
 impl<'html> HtmlRewrite<'html> {
-    pub fn new(url: Rc<url::Url>) -> Self {
+    pub fn new(
+        shared_state: Arc<SharedState>,
+        url: Rc<url::Url>,
+        style_nonce: Option<Rc<str>>,
+    ) -> Self {
+        Self::with_encoding(shared_state, url, style_nonce, encoding_rs::UTF_8)
+    }
+
+    /**
+     * Creates a rewriter for documents in the given `encoding`, the output is always UTF-8.
+     * Links are rewritten with the given shared values, which should be taken once per request.
+     **/
+    pub fn with_encoding(
+        shared_state: Arc<SharedState>,
+        url: Rc<url::Url>,
+        style_nonce: Option<Rc<str>>,
+        encoding: &'static encoding_rs::Encoding,
+    ) -> Self {
         let output = Rc::new(RefCell::new(Vec::<u8>::new()));
         let css_rewriter: CssRewriteRef = Rc::new(RefCell::new(None));
-        let style_hashes: StyleHashList = Rc::new(RefCell::new(Vec::<String>::new()));
         let noscript_buf: NoScriptBuffer = Rc::new(RefCell::new(String::new()));
+        let config_opt = shared_state.config.as_deref();
 
         Self {
+            decoder: encoding.new_decoder_with_bom_removal(),
             output: output.clone(),
             rewriter: lol_html::HtmlRewriter::new(
                 lol_html::Settings {
                     element_content_handlers: vec![
                         lol_html::element!("*", Self::remove_disallowed_attributes),
-                        lol_html::element!("*[href]", Self::transform_href(url.clone())),
-                        lol_html::element!("*[src]", Self::transform_src(url.clone())),
+                        lol_html::element!(
+                            "*[href]",
+                            Self::transform_href(shared_state.clone(), url.clone())
+                        ),
+                        lol_html::element!(
+                            "*[src]",
+                            Self::transform_src(shared_state.clone(), url.clone())
+                        ),
                         lol_html::element!("applet", Self::remove_element),
                         lol_html::element!("base", Self::remove_element),
-                        lol_html::element!("body", Self::append_proxy_header(url.clone())),
+                        lol_html::element!(
+                            "body",
+                            Self::append_proxy_header(
+                                url.clone(),
+                                config_opt.is_some_and(|config| config.path_urls)
+                            )
+                        ),
                         lol_html::element!("canvas", Self::remove_element),
                         lol_html::element!("embed", Self::remove_element),
-                        lol_html::element!("form", Self::transform_form(url.clone())),
+                        lol_html::element!(
+                            "form",
+                            Self::transform_form(shared_state.clone(), url.clone())
+                        ),
                         lol_html::element!("head", Self::append_proxy_styles),
                         lol_html::element!(
                             "img",
                             Self::transform_img(
-                                match crate::utilities::shared::GLOBAL_CONFIG.get() {
-                                    Some(config) => config.lazy_images,
-                                    _ => false,
-                                }
+                                config_opt.is_some_and(|config| config.lazy_images)
                             )
                         ),
-                        lol_html::element!("img[srcset]", Self::transform_srcset(url.clone())),
-                        lol_html::element!("source[srcset]", Self::transform_srcset(url.clone())),
+                        lol_html::element!(
+                            "img[srcset]",
+                            Self::transform_srcset(shared_state.clone(), url.clone())
+                        ),
+                        lol_html::element!(
+                            "source[srcset]",
+                            Self::transform_srcset(shared_state.clone(), url.clone())
+                        ),
                         lol_html::element!("link", Self::filter_link_elements),
                         lol_html::element!("math", Self::remove_element),
-                        lol_html::element!("meta", Self::filter_meta_elements(url.clone())),
+                        lol_html::element!(
+                            "meta",
+                            Self::filter_meta_elements(shared_state.clone(), url.clone())
+                        ),
                         lol_html::element!(
                             "noscript",
                             Self::transform_noscript(
+                                shared_state.clone(),
                                 url.clone(),
                                 noscript_buf.clone(),
-                                style_hashes.clone()
+                                style_nonce.clone()
                             )
                         ),
                         lol_html::element!("script", Self::remove_element),
                         lol_html::element!(
                             "style",
-                            Self::transform_style(url, css_rewriter.clone(), style_hashes.clone())
+                            Self::transform_style(
+                                shared_state.clone(),
+                                url,
+                                css_rewriter.clone(),
+                                style_nonce
+                            )
                         ),
                         lol_html::element!("svg", Self::remove_element),
                         lol_html::text!("noscript", Self::write_noscript_content(noscript_buf)),
@@ -158,24 +200,45 @@ impl<'html> HtmlRewrite<'html> {
                     output.borrow_mut().extend_from_slice(chunk);
                 }),
             ),
-            style_hashes,
         }
     }
 
     pub fn write(&mut self, data: &[u8]) -> Result<(), lol_html::errors::RewritingError> {
-        self.rewriter.write(data)
+        let decoded = self.decode(data, false);
+
+        self.rewriter.write(decoded.as_bytes())
+    }
+
+    /// Returns the output produced since the last call, without waiting for the document to end.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.take()
     }
 
-    pub fn end(self) -> Result<HtmlRewriteResult, lol_html::errors::RewritingError> {
+    pub fn end(mut self) -> Result<HtmlRewriteResult, lol_html::errors::RewritingError> {
+        let decoded = self.decode(&[], true);
+
+        self.rewriter.write(decoded.as_bytes())?;
         self.rewriter.end()?;
 
         Ok(HtmlRewriteResult {
             html: self.output.take(),
-            style_hashes: self.style_hashes.take(),
         })
     }
 
+    fn decode(&mut self, data: &[u8], last: bool) -> String {
+        let mut decoded = String::with_capacity(
+            self.decoder
+                .max_utf8_buffer_length(data.len())
+                .unwrap_or(data.len()),
+        );
+        // the capacity is sufficient for the whole input, therefore this can't return `OutputFull`
+        let _ = self.decoder.decode_to_string(data, &mut decoded, last);
+
+        decoded
+    }
+
     fn transform_src(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
@@ -183,6 +246,7 @@ impl<'html> HtmlRewrite<'html> {
             element.set_attribute(
                 "src",
                 &rewrite_url(
+                    &shared_state,
                     base_url.as_ref(),
                     Self::get_unchecked_attribute_value(element, "src").as_str(),
                 )?,
@@ -210,6 +274,7 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn transform_srcset(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
@@ -221,7 +286,8 @@ impl<'html> HtmlRewrite<'html> {
             for group in IMG_SRCSET_REGEX.captures_iter(&src_set_values) {
                 if let Some(matched_url) = group.name("url") {
                     let html_decoded = Self::html_entity_decode(matched_url.as_str());
-                    let proxy_url = rewrite_url(base_url.as_ref(), html_decoded.as_str())?;
+                    let proxy_url =
+                        rewrite_url(&shared_state, base_url.as_ref(), html_decoded.as_str())?;
 
                     output.push_str(&src_set_values[offset..matched_url.start()]);
                     output.push_str(&proxy_url);
@@ -237,6 +303,7 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn transform_href(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
@@ -244,6 +311,7 @@ impl<'html> HtmlRewrite<'html> {
             element.set_attribute(
                 "href",
                 &rewrite_url(
+                    &shared_state,
                     base_url.as_ref(),
                     Self::get_unchecked_attribute_value(element, "href").as_str(),
                 )?,
@@ -254,19 +322,24 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn transform_style(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
         css_rewriter: CssRewriteRef,
-        style_hashes: StyleHashList,
+        style_nonce: Option<Rc<str>>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
         move |element: &mut Element<'_, '_>| {
-            css_rewriter.replace(Some(CssRewrite::new(base_url.clone())));
+            css_rewriter.replace(Some(CssRewrite::new(
+                shared_state.clone(),
+                base_url.clone(),
+            )));
+
+            if let Some(nonce) = style_nonce.as_deref() {
+                element.set_attribute("nonce", nonce)?;
+            }
 
             if let Some(end_tag_handlers) = element.end_tag_handlers() {
-                end_tag_handlers.push(Box::new(Self::flush_style(
-                    css_rewriter.clone(),
-                    style_hashes.clone(),
-                )));
+                end_tag_handlers.push(Box::new(Self::flush_style(css_rewriter.clone())));
             }
 
             Ok(())
@@ -275,25 +348,12 @@ impl<'html> HtmlRewrite<'html> {
 
     fn flush_style(
         css_rewriter: CssRewriteRef,
-        style_hashes: StyleHashList,
     ) -> impl Fn(&mut EndTag<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'static
     {
         move |end| {
             let current_css_rewriter = css_rewriter.replace(None);
             let css_bytes = current_css_rewriter.unwrap().end()?;
 
-            style_hashes.borrow_mut().push(format!(
-                "'sha256-{}'",
-                BASE64_ENGINE.encode({
-                    use sha2::Digest;
-
-                    let mut hasher = sha2::Sha256::new();
-
-                    hasher.update(css_bytes.as_slice());
-                    hasher.finalize()
-                })
-            ));
-
             end.before(
                 std::str::from_utf8(&css_bytes)?,
                 lol_html::html_content::ContentType::Html,
@@ -319,6 +379,7 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn transform_form(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         move |element: &mut Element<'_, '_>| {
@@ -343,6 +404,7 @@ impl<'html> HtmlRewrite<'html> {
                 element.set_attribute(
                     "action",
                     &rewrite_url(
+                        &shared_state,
                         base_url.as_ref(),
                         Self::html_entity_decode(action.trim()).as_str(),
                     )?,
@@ -368,6 +430,7 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn filter_meta_elements(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         move |element: &mut Element<'_, '_>| {
@@ -379,6 +442,11 @@ impl<'html> HtmlRewrite<'html> {
                     element.remove()
                 }
 
+                if lc_equiv_trim == "content-type" {
+                    // the output is always UTF-8, regardless of the original encoding
+                    element.set_attribute("content", mime::TEXT_HTML_UTF_8.as_ref())?;
+                }
+
                 if lc_equiv_trim == "refresh" {
                     if let Some(content) = element.get_attribute("content") {
                         if let Some(refresh_capture) = META_EQUIV_REFRESH.captures(&content) {
@@ -391,7 +459,11 @@ impl<'html> HtmlRewrite<'html> {
                                     format!(
                                         "{}{}",
                                         &content[..url_match.start()],
-                                        rewrite_url(&base_url, html_decoded.as_str())?
+                                        rewrite_url(
+                                            &shared_state,
+                                            &base_url,
+                                            html_decoded.as_str()
+                                        )?
                                     )
                                     .as_str(),
                                 )?;
@@ -403,7 +475,9 @@ impl<'html> HtmlRewrite<'html> {
 
                     element.remove()
                 }
-            } else if !element.has_attribute("charset") {
+            } else if element.has_attribute("charset") {
+                element.set_attribute("charset", "utf-8")?;
+            } else {
                 element.remove()
             }
 
@@ -412,17 +486,19 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn transform_noscript(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
         noscript_buf: NoScriptBuffer,
-        style_hashes: StyleHashList,
+        style_nonce: Option<Rc<str>>,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
         move |element| {
             if let Some(end_tag_handlers) = element.end_tag_handlers() {
                 end_tag_handlers.push(Box::new(Self::flush_noscript_content(
+                    shared_state.clone(),
                     base_url.clone(),
                     noscript_buf.clone(),
-                    style_hashes.clone(),
+                    style_nonce.clone(),
                 )));
             }
 
@@ -433,25 +509,20 @@ impl<'html> HtmlRewrite<'html> {
     }
 
     fn flush_noscript_content(
+        shared_state: Arc<SharedState>,
         base_url: Rc<url::Url>,
         noscript_buf: NoScriptBuffer,
-        style_hashes: StyleHashList,
+        style_nonce: Option<Rc<str>>,
     ) -> impl Fn(&mut EndTag<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'static
     {
         move |end| {
-            let mut rewriter = HtmlRewrite::new(base_url.clone());
+            let mut rewriter =
+                HtmlRewrite::new(shared_state.clone(), base_url.clone(), style_nonce.clone());
 
             rewriter.write(noscript_buf.take().as_bytes())?;
 
-            let result = rewriter.end()?;
-            let mut hash_list = style_hashes.borrow_mut();
-
-            for hash in result.style_hashes {
-                hash_list.push(hash);
-            }
-
             end.after(
-                String::from_utf8(result.html)?.as_str(),
+                String::from_utf8(rewriter.end()?.html)?.as_str(),
                 lol_html::html_content::ContentType::Html,
             );
 
@@ -472,12 +543,14 @@ impl<'html> HtmlRewrite<'html> {
 
     fn append_proxy_header(
         base_url: Rc<url::Url>,
+        path_urls: bool,
     ) -> impl Fn(&mut Element<'_, '_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + 'html
     {
         move |element: &mut Element<'_, '_>| {
             element.prepend(
                 crate::templates::render_template_string(crate::templates::Template::Header(
                     base_url.clone(),
+                    path_urls,
                 ))
                 .as_str(),
                 lol_html::html_content::ContentType::Html,
@@ -564,11 +637,13 @@ mod tests {
 
     #[test]
     fn rewrite_a_href_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<a href='/'>main</a>").unwrap();
 
@@ -581,11 +656,13 @@ mod tests {
 
     #[test]
     fn rewrite_a_href_relative_html_entity_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<a href='/?a=b&amp;c=d'>example</a>")
@@ -600,11 +677,13 @@ mod tests {
 
     #[test]
     fn rewrite_img_src_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<img src='/logo.png'>").unwrap();
 
@@ -618,11 +697,13 @@ mod tests {
 
     #[test]
     fn rewrite_img_src_relative_html_entity_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<img src='/logo&comma;png'>").unwrap();
 
@@ -636,11 +717,13 @@ mod tests {
 
     #[test]
     fn rewrite_iframe_src_relative_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<iframe src='/test.html'></iframe>")
@@ -655,11 +738,13 @@ mod tests {
 
     #[test]
     fn rewrite_iframe_src_relative_html_entity_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<iframe src='/test&comma;html'></iframe>")
@@ -674,11 +759,13 @@ mod tests {
 
     #[test]
     fn rewrite_img_attributes_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<img class='image' onmouseover='javascript:console.log(this)' onerror='javascript:alert(\"failed\")'>").unwrap();
 
@@ -690,11 +777,13 @@ mod tests {
 
     #[test]
     fn rewrite_img_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<img srcset='header640.png 640w, header960.png 960w, header1024.png 1024w, header.png'>").unwrap();
 
@@ -710,11 +799,13 @@ mod tests {
 
     #[test]
     fn rewrite_img_srcset_html_entity_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<img srcset='header640&comma;png 640w, header&amp;png'>")
@@ -731,11 +822,13 @@ mod tests {
 
     #[test]
     fn rewrite_iframe_attributes_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<iframe height='1' width='1' onclick='javascript:alert(1)'></iframe>")
@@ -749,11 +842,13 @@ mod tests {
 
     #[test]
     fn remove_applet_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<applet />").unwrap();
 
@@ -765,11 +860,13 @@ mod tests {
 
     #[test]
     fn remove_canvas_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<canvas />").unwrap();
 
@@ -781,11 +878,13 @@ mod tests {
 
     #[test]
     fn remove_embed_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<embed />").unwrap();
 
@@ -797,11 +896,13 @@ mod tests {
 
     #[test]
     fn remove_math_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<math />").unwrap();
 
@@ -813,11 +914,13 @@ mod tests {
 
     #[test]
     fn remove_script_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<script />").unwrap();
 
@@ -829,11 +932,13 @@ mod tests {
 
     #[test]
     fn remove_svg_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<svg />").unwrap();
 
@@ -845,11 +950,13 @@ mod tests {
 
     #[test]
     fn rewrite_body_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<body><h1>Test</h1></body>").unwrap();
 
@@ -864,11 +971,13 @@ mod tests {
 
     #[test]
     fn rewrite_head_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/index.html").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<head><title>Test</title></head>").unwrap();
 
@@ -883,11 +992,13 @@ mod tests {
 
     #[test]
     fn rewrite_style_plain_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<head><style>a{color:red}</style></head>")
@@ -904,11 +1015,13 @@ mod tests {
 
     #[test]
     fn rewrite_style_url_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<head><style>body{background-image:url('/main.css')}</style></head>")
@@ -927,11 +1040,13 @@ mod tests {
 
     #[test]
     fn rewrite_style_url_n_3() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<head><style>url('/main.css')</style><style>url('/index.css')</style><style>url('/theme.css')</style></head>")
@@ -951,11 +1066,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_icon_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link rel=\"icon\" href=\"favicon.ico\">")
@@ -970,11 +1087,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_icon_html_entity_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link rel=\"icon\" href=\"favicon&comma;ico\">")
@@ -989,11 +1108,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_shortcut_icon_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link rel=\"shortcut icon\" href=\"favicon.ico\">")
@@ -1007,11 +1128,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_stylesheet_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link href=\"default.css\" rel=\"stylesheet\" type=\"text/css\">")
@@ -1025,11 +1148,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_alternate_stylesheet_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link href=\"basic.css\" rel=\"alternate stylesheet\" type=\"text/css\">")
@@ -1043,11 +1168,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_help_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<link href=\"/a\" rel=\"help\">").unwrap();
 
@@ -1059,11 +1186,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_license_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link href=\"/a\" rel=\"license\">")
@@ -1077,11 +1206,13 @@ mod tests {
 
     #[test]
     fn rewrite_link_alternate_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<link href=\"/rss\" rel=\"alternate\" type=\"application/rss+xml\">")
@@ -1095,11 +1226,13 @@ mod tests {
 
     #[test]
     fn rewrite_meta_content_type_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<meta http-equiv=\"content-type\" content=\"text/html; charset=utf-8\">")
@@ -1113,11 +1246,13 @@ mod tests {
 
     #[test]
     fn rewrite_meta_ua_compatible_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<meta http-equiv=\"x-ua-compatible\" content=\"IE=edge\">")
@@ -1131,11 +1266,13 @@ mod tests {
 
     #[test]
     fn rewrite_meta_refresh_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<meta http-equiv=\"refresh\" content=\"1;url=/a\">")
@@ -1149,11 +1286,13 @@ mod tests {
 
     #[test]
     fn rewrite_form_method_get_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<form method=\"get\" action=\"/a\"></form>")
@@ -1168,11 +1307,13 @@ mod tests {
 
     #[test]
     fn rewrite_form_method_post_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<form method=\"Post\" action=\"/a\"></form>")
@@ -1187,11 +1328,13 @@ mod tests {
 
     #[test]
     fn rewrite_form_no_method_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<form action=\"/a\"></form>").unwrap();
 
@@ -1203,11 +1346,13 @@ mod tests {
 
     #[test]
     fn rewrite_form_no_action_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter.write(b"<form></form>").unwrap();
 
@@ -1219,11 +1364,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_width_img_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<img srcset=\"https://ex.amp.le 1w\">")
@@ -1237,11 +1384,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_width_source_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<source srcset=\"https://ex.amp.le 1w\">")
@@ -1255,11 +1404,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_density_img_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<img srcset=\"https://ex.amp.le 1x\">")
@@ -1273,11 +1424,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_density_source_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<source srcset=\"https://ex.amp.le 1x\">")
@@ -1291,11 +1444,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_data_source_srcset_n1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<source srcset=\"data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7\">")
@@ -1309,11 +1464,13 @@ mod tests {
 
     #[test]
     fn rewrite_valid_density_data_source_srcset_n1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<source srcset=\"data:image/gif;base64,R0lGODlhAQABAIAAAAAAAP///yH5BAEAAAAALAAAAAABAAEAAAIBRAA7 1x\">")
@@ -1327,11 +1484,13 @@ mod tests {
 
     #[test]
     fn rewrite_invalid_img_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<img srcset=\"https://ex.amp.le 1w 2h\">")
@@ -1345,11 +1504,13 @@ mod tests {
 
     #[test]
     fn rewrite_invalid_source_srcset_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<source srcset=\"https://ex.amp.le 1w 2h\">")
@@ -1363,11 +1524,13 @@ mod tests {
 
     #[test]
     fn rewrite_noscript_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<noscript><h1>No JavaScript!</h1></noscript>")
@@ -1381,11 +1544,13 @@ mod tests {
 
     #[test]
     fn rewrite_noscript_n_3() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<noscript><h1>No</h1></noscript><h2>Yes</h2><noscript><h3>Maybe</h3></noscript><h4>Definitely</h4><noscript><h5>Enough</h5></noscript>")
@@ -1399,11 +1564,13 @@ mod tests {
 
     #[test]
     fn rewrite_noscript_style_n_3() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<noscript><style>a{opacity:1}</style></noscript><noscript><style>b{opacity:1}</style></noscript><noscript><style>c{opacity:1}</style></noscript>")
@@ -1415,16 +1582,17 @@ mod tests {
             std::str::from_utf8(result.html.as_slice()).unwrap(),
             "<style>a{opacity:1}</style><style>b{opacity:1}</style><style>c{opacity:1}</style>"
         );
-        assert_eq!(result.style_hashes.len(), 3);
     }
 
     #[test]
     fn rewrite_head_noscript_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
-        let mut rewriter = HtmlRewrite::new(Rc::new(
-            url::Url::parse("https://www.example.com/").unwrap(),
-        ));
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
 
         rewriter
             .write(b"<html><head><noscript><style>img{opacity:1}</style></noscript></head></html>")
@@ -1439,16 +1607,15 @@ mod tests {
                 HEADER_STYLE_ELEMENT.as_str()
             )
         );
-        assert_eq!(result.style_hashes.len(), 1);
     }
 
     #[test]
     fn rewrite_body_noscript_n_1() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         let url = Rc::new(url::Url::parse("https://www.example.com/").unwrap());
 
-        let mut rewriter = HtmlRewrite::new(url.clone());
+        let mut rewriter = HtmlRewrite::new(shared_state.clone(), url.clone(), None);
 
         rewriter
             .write(b"<html><body><noscript><style>img{opacity:1}</style><a href=\"https://www.example.com/\">example</a></noscript></body></html>")
@@ -1460,9 +1627,140 @@ mod tests {
             std::str::from_utf8(result.html.as_slice()).unwrap(),
             format!(
                 "<html><body>{}<style>img{{opacity:1}}</style><a href=\"./?url=https%3A%2F%2Fwww.example.com%2F&hash=85870232cac1676c4477f7cae4da7173ccee4002f32e89c16038547aa20175c0\">example</a></body></html>",
-                crate::templates::render_template_string(crate::templates::Template::Header(url))
+                crate::templates::render_template_string(crate::templates::Template::Header(
+                    url, false
+                ))
             )
         );
-        assert_eq!(result.style_hashes.len(), 1);
+    }
+
+    #[test]
+    fn rewrite_style_nonce_n_1() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            Some(Rc::from("bm9uY2U=")),
+        );
+
+        rewriter
+            .write(b"<head><style nonce='attacker'>a{color:red}</style></head>")
+            .unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice()).unwrap(),
+            format!(
+                "<head><style nonce=\"bm9uY2U=\">a{{color:red}}</style>{}</head>",
+                HEADER_STYLE_ELEMENT.as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn rewrite_take_output_n_2() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/index.html").unwrap()),
+            None,
+        );
+
+        rewriter.write(b"<p>first</p><a href='/'>").unwrap();
+
+        assert!(
+            std::str::from_utf8(rewriter.take_output().as_slice())
+                .unwrap()
+                .starts_with("<p>first</p>")
+        );
+
+        rewriter.write(b"main</a>").unwrap();
+
+        assert!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice())
+                .unwrap()
+                .ends_with("main</a>")
+        );
+    }
+
+    #[test]
+    fn rewrite_meta_charset_n_1() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
+
+        rewriter.write(b"<meta charset=\"windows-1251\">").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice()).unwrap(),
+            "<meta charset=\"utf-8\">"
+        );
+    }
+
+    #[test]
+    fn rewrite_meta_content_type_charset_n_1() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::new(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+        );
+
+        rewriter
+            .write(b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=Shift_JIS\">")
+            .unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice()).unwrap(),
+            "<meta http-equiv=\"Content-Type\" content=\"text/html; charset=utf-8\">"
+        );
+    }
+
+    #[test]
+    fn rewrite_shift_jis_n_1() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::with_encoding(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+            encoding_rs::SHIFT_JIS,
+        );
+
+        // "<a href='/日本'>日本</a>" encoded as Shift_JIS, split within a multibyte sequence
+        rewriter.write(b"<a href='/\x93\xfa\x96").unwrap();
+        rewriter.write(b"\x7b'>\x93\xfa\x96\x7b</a>").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice()).unwrap(),
+            "<a href=\"./?url=https%3A%2F%2Fwww.example.com%2F%25E6%2597%25A5%25E6%259C%25AC\
+            &hash=494facd37a9cc787d38cc1f215969060dcd8a75506b8e54ad303c42f8cbe2875\">日本</a>"
+        );
+    }
+
+    #[test]
+    fn rewrite_windows_1251_n_1() {
+        let shared_state = crate::utilities::test_setup_hmac();
+
+        let mut rewriter = HtmlRewrite::with_encoding(
+            shared_state.clone(),
+            Rc::new(url::Url::parse("https://www.example.com/").unwrap()),
+            None,
+            encoding_rs::WINDOWS_1251,
+        );
+
+        // "<p>Привет</p>" encoded as windows-1251
+        rewriter.write(b"<p>\xcf\xf0\xe8\xe2\xe5\xf2</p>").unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(rewriter.end().unwrap().html.as_slice()).unwrap(),
+            "<p>Привет</p>"
+        );
     }
 }
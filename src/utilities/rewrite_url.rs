@@ -1,3 +1,10 @@
+use base64::Engine;
+
+use crate::utilities::link_store::LINK_ID_LENGTH;
+
+/// Path route relative to pages which were served by it ("p/<hash>/<url>").
+const PATH_URL_PREFIX: &str = "../";
+
 #[derive(thiserror::Error, Debug)]
 pub enum RewriteUrlError {
     #[error("HMAC instance uninitialized")]
@@ -8,14 +15,17 @@ pub enum RewriteUrlError {
     Serialize(#[from] serde_qs::Error),
     #[error("Failed to create UTF-8 string")]
     Utf8String(#[from] std::string::FromUtf8Error),
+    #[error("URL cipher uninitialized")]
+    UrlCipherInstance,
+    #[error("URL encryption failed")]
+    UrlCipher(#[from] crate::utilities::UrlCipherError),
 }
 
 pub fn rewrite_url<'url>(
+    shared_state: &crate::utilities::SharedState,
     base_url: &url::Url,
     url: &'url str,
 ) -> Result<std::borrow::Cow<'url, str>, RewriteUrlError> {
-    use hmac::Mac;
-
     if url.starts_with("data:") {
         return if url.starts_with("data:image/") {
             Ok(std::borrow::Cow::Borrowed(url))
@@ -27,51 +37,169 @@ pub fn rewrite_url<'url>(
         return Ok(std::borrow::Cow::Borrowed(url));
     }
 
-    let mut hmac = match crate::utilities::HMAC.get() {
-        Some(instance) => instance.clone(),
+    Ok(std::borrow::Cow::Owned(get_proxy_url(
+        shared_state,
+        base_url.join(url)?,
+        get_url_expiry(shared_state),
+        PATH_URL_PREFIX,
+    )?))
+}
+
+/// Returns the expiry of newly signed URLs, according to the configured URL lifetime.
+pub fn get_url_expiry(shared_state: &crate::utilities::SharedState) -> Option<u64> {
+    shared_state
+        .config
+        .as_ref()
+        .and_then(|config| config.url_lifetime)
+        .map(|url_lifetime| crate::utilities::get_unix_timestamp().saturating_add(url_lifetime))
+}
+
+/**
+ * Returns the proxy URL for the (absolute) target URL, which is signed, encrypted or stored as short link
+ * according to the configuration. The path prefix has to point to the "p/" route, relative to the page
+ * the URL is used on, while the query form is always relative to the index route.
+ **/
+pub fn get_proxy_url(
+    shared_state: &crate::utilities::SharedState,
+    mut next_url: url::Url,
+    expires: Option<u64>,
+    path_prefix: &str,
+) -> Result<String, RewriteUrlError> {
+    let hmac_keys = match shared_state.hmac.as_deref() {
+        Some(instance) => instance,
         None => return Err(RewriteUrlError::HmacInstance),
     };
-    let mut next_base_url = base_url.join(url)?;
-    // `./` (2) + `?url=` (5) + `&hash=` (6) + "hash" (64) + `next_base_url.len()` (* 2 [url encoding])
-    let mut result = Vec::with_capacity(2 + 5 + 6 + 64 + (next_base_url.as_str().len() * 2));
-    let next_url_fragment: Option<String> = next_base_url.fragment().map(String::from);
+    let next_url_fragment: Option<String> = next_url.fragment().map(String::from);
 
     if next_url_fragment.is_some() {
         // exclude fragment from request URL
-        next_base_url.set_fragment(None);
+        next_url.set_fragment(None);
     }
 
-    let next_url = next_base_url.to_string();
+    let next_url = String::from(next_url);
+    let config_opt = shared_state.config.as_deref();
+    let path_urls = config_opt.is_some_and(|config| config.path_urls);
+    let mut result = if let Some(link_id) = store_link(config_opt, hmac_keys, &next_url, expires) {
+        if path_urls {
+            format!("{path_prefix}l/{link_id}").into_bytes()
+        } else {
+            format!("./?link={link_id}").into_bytes()
+        }
+    } else if path_urls {
+        get_path_url(
+            path_prefix,
+            &hmac_keys.sign_url(&next_url, expires),
+            &next_url,
+            expires,
+        )
+        .into_bytes()
+    } else {
+        // `./` (2) + `?url=` (5) + `&hash=` (6) + "hash" (64) + `&expires=` (9) + "expires" (20) + `next_url.len()` (* 2 [url encoding])
+        let mut result = Vec::with_capacity(2 + 5 + 6 + 64 + 9 + 20 + (next_url.len() * 2));
 
-    hmac.update(next_url.as_bytes());
-    result.extend_from_slice("./?".as_bytes());
+        result.extend_from_slice("./?".as_bytes());
+        write_query_url(
+            &mut result,
+            hmac_keys,
+            shared_state.url_cipher.as_deref(),
+            next_url,
+            expires,
+            config_opt.is_some_and(|config| config.encrypt_urls),
+        )?;
 
-    serde_qs::to_writer(
-        &crate::model::IndexHttpArgs {
-            hash: Some(hex::encode(hmac.finalize().into_bytes())),
-            url: Some(next_url),
-        },
-        &mut result,
-    )?;
+        result
+    };
 
     if let Some(fragment) = next_url_fragment {
         result.push(b'#');
         result.extend_from_slice(fragment.as_bytes());
     }
 
-    Ok(std::borrow::Cow::Owned(String::from_utf8(result)?))
+    Ok(String::from_utf8(result)?)
+}
+
+/**
+ * Returns the path form of a signed URL: "<prefix><base64url hash>/<base64url URL>[?expires=<expires>]".
+ * The prefix has to point to the "p/" route, relative to the page the URL is used on.
+ **/
+pub fn get_path_url(prefix: &str, hash: &[u8], url: &str, expires_opt: Option<u64>) -> String {
+    let mut path_url = format!(
+        "{prefix}{}/{}",
+        crate::utilities::BASE64_URL_ENGINE.encode(hash),
+        crate::utilities::BASE64_URL_ENGINE.encode(url)
+    );
+
+    if let Some(expires) = expires_opt {
+        path_url.push_str("?expires=");
+        path_url.push_str(&expires.to_string());
+    }
+
+    path_url
+}
+
+/// Stores URLs above the link threshold (if enabled) and returns their short link id.
+fn store_link(
+    config_opt: Option<&crate::model::Config<'static, 'static>>,
+    hmac_keys: &crate::utilities::HmacKeys,
+    next_url: &str,
+    expires: Option<u64>,
+) -> Option<String> {
+    if next_url.len() <= config_opt?.link_store.as_ref()?.threshold {
+        return None;
+    }
+
+    let link_store = crate::utilities::LINK_STORE.get()?;
+    // the truncated hash (without the expiry) is stable for the same URL and can't be guessed without the secret
+    let link_id = crate::utilities::BASE64_URL_ENGINE
+        .encode(&hmac_keys.sign_url(next_url, None)[..LINK_ID_LENGTH]);
+
+    link_store.insert(&link_id, next_url, expires);
+
+    Some(link_id)
+}
+
+fn write_query_url(
+    result: &mut Vec<u8>,
+    hmac_keys: &crate::utilities::HmacKeys,
+    url_cipher_opt: Option<&crate::utilities::UrlCipher>,
+    next_url: String,
+    expires: Option<u64>,
+    encrypt_urls: bool,
+) -> Result<(), RewriteUrlError> {
+    serde_qs::to_writer(
+        &if encrypt_urls {
+            crate::model::IndexHttpArgs {
+                opaque: Some(match url_cipher_opt {
+                    Some(url_cipher) => url_cipher.encrypt(&next_url, expires)?,
+                    None => return Err(RewriteUrlError::UrlCipherInstance),
+                }),
+                ..crate::model::IndexHttpArgs::default()
+            }
+        } else {
+            crate::model::IndexHttpArgs {
+                hash: Some(hex::encode(hmac_keys.sign_url(&next_url, expires))),
+                url: Some(next_url),
+                expires,
+                ..crate::model::IndexHttpArgs::default()
+            }
+        },
+        result,
+    )?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::utilities::rewrite_url::rewrite_url;
+    use crate::utilities::rewrite_url::{get_path_url, rewrite_url};
 
     #[test]
     fn rewrite_relative() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://www.example.com").unwrap(),
                 "/index.html"
             )
@@ -82,10 +210,11 @@ mod tests {
 
     #[test]
     fn rewrite_relative_parent() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://www.example.com/home/about").unwrap(),
                 "../index.html"
             )
@@ -96,10 +225,11 @@ mod tests {
 
     #[test]
     fn rewrite_absolute() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "https://www.example.com/"
             )
@@ -110,10 +240,11 @@ mod tests {
 
     #[test]
     fn accept_data_image_png() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "data:image/png;base64,dGVzdA=="
             )
@@ -124,10 +255,11 @@ mod tests {
 
     #[test]
     fn accept_data_image_jpg() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "data:image/jpg;base64,dGVzdA=="
             )
@@ -138,10 +270,11 @@ mod tests {
 
     #[test]
     fn reject_data_script() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "data:application/javascript;base64,dGVzdA=="
             )
@@ -152,10 +285,11 @@ mod tests {
 
     #[test]
     fn reject_data_text() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "data:text/plain;base64,dGVzdA=="
             )
@@ -166,20 +300,26 @@ mod tests {
 
     #[test]
     fn pass_through_fragment_ref() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
-            rewrite_url(&url::Url::parse("https://example.com/").unwrap(), "#about").unwrap(),
+            rewrite_url(
+                &shared_state,
+                &url::Url::parse("https://example.com/").unwrap(),
+                "#about"
+            )
+            .unwrap(),
             "#about"
         );
     }
 
     #[test]
     fn rewrite_prefixed_path_fragment_ref() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "/home/#about"
             )
@@ -190,10 +330,11 @@ mod tests {
 
     #[test]
     fn rewrite_prefixed_fragment_ref() {
-        crate::utilities::test_setup_hmac();
+        let shared_state = crate::utilities::test_setup_hmac();
 
         assert_eq!(
             rewrite_url(
+                &shared_state,
                 &url::Url::parse("https://example.com/").unwrap(),
                 "https://another.example.com/#about"
             )
@@ -201,4 +342,24 @@ mod tests {
             "./?url=https%3A%2F%2Fanother.example.com%2F&hash=743bb69ce433c306c9883528f2a7b451531362a1d41bbf6519ed97cdb81b907b#about"
         );
     }
+
+    #[test]
+    fn path_url() {
+        let hash = hex::decode("7554946c4d3998da8be40b803c938c943f3dbbbb78958addd008b55bcacfb8c0")
+            .unwrap();
+
+        assert_eq!(
+            get_path_url("../", &hash, "https://www.example.com/index.html", None),
+            "../dVSUbE05mNqL5AuAPJOMlD89u7t4lYrd0Ai1W8rPuMA/aHR0cHM6Ly93d3cuZXhhbXBsZS5jb20vaW5kZXguaHRtbA"
+        );
+        assert_eq!(
+            get_path_url(
+                "./p/",
+                &hash,
+                "https://www.example.com/",
+                Some(1_700_000_000)
+            ),
+            "./p/dVSUbE05mNqL5AuAPJOMlD89u7t4lYrd0Ai1W8rPuMA/aHR0cHM6Ly93d3cuZXhhbXBsZS5jb20v?expires=1700000000"
+        );
+    }
 }
@@ -1,10 +1,61 @@
 pub type HmacInstance = hmac::Hmac<sha2::Sha256>;
 
-pub static HMAC: once_cell::sync::OnceCell<HmacInstance> = once_cell::sync::OnceCell::new();
-pub static REQUEST_CLIENT: once_cell::sync::OnceCell<reqwest::Client> =
+static SHARED_STATE: once_cell::sync::Lazy<RwLock<Arc<SharedState>>> =
+    once_cell::sync::Lazy::new(RwLock::default);
+
+/// Values which depend on the config and are replaced all at once when it's reloaded.
+#[derive(Clone, Default)]
+pub struct SharedState {
+    pub config: Option<Arc<crate::model::Config<'static, 'static>>>,
+    pub hmac: Option<Arc<crate::utilities::HmacKeys>>,
+    pub request_client: Option<Arc<reqwest::Client>>,
+    pub url_cipher: Option<Arc<crate::utilities::UrlCipher>>,
+}
+
+/**
+ * Accessor for one value of the shared state.
+ * Callers keep the value they got until they drop it, so running requests finish with the previous value after a reload.
+ **/
+pub struct SharedValue<T: 'static>(fn(&SharedState) -> &Option<Arc<T>>);
+
+impl<T> SharedValue<T> {
+    pub fn get(&self) -> Option<Arc<T>> {
+        (self.0)(&get_shared_state()).clone()
+    }
+}
+
+/**
+ * Returns the current shared values, which are taken once per request and passed along,
+ * so that a response is handled (e.g. all of its links are rewritten) with the same values.
+ **/
+pub fn get_shared_state() -> Arc<SharedState> {
+    SHARED_STATE
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Replaces all shared values at once and returns the previous ones.
+pub fn replace_shared_state(state: SharedState) -> Arc<SharedState> {
+    std::mem::replace(
+        &mut SHARED_STATE.write().unwrap_or_else(PoisonError::into_inner),
+        Arc::new(state),
+    )
+}
+
+use std::sync::{Arc, PoisonError, RwLock};
+
+pub static HMAC: SharedValue<crate::utilities::HmacKeys> = SharedValue(|state| &state.hmac);
+pub static URL_CIPHER: SharedValue<crate::utilities::UrlCipher> =
+    SharedValue(|state| &state.url_cipher);
+pub static GLOBAL_CONFIG: SharedValue<crate::model::Config<'static, 'static>> =
+    SharedValue(|state| &state.config);
+pub static LINK_STORE: once_cell::sync::OnceCell<crate::utilities::LinkStore> =
     once_cell::sync::OnceCell::new();
-pub static GLOBAL_CONFIG: once_cell::sync::OnceCell<crate::model::Config<'_, '_>> =
+pub static RESPONSE_CACHE: once_cell::sync::OnceCell<crate::utilities::ResponseCache> =
     once_cell::sync::OnceCell::new();
+pub static IN_FLIGHT_REQUESTS: once_cell::sync::Lazy<crate::utilities::Singleflight> =
+    once_cell::sync::Lazy::new(crate::utilities::Singleflight::default);
 pub static HEADER_VALUE_NO_CACHE: actix_web::http::header::HeaderValue =
     actix_web::http::header::HeaderValue::from_static("no-cache");
 pub static HEADER_VALUE_CONTENT_HTML: once_cell::sync::Lazy<actix_web::http::header::HeaderValue> =
@@ -15,15 +66,32 @@ pub const BASE64_ENGINE: base64::engine::GeneralPurpose = base64::engine::Genera
     &base64::alphabet::STANDARD,
     base64::engine::GeneralPurposeConfig::new(),
 );
+pub const BASE64_URL_ENGINE: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
 
+pub fn get_unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// Sets the HMAC keys (if there are none yet) and returns the shared values.
 #[cfg(test)]
-pub fn test_setup_hmac() {
-    use hmac::digest::KeyInit;
-
-    if HMAC
-        .set(hmac::Hmac::new_from_slice(b"example").unwrap())
-        .is_err()
-    {
-        // silently ignore this, since it only `Err`s on successive calls
+pub fn test_setup_hmac() -> Arc<SharedState> {
+    let mut shared_state = SHARED_STATE.write().unwrap_or_else(PoisonError::into_inner);
+
+    if shared_state.hmac.is_none() {
+        *shared_state = Arc::new(SharedState {
+            hmac: Some(Arc::new(
+                crate::utilities::HmacKeys::new(b"example", []).unwrap(),
+            )),
+            ..SharedState::clone(&shared_state)
+        });
     }
+
+    shared_state.clone()
 }
@@ -0,0 +1,715 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Waker},
+};
+
+use futures_util::StreamExt;
+
+use crate::utilities::{
+    client::{
+        CacheHeaders, ClientError, ClientRedirect, ClientResponse, ClientResponseStream,
+        FetchResult, UpstreamDocument, UpstreamResult,
+    },
+    media_type::is_navigable_request,
+};
+
+/**
+ * Amount of body bytes which will be kept for requests joining a flight later on.
+ * The upstream body won't be read further ahead of the slowest request than this, unless another request
+ * is waiting for the next chunk, in which case the slowest requests will be detached (see `detach_lagging_readers`).
+ **/
+const FLIGHT_BUFFER_SIZE: usize = 1_048_576;
+
+/// Everything which influences the upstream request and its transformation.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct FlightKey {
+    accept_language: Option<actix_web::http::header::HeaderValue>,
+    navigable: bool,
+    url: String,
+}
+
+/// Coalesces concurrent identical upstream fetches, so that all requests share one upstream response.
+#[derive(Default)]
+pub struct Singleflight {
+    flights: Mutex<HashMap<FlightKey, Arc<Flight>>>,
+}
+
+#[derive(Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+}
+
+#[derive(Default)]
+struct FlightState {
+    buffered_size: usize,
+    chunks: VecDeque<bytes::Bytes>,
+    /// Set once the flight was abandoned by every request, so that no one else can join it.
+    closed: bool,
+    driver_waker: Option<Waker>,
+    end: Option<Result<(), Arc<ClientError>>>,
+    head: Option<Result<FlightHead, Arc<ClientError>>>,
+    next_reader_id: u64,
+    /// Index of the first chunk in `chunks`.
+    offset: usize,
+    readers: HashMap<u64, ReaderState>,
+}
+
+#[derive(Default)]
+struct ReaderState {
+    /// Set once the request fell too far behind, it will fail instead of holding back the other requests.
+    detached: bool,
+    position: usize,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone)]
+enum FlightHead {
+    /// The raw document is shared, every request rewrites it on its own (see `UpstreamDocument`).
+    Document(DocumentHead),
+    NotModified(CacheHeaders),
+    Redirect(ClientRedirect),
+    Response(Box<ResponseHead>),
+}
+
+#[derive(Clone)]
+struct ResponseHead {
+    accept_ranges: Option<reqwest::header::HeaderValue>,
+    cache_headers: Option<CacheHeaders>,
+    content_disposition: Option<reqwest::header::HeaderValue>,
+    content_length: Option<u64>,
+    content_range: Option<reqwest::header::HeaderValue>,
+    content_type: mime::Mime,
+    rewritten: bool,
+    status_code: reqwest::StatusCode,
+}
+
+#[derive(Clone)]
+struct DocumentHead {
+    base_url: url::Url,
+    content_type: mime::Mime,
+}
+
+struct FlightReader {
+    finished: bool,
+    flight: Arc<Flight>,
+    id: u64,
+}
+
+impl FlightKey {
+    pub fn new(url: &str, headers: &actix_web::http::header::HeaderMap) -> Self {
+        Self {
+            accept_language: headers
+                .get(actix_web::http::header::ACCEPT_LANGUAGE)
+                .cloned(),
+            navigable: is_navigable_request(headers),
+            url: String::from(url),
+        }
+    }
+}
+
+impl Singleflight {
+    /**
+     * Joins the flight for `key` if there is one, which still holds the beginning of its body.
+     * Otherwise `fetch` will be spawned as a new flight, which is driven independently of the requests reading it.
+     * HTML documents are shared before they are rewritten, since every response needs its own CSP nonce.
+     **/
+    pub async fn fetch<F>(
+        &'static self,
+        key: FlightKey,
+        fetch: F,
+    ) -> Result<UpstreamResult, ClientError>
+    where
+        F: Future<Output = Result<UpstreamResult, ClientError>> + 'static,
+    {
+        let reader = {
+            let mut flights = lock(&self.flights);
+
+            match flights.get(&key).and_then(FlightReader::join) {
+                Some(reader) => {
+                    log::debug!("coalescing request: '{}'", key.url);
+                    reader
+                }
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    let reader =
+                        FlightReader::join(&flight).expect("a new flight is always joinable");
+
+                    flights.insert(key.clone(), flight.clone());
+                    actix_web::rt::spawn(self.drive(key, flight, fetch));
+                    reader
+                }
+            }
+        };
+
+        reader.into_upstream_result().await
+    }
+
+    async fn drive<F>(&'static self, key: FlightKey, flight: Arc<Flight>, fetch: F)
+    where
+        F: Future<Output = Result<UpstreamResult, ClientError>>,
+    {
+        let body_opt = match fetch.await {
+            Ok(UpstreamResult::Document(document)) => {
+                flight.set_head(Ok(FlightHead::Document(DocumentHead {
+                    base_url: document.base_url,
+                    content_type: document.content_type,
+                })));
+                Some(document.body)
+            }
+            Ok(UpstreamResult::Fetched(FetchResult::Response(client_response))) => {
+                let (head, body) = ResponseHead::split(*client_response);
+
+                flight.set_head(Ok(FlightHead::Response(Box::new(head))));
+                Some(body)
+            }
+            Ok(UpstreamResult::Fetched(FetchResult::Redirect(client_redirect))) => {
+                flight.set_head(Ok(FlightHead::Redirect(client_redirect)));
+                None
+            }
+            Ok(UpstreamResult::Fetched(FetchResult::NotModified(cache_headers))) => {
+                flight.set_head(Ok(FlightHead::NotModified(cache_headers)));
+                None
+            }
+            Err(err) => {
+                flight.set_head(Err(Arc::new(err)));
+                None
+            }
+        };
+
+        if let Some(mut body) = body_opt {
+            loop {
+                // wait for the slowest request to catch up, or stop if every request is gone
+                if !futures_util::future::poll_fn(|cx| flight.poll_capacity(cx)).await {
+                    break;
+                }
+
+                match body.next().await {
+                    Some(Ok(chunk)) => flight.push_chunk(chunk),
+                    Some(Err(err)) => {
+                        flight.set_end(Err(Arc::new(err)));
+                        break;
+                    }
+                    None => {
+                        flight.set_end(Ok(()));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut flights = lock(&self.flights);
+
+        if flights
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &flight))
+        {
+            flights.remove(&key);
+        }
+    }
+}
+
+impl Flight {
+    fn set_head(&self, head: Result<FlightHead, Arc<ClientError>>) {
+        let mut state = lock(&self.state);
+
+        state.head = Some(head);
+        state.wake_readers();
+    }
+
+    fn push_chunk(&self, chunk: bytes::Bytes) {
+        let mut state = lock(&self.state);
+
+        state.buffered_size += chunk.len();
+        state.chunks.push_back(chunk);
+        state.wake_readers();
+    }
+
+    fn set_end(&self, end: Result<(), Arc<ClientError>>) {
+        let mut state = lock(&self.state);
+
+        state.end = Some(end);
+        state.wake_readers();
+    }
+
+    /// Resolves to `true` once more body chunks may be buffered, or to `false` if there are no readers left.
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        let mut state = lock(&self.state);
+
+        if state.attached_positions().next().is_none() {
+            state.closed = true;
+            Poll::Ready(false)
+        } else if state.buffered_size <= FLIGHT_BUFFER_SIZE {
+            Poll::Ready(true)
+        } else {
+            state.driver_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl FlightState {
+    fn wake_readers(&mut self) {
+        for reader in self.readers.values_mut() {
+            if let Some(waker) = reader.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn attached_positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.readers
+            .values()
+            .filter(|reader| !reader.detached)
+            .map(|reader| reader.position)
+    }
+
+    /// Drops chunks which were read by every (attached) request, once the buffer exceeds `FLIGHT_BUFFER_SIZE`.
+    fn trim(&mut self) {
+        if self.buffered_size <= FLIGHT_BUFFER_SIZE {
+            return;
+        }
+
+        let min_position = self
+            .attached_positions()
+            .min()
+            .unwrap_or(self.offset + self.chunks.len());
+
+        while self.offset < min_position {
+            let Some(chunk) = self.chunks.pop_front() else {
+                break;
+            };
+
+            self.buffered_size -= chunk.len();
+            self.offset += 1;
+        }
+
+        if self.buffered_size <= FLIGHT_BUFFER_SIZE
+            && let Some(waker) = self.driver_waker.take()
+        {
+            waker.wake();
+        }
+    }
+
+    /**
+     * Detaches the slowest requests until the buffer has room for the next chunk again.
+     * Called by a request which read every buffered chunk, so that one stalled request can't hold back the others
+     * (while the upstream idle timeout doesn't apply).
+     **/
+    fn detach_lagging_readers(&mut self) {
+        let end_position = self.offset + self.chunks.len();
+
+        while self.buffered_size > FLIGHT_BUFFER_SIZE {
+            let Some(min_position) = self
+                .attached_positions()
+                .min()
+                .filter(|min_position| *min_position < end_position)
+            else {
+                break;
+            };
+
+            for reader in self.readers.values_mut() {
+                if !reader.detached && reader.position == min_position {
+                    reader.detached = true;
+
+                    if let Some(waker) = reader.waker.take() {
+                        waker.wake();
+                    }
+                }
+            }
+
+            self.trim();
+        }
+    }
+}
+
+impl ResponseHead {
+    fn split(client_response: ClientResponse) -> (Self, ClientResponseStream) {
+        (
+            Self {
+                accept_ranges: client_response.accept_ranges,
+                cache_headers: client_response.cache_headers,
+                content_disposition: client_response.content_disposition,
+                content_length: client_response.content_length,
+                content_range: client_response.content_range,
+                content_type: client_response.content_type,
+                rewritten: client_response.rewritten,
+                status_code: client_response.status_code,
+            },
+            client_response.body,
+        )
+    }
+
+    fn into_client_response(self, body: ClientResponseStream) -> ClientResponse {
+        ClientResponse {
+            accept_ranges: self.accept_ranges,
+            body,
+            cache_headers: self.cache_headers,
+            content_disposition: self.content_disposition,
+            content_length: self.content_length,
+            content_range: self.content_range,
+            content_type: self.content_type,
+            rewritten: self.rewritten,
+            status_code: self.status_code,
+            style_nonce: None,
+        }
+    }
+}
+
+impl FlightReader {
+    /// Returns `None` if the beginning of the body was already dropped.
+    fn join(flight: &Arc<Flight>) -> Option<Self> {
+        let mut state = lock(&flight.state);
+
+        if state.closed || state.offset > 0 || matches!(state.end, Some(Err(_))) {
+            return None;
+        }
+
+        let id = state.next_reader_id;
+
+        state.next_reader_id += 1;
+        state.readers.insert(id, ReaderState::default());
+
+        Some(Self {
+            finished: false,
+            flight: flight.clone(),
+            id,
+        })
+    }
+
+    async fn into_upstream_result(self) -> Result<UpstreamResult, ClientError> {
+        let head = futures_util::future::poll_fn(|cx| {
+            let mut state = lock(&self.flight.state);
+
+            match state.head.clone() {
+                Some(head) => Poll::Ready(head),
+                None => {
+                    if let Some(reader) = state.readers.get_mut(&self.id) {
+                        reader.waker = Some(cx.waker().clone());
+                    }
+
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+        .map_err(ClientError::Shared)?;
+
+        Ok(match head {
+            FlightHead::Document(document_head) => UpstreamResult::Document(UpstreamDocument {
+                base_url: document_head.base_url,
+                body: Box::pin(self),
+                content_type: document_head.content_type,
+            }),
+            FlightHead::NotModified(cache_headers) => {
+                UpstreamResult::Fetched(FetchResult::NotModified(cache_headers))
+            }
+            FlightHead::Redirect(client_redirect) => {
+                UpstreamResult::Fetched(FetchResult::Redirect(client_redirect))
+            }
+            FlightHead::Response(response_head) => UpstreamResult::Fetched(FetchResult::Response(
+                Box::new(response_head.into_client_response(Box::pin(self))),
+            )),
+        })
+    }
+}
+
+impl futures_util::Stream for FlightReader {
+    type Item = Result<bytes::Bytes, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        let flight = self.flight.clone();
+        let mut state = lock(&flight.state);
+        let offset = state.offset;
+        let Some((position, detached)) = state
+            .readers
+            .get(&self.id)
+            .map(|reader| (reader.position, reader.detached))
+        else {
+            return Poll::Ready(None);
+        };
+
+        if detached {
+            drop(state);
+            self.finished = true;
+            return Poll::Ready(Some(Err(ClientError::SlowReader)));
+        }
+
+        if let Some(chunk) = state.chunks.get(position - offset).cloned() {
+            if let Some(reader) = state.readers.get_mut(&self.id) {
+                reader.position += 1;
+            }
+
+            state.trim();
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        match state.end.clone() {
+            Some(end) => {
+                drop(state);
+                self.finished = true;
+                Poll::Ready(end.err().map(|err| Err(ClientError::Shared(err))))
+            }
+            None => {
+                state.detach_lagging_readers();
+
+                if let Some(reader) = state.readers.get_mut(&self.id) {
+                    reader.waker = Some(cx.waker().clone());
+                }
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for FlightReader {
+    fn drop(&mut self) {
+        let mut state = lock(&self.flight.state);
+
+        state.readers.remove(&self.id);
+        state.trim();
+
+        if state.attached_positions().next().is_none()
+            && let Some(waker) = state.driver_waker.take()
+        {
+            waker.wake();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use futures_util::StreamExt;
+
+    use super::{FLIGHT_BUFFER_SIZE, FlightKey, Singleflight};
+    use crate::utilities::client::{
+        ClientError, ClientResponse, FetchResult, UpstreamDocument, UpstreamResult,
+    };
+
+    fn singleflight() -> &'static Singleflight {
+        Box::leak(Box::default())
+    }
+
+    fn key() -> FlightKey {
+        FlightKey::new(
+            "https://example.com/image.png",
+            &actix_web::http::header::HeaderMap::new(),
+        )
+    }
+
+    fn response(chunks: Vec<Result<bytes::Bytes, ClientError>>) -> UpstreamResult {
+        UpstreamResult::Fetched(FetchResult::Response(Box::new(ClientResponse {
+            accept_ranges: None,
+            body: Box::pin(futures_util::stream::iter(chunks)),
+            cache_headers: None,
+            content_disposition: None,
+            content_length: None,
+            content_range: None,
+            content_type: mime::IMAGE_PNG,
+            rewritten: false,
+            status_code: reqwest::StatusCode::OK,
+            style_nonce: None,
+        })))
+    }
+
+    async fn collect_body(
+        upstream_result: Result<UpstreamResult, ClientError>,
+    ) -> Vec<Result<bytes::Bytes, ClientError>> {
+        match upstream_result {
+            Ok(UpstreamResult::Fetched(FetchResult::Response(client_response))) => {
+                client_response.body.collect().await
+            }
+            _ => panic!("expected a response"),
+        }
+    }
+
+    fn counted_fetch(
+        count: &Rc<Cell<usize>>,
+        chunks: Vec<Result<bytes::Bytes, ClientError>>,
+    ) -> impl Future<Output = Result<UpstreamResult, ClientError>> + 'static {
+        let count = count.clone();
+
+        async move {
+            count.set(count.get() + 1);
+            actix_web::rt::task::yield_now().await;
+            Ok(response(chunks))
+        }
+    }
+
+    #[actix_web::test]
+    async fn coalesce_concurrent_fetches() {
+        let singleflight = singleflight();
+        let count = Rc::new(Cell::new(0));
+        let chunks = || {
+            vec![
+                Ok(bytes::Bytes::from_static(b"01234")),
+                Ok(bytes::Bytes::from_static(b"56789")),
+            ]
+        };
+        let (first, second) = futures_util::future::join(
+            singleflight.fetch(key(), counted_fetch(&count, chunks())),
+            singleflight.fetch(key(), counted_fetch(&count, chunks())),
+        )
+        .await;
+        let (first_body, second_body) =
+            futures_util::future::join(collect_body(first), collect_body(second)).await;
+
+        assert_eq!(count.get(), 1);
+
+        for body in [first_body, second_body] {
+            assert_eq!(
+                body.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+                chunks().into_iter().map(Result::unwrap).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn separate_flights_after_completion() {
+        let singleflight = singleflight();
+        let count = Rc::new(Cell::new(0));
+
+        for _ in 0..2 {
+            let body = collect_body(
+                singleflight
+                    .fetch(key(), counted_fetch(&count, vec![Ok(bytes::Bytes::new())]))
+                    .await,
+            )
+            .await;
+
+            assert_eq!(body.len(), 1);
+            // let the driver remove the completed flight
+            actix_web::rt::task::yield_now().await;
+        }
+
+        assert_eq!(count.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn share_errors() {
+        let singleflight = singleflight();
+        let (first, second) = futures_util::future::join(
+            singleflight.fetch(key(), async { Err(ClientError::BadRequest) }),
+            singleflight.fetch(key(), async { Ok(response(Vec::new())) }),
+        )
+        .await;
+
+        for result in [first, second] {
+            assert!(matches!(
+                result,
+                Err(ClientError::Shared(err)) if matches!(*err, ClientError::BadRequest)
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn share_documents() {
+        let singleflight = singleflight();
+        let count = Rc::new(Cell::new(0));
+        let document_fetch = || {
+            let count = count.clone();
+
+            async move {
+                count.set(count.get() + 1);
+                actix_web::rt::task::yield_now().await;
+                Ok(UpstreamResult::Document(UpstreamDocument {
+                    base_url: url::Url::parse("https://example.com/").unwrap(),
+                    body: Box::pin(futures_util::stream::iter([Ok(bytes::Bytes::from_static(
+                        b"<style></style>",
+                    ))])),
+                    content_type: mime::TEXT_HTML,
+                }))
+            }
+        };
+        let (first, second) = futures_util::future::join(
+            singleflight.fetch(key(), document_fetch()),
+            singleflight.fetch(key(), document_fetch()),
+        )
+        .await;
+
+        assert_eq!(count.get(), 1);
+
+        for result in [first, second] {
+            let Ok(UpstreamResult::Document(document)) = result else {
+                panic!("expected a document");
+            };
+            let body = document.body.collect::<Vec<_>>().await;
+
+            assert_eq!(document.base_url.as_str(), "https://example.com/");
+            assert_eq!(document.content_type, mime::TEXT_HTML);
+            assert_eq!(
+                body.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+                [bytes::Bytes::from_static(b"<style></style>")]
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn detach_slow_reader() {
+        let singleflight = singleflight();
+        let count = Rc::new(Cell::new(0));
+        let chunks = || {
+            let chunk = bytes::Bytes::from(vec![0u8; FLIGHT_BUFFER_SIZE]);
+
+            vec![Ok(chunk.clone()), Ok(chunk.clone()), Ok(chunk)]
+        };
+        let (first, second) = futures_util::future::join(
+            singleflight.fetch(key(), counted_fetch(&count, chunks())),
+            singleflight.fetch(key(), counted_fetch(&count, chunks())),
+        )
+        .await;
+        // the second request doesn't read anything until the first one is done
+        let first_body = collect_body(first).await;
+        let second_body = collect_body(second).await;
+
+        assert_eq!(count.get(), 1);
+        assert_eq!(first_body.len(), 3);
+        assert!(first_body.iter().all(Result::is_ok));
+        assert!(matches!(
+            second_body.as_slice(),
+            [Err(ClientError::SlowReader)]
+        ));
+    }
+
+    #[actix_web::test]
+    async fn no_join_after_trim() {
+        let singleflight = singleflight();
+        let count = Rc::new(Cell::new(0));
+        let chunks = || {
+            let chunk = bytes::Bytes::from(vec![0u8; FLIGHT_BUFFER_SIZE]);
+
+            vec![Ok(chunk.clone()), Ok(chunk.clone()), Ok(chunk)]
+        };
+        let first = singleflight
+            .fetch(key(), counted_fetch(&count, chunks()))
+            .await;
+        let Ok(UpstreamResult::Fetched(FetchResult::Response(mut first_response))) = first else {
+            panic!("expected a response");
+        };
+
+        // read until the beginning of the body was dropped
+        for _ in 0..2 {
+            assert!(first_response.body.next().await.unwrap().is_ok());
+        }
+
+        let second = singleflight
+            .fetch(key(), counted_fetch(&count, chunks()))
+            .await;
+
+        assert_eq!(count.get(), 2);
+        assert_eq!(collect_body(second).await.len(), 3);
+    }
+}
@@ -0,0 +1,201 @@
+use base64::Engine;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce, aead::Aead};
+
+use crate::utilities::{hmac_keys::get_url_message, shared::BASE64_URL_ENGINE};
+
+/// HKDF "info" which separates the encryption key from other uses of the secret.
+const KEY_DERIVATION_INFO: &[u8] = b"searproxy url encryption";
+const NONCE_LENGTH: usize = 24;
+
+#[derive(thiserror::Error, Debug)]
+pub enum UrlCipherError {
+    #[error("Invalid encrypted URL encoding")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Encrypted URL couldn't be decrypted")]
+    Decrypt,
+    #[error("URL encryption failed")]
+    Encrypt,
+    #[error("Key derivation failed")]
+    KeyDerivation,
+    #[error("Random number generation failed")]
+    Random(#[from] getrandom::Error),
+    #[error("Decrypted URL isn't valid UTF-8")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+/**
+ * Encrypts target URLs (and their expiry) with keys derived from the HMAC secrets, so they can be used as opaque URLs.
+ * Like `HmacKeys`, the primary key is used for encryption and the previous ones are only used for decryption.
+ **/
+pub struct UrlCipher {
+    primary: XChaCha20Poly1305,
+    decrypt_only: Vec<XChaCha20Poly1305>,
+}
+
+impl UrlCipher {
+    pub fn new<'secret>(
+        primary_secret: &[u8],
+        decrypt_only_secrets: impl IntoIterator<Item = &'secret [u8]>,
+    ) -> Result<Self, UrlCipherError> {
+        Ok(Self {
+            primary: derive_cipher(primary_secret)?,
+            decrypt_only: decrypt_only_secrets
+                .into_iter()
+                .map(derive_cipher)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Returns the URL safe Base64 encoded nonce and ciphertext of the URL and its expiry (if any).
+    pub fn encrypt(&self, url: &str, expires_opt: Option<u64>) -> Result<String, UrlCipherError> {
+        let mut nonce = [0u8; NONCE_LENGTH];
+
+        getrandom::fill(&mut nonce)?;
+
+        let ciphertext = self
+            .primary
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                get_url_message(url, expires_opt).as_ref(),
+            )
+            .map_err(|_| UrlCipherError::Encrypt)?;
+
+        Ok(BASE64_URL_ENGINE.encode([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    /// Returns the URL and its expiry (if any).
+    pub fn decrypt(&self, opaque_url: &str) -> Result<(String, Option<u64>), UrlCipherError> {
+        self.decrypt_with_key(opaque_url)
+            .map(|(_, url, expires_opt)| (url, expires_opt))
+    }
+
+    /// Returns the index of the key which decrypted the URL (`0` is the primary key), the URL and its expiry (if any).
+    pub fn decrypt_with_key(
+        &self,
+        opaque_url: &str,
+    ) -> Result<(usize, String, Option<u64>), UrlCipherError> {
+        let payload = BASE64_URL_ENGINE.decode(opaque_url)?;
+
+        if payload.len() < NONCE_LENGTH {
+            return Err(UrlCipherError::Decrypt);
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+        let (index, message) = std::iter::once(&self.primary)
+            .chain(self.decrypt_only.iter())
+            .enumerate()
+            .find_map(|(index, cipher)| {
+                cipher
+                    .decrypt(XNonce::from_slice(nonce), ciphertext)
+                    .ok()
+                    .map(|message| (index, message))
+            })
+            .ok_or(UrlCipherError::Decrypt)?;
+        let (url, expires_opt) = parse_url_message(String::from_utf8(message)?);
+
+        Ok((index, url, expires_opt))
+    }
+}
+
+impl std::fmt::Debug for UrlCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't expose the derived keys
+        f.debug_struct("UrlCipher").finish_non_exhaustive()
+    }
+}
+
+fn derive_cipher(secret: &[u8]) -> Result<XChaCha20Poly1305, UrlCipherError> {
+    let mut key = [0u8; 32];
+
+    hkdf::Hkdf::<sha2::Sha256>::new(None, secret)
+        .expand(KEY_DERIVATION_INFO, &mut key)
+        .map_err(|_| UrlCipherError::KeyDerivation)?;
+
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Reverses `get_url_message`, the expiry prefix is unambiguous since a URL scheme has to start with a letter.
+fn parse_url_message(message: String) -> (String, Option<u64>) {
+    if let Some((expires, url)) = message.split_once(':')
+        && let Ok(expires) = expires.parse::<u64>()
+    {
+        return (String::from(url), Some(expires));
+    }
+
+    (message, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+
+    use super::{NONCE_LENGTH, UrlCipher, UrlCipherError};
+    use crate::utilities::shared::BASE64_URL_ENGINE;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let cipher = UrlCipher::new(b"primary", []).unwrap();
+        let url = "https://example.com/path?query=value";
+        let opaque_url = cipher.encrypt(url, None).unwrap();
+
+        assert!(!opaque_url.contains("example"));
+        assert_ne!(opaque_url, cipher.encrypt(url, None).unwrap());
+        assert_eq!(
+            cipher.decrypt(&opaque_url).unwrap(),
+            (String::from(url), None)
+        );
+        assert_eq!(
+            cipher
+                .decrypt(&cipher.encrypt(url, Some(1_700_000_000)).unwrap())
+                .unwrap(),
+            (String::from(url), Some(1_700_000_000))
+        );
+    }
+
+    #[test]
+    fn decrypt_with_previous_key() {
+        let previous_cipher = UrlCipher::new(b"previous", []).unwrap();
+        let cipher = UrlCipher::new(b"primary", [b"previous".as_slice()]).unwrap();
+        let opaque_url = previous_cipher
+            .encrypt("https://example.com/", None)
+            .unwrap();
+
+        assert_eq!(
+            cipher.decrypt(&opaque_url).unwrap().0,
+            "https://example.com/"
+        );
+        assert_eq!(cipher.decrypt_with_key(&opaque_url).unwrap().0, 1);
+        assert!(matches!(
+            UrlCipher::new(b"other", []).unwrap().decrypt(&opaque_url),
+            Err(UrlCipherError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let cipher = UrlCipher::new(b"primary", []).unwrap();
+        let opaque_url = cipher.encrypt("https://example.com/", None).unwrap();
+        let payload = BASE64_URL_ENGINE.decode(&opaque_url).unwrap();
+
+        // flip a byte of the nonce, the ciphertext and the tag
+        for index in [0, NONCE_LENGTH, payload.len() - 1] {
+            let mut tampered_payload = payload.clone();
+
+            tampered_payload[index] ^= 0x01;
+
+            assert!(matches!(
+                cipher.decrypt(&BASE64_URL_ENGINE.encode(tampered_payload)),
+                Err(UrlCipherError::Decrypt)
+            ));
+        }
+
+        assert!(matches!(
+            cipher.decrypt("AAAA"),
+            Err(UrlCipherError::Decrypt)
+        ));
+        assert!(matches!(
+            cipher.decrypt("not base64!"),
+            Err(UrlCipherError::Base64(_))
+        ));
+    }
+}